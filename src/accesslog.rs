@@ -0,0 +1,153 @@
+//! `--access-log`: client connections and disconnections as JSON lines in a separate file
+
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    json::{fields_into, Field},
+    log, signals,
+};
+
+/// Sends records to the access log writer task
+#[derive(Clone)]
+pub struct AccessLog {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl AccessLog {
+    /// Queue a record like `{"event":"connect","ts":"...",...}` for writing
+    pub fn record(&self, event: &str, fields: &[(&str, Field)]) {
+        let mut buf = String::with_capacity(256);
+        let _ = write!(buf, "{{\"event\":\"{event}\",\"ts\":\"");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        crate::write_rfc3339(&mut buf, now);
+        buf.push('"');
+        fields_into(&mut buf, fields);
+        buf.push_str("}\n");
+        let _ = self.tx.send(buf);
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    file: File,
+    /// Size of the file, for `--access-log-max-bytes`
+    size: u64,
+    max_bytes: Option<u64>,
+}
+
+impl Writer {
+    fn open(path: PathBuf, max_bytes: Option<u64>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_bytes,
+        })
+    }
+
+    /// Open the file again, e.g. after it was moved away by external log rotation
+    fn reopen(&mut self) -> std::io::Result<()> {
+        *self = Self::open(self.path.clone(), self.max_bytes)?;
+        Ok(())
+    }
+
+    /// Rename the file to `<PATH>.1`, replacing the previous one, and start a new file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let mut old = self.path.clone().into_os_string();
+        old.push(".1");
+        std::fs::rename(&self.path, old)?;
+        self.reopen()
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self
+            .max_bytes
+            .is_some_and(|m| self.size > 0 && self.size + line.len() as u64 > m)
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn report_error(path: &std::path::Path, e: std::io::Error) {
+    log::event(
+        log::Level::Error,
+        "access_log_error",
+        &[
+            ("path", Field::Str(&path.to_string_lossy())),
+            ("error", Field::Str(&e.to_string())),
+        ],
+        Some(format_args!("Access log {}: {e}", path.display())),
+    );
+}
+
+/// Open the log file and spawn the task writing to it. The file is reopened on SIGHUP.
+pub fn spawn(path: PathBuf, max_bytes: Option<u64>) -> anyhow::Result<AccessLog> {
+    let mut writer = Writer::open(path.clone(), max_bytes)
+        .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+    let mut sighup = signals::Signal::new(libc::SIGHUP)?;
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                line = rx.recv() => {
+                    let Some(line) = line else {
+                        break;
+                    };
+                    if let Err(e) = writer.write(&line) {
+                        report_error(&writer.path, e);
+                    }
+                }
+                Ok(()) = sighup.recv() => {
+                    if let Err(e) = writer.reopen() {
+                        report_error(&writer.path, e);
+                    }
+                }
+            }
+        }
+    });
+    Ok(AccessLog { tx })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let log = AccessLog { tx };
+        log.record(
+            "disconnect",
+            &[
+                ("client", Field::Num(1)),
+                ("peer", Field::Str("a\"b")),
+                ("duration", Field::Float(1.5)),
+            ],
+        );
+        let line = rx.try_recv().unwrap();
+        let (head, rest) = line.split_once(",\"ts\":\"").unwrap();
+        assert_eq!(head, r#"{"event":"disconnect""#);
+        let (ts, rest) = rest.split_at(27);
+        assert!(ts.ends_with('Z'), "{ts}");
+        assert_eq!(
+            rest,
+            concat!(r#"","client":1,"peer":"a\"b","duration":1.5}"#, "\n")
+        );
+    }
+}
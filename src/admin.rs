@@ -0,0 +1,118 @@
+//! `--admin-socket`: UNIX socket accepting line-oriented control commands
+//!
+//! Commands: `STATUS`, `DISCONNECT ALL`, `RESET HISTORY`, `SET QLEN <N>` and `QUIT`.
+//! Each command gets one reply line: `OK`, `ERROR <reason>` or JSON report for `STATUS`.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{watch, Notify},
+};
+
+use crate::{
+    broadcaster::Channel,
+    history::{History, TopicHistories},
+    log::{self, Level},
+    stats::Stats,
+    ClientPresence,
+};
+
+pub struct Admin {
+    pub stats: Arc<Stats>,
+    pub presence: Arc<ClientPresence>,
+    pub history: Option<Arc<Mutex<History>>>,
+    /// `--history-tiers` buffers of the smaller tiers
+    pub tier_histories: Vec<Arc<Mutex<History>>>,
+    /// History of each topic or group
+    pub topic_histories: Option<Arc<TopicHistories>>,
+    /// Weak to let the channel close on stdin EOF regardless of admin task
+    pub channel: Weak<Mutex<Channel>>,
+    /// Smallest allowed `SET QLEN` value
+    pub min_qlen: usize,
+    /// Incremented to make all clients disconnect
+    pub disconnect: Arc<watch::Sender<u64>>,
+    /// Notified to initiate graceful shutdown
+    pub quit: Arc<Notify>,
+}
+
+impl Admin {
+    fn execute(&self, command: &str) -> String {
+        match command.split_whitespace().collect::<Vec<_>>()[..] {
+            ["STATUS"] => {
+                let history_size = match self.history {
+                    Some(ref hb) => hb.lock().unwrap().len(),
+                    None => 0,
+                };
+                self.stats.health_json(self.presence.count(), history_size)
+            }
+            ["DISCONNECT", "ALL"] => {
+                self.disconnect.send_modify(|x| *x += 1);
+                "OK".to_owned()
+            }
+            ["RESET", "HISTORY"] => match self.history {
+                Some(ref hb) => {
+                    hb.lock().unwrap().clear();
+                    for tb in &self.tier_histories {
+                        tb.lock().unwrap().clear();
+                    }
+                    if let Some(ref th) = self.topic_histories {
+                        th.clear();
+                    }
+                    "OK".to_owned()
+                }
+                None => "ERROR NO_HISTORY".to_owned(),
+            },
+            ["SET", "QLEN", n] => {
+                let Some(n) = n.parse::<usize>().ok().filter(|&n| n >= self.min_qlen) else {
+                    return "ERROR BAD_QLEN".to_owned();
+                };
+                let Some(channel) = self.channel.upgrade() else {
+                    return "ERROR EOF".to_owned();
+                };
+                let mut channel = channel.lock().unwrap();
+                if channel.closed {
+                    return "ERROR EOF".to_owned();
+                }
+                channel.replace(n);
+                "OK".to_owned()
+            }
+            ["QUIT"] => {
+                self.quit.notify_one();
+                "OK".to_owned()
+            }
+            _ => "ERROR UNKNOWN_COMMAND".to_owned(),
+        }
+    }
+
+    async fn handle(&self, conn: UnixStream) -> std::io::Result<()> {
+        let mut conn = BufReader::new(conn);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if conn.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let mut reply = self.execute(line.trim());
+            reply.push('\n');
+            conn.write_all(reply.as_bytes()).await?;
+        }
+    }
+}
+
+/// Serve admin connections one at a time until accepting fails
+pub async fn serve(listener: UnixListener, admin: Admin) {
+    loop {
+        let Ok((conn, _)) = listener.accept().await else {
+            log::event(
+                Level::Error,
+                "accept_error",
+                &[],
+                Some(format_args!("Error accepting admin connection")),
+            );
+            break;
+        };
+        let _ = admin.handle(conn).await;
+    }
+}
@@ -0,0 +1,107 @@
+//! `--announce-clients`: telling clients about other clients connecting and disconnecting
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex, Weak,
+    },
+    time::Instant,
+};
+
+use crate::{
+    broadcaster::Channel,
+    history::{History, TopicHistories},
+    json::Field,
+    stats::Stats,
+    Msg, MsgInner,
+};
+
+#[derive(Clone)]
+pub struct ClientEvent {
+    /// Number of the event, to skip events received both from history and from the channel
+    pub n: u64,
+    /// Client the event is about
+    pub id: u64,
+    pub addr: Arc<str>,
+    /// Disconnection reason, `None` for connection
+    pub reason: Option<&'static str>,
+}
+
+impl ClientEvent {
+    /// `kind` of the message with `--json`
+    pub fn kind(&self) -> &'static str {
+        match self.reason {
+            None => "client_connected",
+            Some(_) => "client_disconnected",
+        }
+    }
+
+    pub fn fields(&self) -> Vec<(&'static str, Field<'_>)> {
+        let mut fields = vec![
+            ("id", Field::Num(self.id)),
+            ("addr", Field::Str(&self.addr)),
+        ];
+        if let Some(reason) = self.reason {
+            fields.push(("reason", Field::Str(reason)));
+        }
+        fields
+    }
+}
+
+impl fmt::Display for ClientEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            None => write!(f, "CLIENT_CONNECTED {}", self.addr),
+            Some(reason) => write!(f, "CLIENT_DISCONNECTED {} reason={reason}", self.addr),
+        }
+    }
+}
+
+pub struct Announcer {
+    pub channel: Weak<Mutex<Channel>>,
+    /// Set with `--announce-clients-history`
+    pub history: Option<Arc<Mutex<History>>>,
+    /// `--history-tiers` buffers of the smaller tiers
+    pub tier_histories: Vec<Arc<Mutex<History>>>,
+    /// History of each topic or group
+    pub topic_histories: Option<Arc<TopicHistories>>,
+    pub stats: Arc<Stats>,
+    /// Number of the next event
+    pub counter: AtomicU64,
+}
+
+impl Announcer {
+    /// Broadcast client event. It carries the sequence number of the next line.
+    pub fn announce(&self, id: u64, addr: Arc<str>, reason: Option<&'static str>) {
+        let Some(channel) = self.channel.upgrade() else {
+            return;
+        };
+        // Hold history lock while sending, so that events are in the same order in both
+        let mut history = self.history.as_ref().map(|hb| hb.lock().unwrap());
+        let event = ClientEvent {
+            n: self.counter.fetch_add(1, Relaxed),
+            id,
+            addr,
+            reason,
+        };
+        let msg = Msg {
+            ts: Instant::now(),
+            inner: MsgInner::ClientEvent(event),
+            seqn: self.stats.next_seqn.load(Relaxed),
+            target: None,
+            topic: None,
+            repeat_count: 0,
+        };
+        if let Some(ref mut hb) = history {
+            hb.push(msg.clone());
+            for tb in &self.tier_histories {
+                tb.lock().unwrap().push(msg.clone());
+            }
+            if let Some(ref th) = self.topic_histories {
+                th.push(&msg);
+            }
+        }
+        channel.lock().unwrap().send(msg);
+    }
+}
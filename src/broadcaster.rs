@@ -0,0 +1,611 @@
+//! Sending lines read from input to the broadcast channel and history buffer
+
+use std::{
+    collections::VecDeque,
+    sync::{atomic::Ordering::Relaxed, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::sync::broadcast;
+
+use crate::{
+    history::{History, TopicHistories},
+    json::Field,
+    loadbalance::LoadBalancer,
+    log,
+    regex::Regex,
+    seqnfile::SeqnFile,
+    spill::Spill,
+    stats::Stats,
+    topic, Msg, MsgInner, TokenBucket,
+};
+
+/// Drops lines not matching a regex
+pub struct LineFilter {
+    pub regex: Regex,
+    /// Line separator, excluded from matching
+    pub separator: u8,
+    /// Whether dropped lines still consume sequence numbers
+    pub count_seqn: bool,
+}
+
+/// Broadcast channel sender. `SET QLEN` admin command replaces it with a channel of different
+/// capacity, after which receivers of the old channel get `Closed` and should resubscribe.
+pub struct Channel {
+    pub tx: broadcast::Sender<Msg>,
+    pub qlen: usize,
+    /// Incremented each time the channel is replaced
+    pub generation: u64,
+    /// `--forward-stdin-eof-as-disconnect`: set after stdin EOF, the sender no longer has receivers
+    pub closed: bool,
+}
+
+impl Channel {
+    pub fn new(qlen: usize) -> Self {
+        Self {
+            tx: broadcast::Sender::new(qlen),
+            qlen,
+            generation: 0,
+            closed: false,
+        }
+    }
+
+    pub fn send(&self, msg: Msg) {
+        let _ = self.tx.send(msg);
+    }
+
+    pub fn replace(&mut self, qlen: usize) {
+        self.tx = broadcast::Sender::new(qlen);
+        self.qlen = qlen;
+        self.generation += 1;
+    }
+
+    /// Disconnect all receivers after they get already sent messages. Unlike [`Channel::replace`],
+    /// the generation stays the same, so receivers stop instead of resubscribing.
+    pub fn close(&mut self) {
+        self.tx = broadcast::Sender::new(1);
+        self.closed = true;
+    }
+}
+
+/// `--backpressure-sleep-strategy`: how long to sleep between checks of a full queue
+#[derive(Clone, Copy)]
+pub enum SleepStrategy {
+    /// Start at 1 µs, doubling up to 65536 µs
+    Exponential,
+    /// Start at `start` µs, adding `step` µs up to 65536 µs
+    Linear { start: u64, step: u64 },
+    /// Always sleep this number of µs
+    Constant(u64),
+}
+
+impl SleepStrategy {
+    const MAX_MICROS: u64 = 65536;
+
+    fn first(self) -> Duration {
+        Duration::from_micros(match self {
+            SleepStrategy::Exponential => 1,
+            SleepStrategy::Linear { start, .. } => start,
+            SleepStrategy::Constant(us) => us,
+        })
+    }
+
+    fn next(self, wait: Duration) -> Duration {
+        let us = wait.as_micros() as u64;
+        let us = match self {
+            SleepStrategy::Exponential if us < Self::MAX_MICROS => us * 2,
+            SleepStrategy::Linear { step, .. } if us < Self::MAX_MICROS => {
+                (us + step).min(Self::MAX_MICROS)
+            }
+            _ => us,
+        };
+        Duration::from_micros(us)
+    }
+}
+
+/// Drops lines identical to one of recently sent lines
+pub struct Dedup {
+    pub recent: VecDeque<Bytes>,
+    pub window: usize,
+    /// Whether dropped lines still consume sequence numbers
+    pub count_seqn: bool,
+}
+
+impl Dedup {
+    pub fn new(window: usize, count_seqn: bool) -> Self {
+        Self {
+            recent: VecDeque::with_capacity(window),
+            window,
+            count_seqn,
+        }
+    }
+
+    /// Returns false if the line is a duplicate, otherwise remembers it
+    fn check(&mut self, content: &Bytes) -> bool {
+        if self.recent.contains(content) {
+            return false;
+        }
+        if self.recent.len() >= self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(content.clone());
+        true
+    }
+}
+
+pub struct Broadcaster {
+    pub channel: Arc<Mutex<Channel>>,
+    pub backpressure: bool,
+    /// Fraction of queue length at which backpressure activates
+    pub backpressure_threshold: f64,
+    pub announce_backpressure_duration: bool,
+    pub backpressure_sleep: SleepStrategy,
+    pub history: Option<Arc<Mutex<History>>>,
+    /// `--history-tiers` buffers of the smaller tiers, `history` is the largest one
+    pub tier_histories: Vec<Arc<Mutex<History>>>,
+    /// History of each `--topic-prefix` topic or `--route` group
+    pub topic_histories: Option<Arc<TopicHistories>>,
+    pub stats: Arc<Stats>,
+    pub throttle: Option<TokenBucket>,
+    pub filter: Option<LineFilter>,
+    pub dedup: Option<Dedup>,
+    pub loadbalancer: Option<Arc<LoadBalancer>>,
+    /// `--topic-prefix` or `--route`
+    pub topics: Option<Arc<topic::Router>>,
+    /// Sequence number of the next line
+    pub seqn: u64,
+    pub seqn_file: Option<SeqnFile>,
+    /// `--spill-dir` overflow files
+    pub spill: Option<Spill>,
+}
+
+impl Broadcaster {
+    fn advance_seqn(&mut self) {
+        self.seqn += 1;
+        self.stats.next_seqn.store(self.seqn, Relaxed);
+        if let Some(ref mut f) = self.seqn_file {
+            f.advance(self.seqn);
+        }
+    }
+
+    fn msg(&self, ts: Instant, inner: MsgInner) -> Msg {
+        Msg {
+            ts,
+            inner,
+            seqn: self.seqn,
+            target: None,
+            topic: None,
+            repeat_count: 0,
+        }
+    }
+
+    /// Apply `--filter` and `--dedup`. Returns false if the line should be dropped.
+    fn admit(&mut self, content: &Bytes) -> bool {
+        if let Some(ref f) = self.filter {
+            let line = content.strip_suffix(&[f.separator]).unwrap_or(content);
+            if !f.regex.is_match(line) {
+                if f.count_seqn {
+                    self.advance_seqn();
+                }
+                return false;
+            }
+        }
+        if let Some(ref mut d) = self.dedup {
+            if !d.check(content) {
+                if d.count_seqn {
+                    self.advance_seqn();
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Take a throttling token or return how long to wait for it
+    fn throttle_wait(&mut self) -> Option<Duration> {
+        self.throttle.as_mut()?.try_take(Instant::now()).err()
+    }
+
+    /// Build content message and record it in history
+    fn prepare(&mut self, ts: Instant, content: Bytes) -> Msg {
+        let topic = self.topics.as_ref().and_then(|t| t.topic(&content));
+        let mut content_msg = self.msg(ts, MsgInner::Content(content));
+        content_msg.topic = topic;
+        if let Some(ref lb) = self.loadbalancer {
+            content_msg.target = lb.pick();
+        }
+        self.record(&content_msg);
+        content_msg
+    }
+
+    /// Add the message to history buffers
+    fn record(&self, msg: &Msg) {
+        if let Some(ref hb) = self.history {
+            hb.lock().unwrap().push(msg.clone());
+        }
+        for hb in &self.tier_histories {
+            hb.lock().unwrap().push(msg.clone());
+        }
+        if let Some(ref th) = self.topic_histories {
+            th.push(msg);
+        }
+    }
+
+    fn queue_full(&self) -> bool {
+        if !self.backpressure {
+            return false;
+        }
+        let ch = self.channel.lock().unwrap();
+        let limit = (ch.qlen as f64 * self.backpressure_threshold) as usize;
+        ch.tx.len() >= limit.clamp(1, ch.qlen - 1)
+    }
+
+    fn begin_backpressure(&self, ts: Instant) {
+        log::event(
+            log::Level::Warn,
+            "backpressure",
+            &[("seqn", Field::Num(self.seqn))],
+            None,
+        );
+        if !self.announce_backpressure_duration {
+            self.send(self.msg(ts, MsgInner::Backpressure(None)));
+        }
+        self.stats.backpressure_active.store(true, Relaxed);
+    }
+
+    fn end_backpressure(&self, sleep_start: Instant) {
+        self.stats.backpressure_active.store(false, Relaxed);
+        if self.announce_backpressure_duration {
+            let duration = sleep_start.elapsed();
+            self.send(self.msg(Instant::now(), MsgInner::Backpressure(Some(duration))));
+        }
+    }
+
+    fn send(&self, msg: Msg) {
+        let ch = self.channel.lock().unwrap();
+        ch.send(msg);
+        self.stats.queue_depth.store(ch.tx.len(), Relaxed);
+    }
+
+    /// Whether the queue has no room for another message without evicting unread ones
+    fn queue_at_capacity(&self) -> bool {
+        let ch = self.channel.lock().unwrap();
+        ch.tx.len() >= ch.qlen
+    }
+
+    /// Send the message, or append it to `--spill-dir` file if the queue is full
+    /// or earlier messages are still waiting there
+    fn deliver(&mut self, msg: Msg) {
+        let full = self.spill.is_some() && self.queue_at_capacity();
+        if let Some(ref mut spill) = self.spill {
+            if full || spill.active() {
+                match spill.write(&msg) {
+                    Ok(Some(path)) => {
+                        log::event(
+                            log::Level::Warn,
+                            "spill_begin",
+                            &[
+                                ("seqn", Field::Num(msg.seqn)),
+                                ("path", Field::Str(&path.to_string_lossy())),
+                            ],
+                            None,
+                        );
+                        return;
+                    }
+                    Ok(None) => return,
+                    Err(e) => log::event(
+                        log::Level::Error,
+                        "spill_error",
+                        &[("error", Field::Str(&e.to_string()))],
+                        Some(format_args!("Writing to --spill-dir: {e}")),
+                    ),
+                }
+            }
+        }
+        self.send(msg);
+    }
+
+    /// Whether `--spill-dir` holds messages not re-injected into the queue yet
+    pub fn spill_pending(&self) -> bool {
+        self.spill.as_ref().is_some_and(Spill::active)
+    }
+
+    /// Re-inject spilled messages into the queue while there is room for them.
+    /// Returns whether any messages were re-injected.
+    pub fn drain_spill(&mut self) -> bool {
+        let mut drained = false;
+        loop {
+            let full = self.queue_at_capacity();
+            let Some(ref mut spill) = self.spill else {
+                return drained;
+            };
+            if full || !spill.active() {
+                return drained;
+            }
+            let msg = match spill.read() {
+                Ok(Some(msg)) => msg,
+                Ok(None) => return drained,
+                Err(e) => {
+                    log::event(
+                        log::Level::Error,
+                        "spill_error",
+                        &[("error", Field::Str(&e.to_string()))],
+                        Some(format_args!("Reading from --spill-dir: {e}")),
+                    );
+                    spill.discard();
+                    return drained;
+                }
+            };
+            if !spill.active() {
+                log::event(
+                    log::Level::Info,
+                    "spill_end",
+                    &[("seqn", Field::Num(msg.seqn))],
+                    None,
+                );
+            }
+            self.send(msg);
+            drained = true;
+        }
+    }
+
+    fn publish(&mut self, content_msg: Msg) {
+        self.deliver(content_msg);
+        self.stats.lines_total.fetch_add(1, Relaxed);
+        self.advance_seqn();
+    }
+
+    /// Send a line to clients and history, blocking if throttling or backpressure is active.
+    /// The lock is not held while waiting, so that priority lines and other inputs can be sent
+    /// meanwhile. The line gets its sequence number only after waiting.
+    pub fn send_line(this: &Mutex<Self>, content: Bytes) {
+        if !this.lock().unwrap().admit(&content) {
+            return;
+        }
+        loop {
+            let wait = this.lock().unwrap().throttle_wait();
+            match wait {
+                Some(wait) => std::thread::sleep(wait),
+                None => break,
+            }
+        }
+
+        let ts = Instant::now();
+        if this.lock().unwrap().queue_full() {
+            this.lock().unwrap().begin_backpressure(ts);
+            let sleep_start = Instant::now();
+            let strategy = this.lock().unwrap().backpressure_sleep;
+            let mut wait = strategy.first();
+            while this.lock().unwrap().queue_full() {
+                std::thread::sleep(wait);
+                wait = strategy.next(wait);
+            }
+            this.lock().unwrap().end_backpressure(sleep_start);
+        }
+        let mut bc = this.lock().unwrap();
+        let content_msg = bc.prepare(ts, content);
+        bc.publish(content_msg);
+    }
+
+    /// Same as [`Broadcaster::send_line`], but waits for throttling and backpressure asynchronously
+    pub async fn send_line_async(this: &Mutex<Self>, content: Bytes) {
+        if !this.lock().unwrap().admit(&content) {
+            return;
+        }
+        loop {
+            let wait = this.lock().unwrap().throttle_wait();
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => break,
+            }
+        }
+
+        let ts = Instant::now();
+        if this.lock().unwrap().queue_full() {
+            this.lock().unwrap().begin_backpressure(ts);
+            let sleep_start = Instant::now();
+            let strategy = this.lock().unwrap().backpressure_sleep;
+            let mut wait = strategy.first();
+            while this.lock().unwrap().queue_full() {
+                tokio::time::sleep(wait).await;
+                wait = strategy.next(wait);
+            }
+            this.lock().unwrap().end_backpressure(sleep_start);
+        }
+        let mut bc = this.lock().unwrap();
+        let content_msg = bc.prepare(ts, content);
+        bc.publish(content_msg);
+    }
+
+    /// Send a line to clients and history immediately, without throttling or backpressure
+    pub fn send_priority_line(&mut self, content: Bytes) {
+        let topic = self.topics.as_ref().and_then(|t| t.topic(&content));
+        let mut msg = self.msg(Instant::now(), MsgInner::Content(content));
+        msg.topic = topic;
+        self.record(&msg);
+        self.publish(msg);
+    }
+
+    pub fn send_eof(&mut self) {
+        log::event(
+            log::Level::Info,
+            "stdin_eof",
+            &[("seqn", Field::Num(self.seqn))],
+            None,
+        );
+        let msg = self.msg(Instant::now(), MsgInner::Eof);
+        self.deliver(msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokenBucket;
+
+    fn sleeps(strategy: SleepStrategy, n: usize) -> Vec<u128> {
+        std::iter::successors(Some(strategy.first()), |&d| Some(strategy.next(d)))
+            .take(n)
+            .map(|d| d.as_micros())
+            .collect()
+    }
+
+    #[test]
+    fn sleep_strategies() {
+        let s = sleeps(SleepStrategy::Exponential, 20);
+        assert_eq!(s[..4], [1, 2, 4, 8]);
+        assert_eq!(s[16..], [65536; 4]);
+
+        let s = sleeps(
+            SleepStrategy::Linear {
+                start: 0,
+                step: 30000,
+            },
+            5,
+        );
+        assert_eq!(s, [0, 30000, 60000, 65536, 65536]);
+        let s = sleeps(
+            SleepStrategy::Linear {
+                start: 100000,
+                step: 1,
+            },
+            2,
+        );
+        assert_eq!(s, [100000, 100000]);
+
+        assert_eq!(sleeps(SleepStrategy::Constant(250), 3), [250; 3]);
+        assert_eq!(sleeps(SleepStrategy::Constant(100000), 2), [100000; 2]);
+    }
+
+    fn broadcaster(throttle: Option<TokenBucket>) -> Broadcaster {
+        Broadcaster {
+            channel: Arc::new(Mutex::new(Channel::new(16))),
+            backpressure: false,
+            backpressure_threshold: 1.0,
+            announce_backpressure_duration: false,
+            backpressure_sleep: SleepStrategy::Exponential,
+            history: None,
+            tier_histories: vec![],
+            topic_histories: None,
+            stats: Arc::new(Stats::new(Instant::now(), false, false)),
+            throttle,
+            filter: None,
+            dedup: None,
+            loadbalancer: None,
+            topics: None,
+            seqn: 0,
+            seqn_file: None,
+            spill: None,
+        }
+    }
+
+    fn content(msg: Msg) -> (u64, Bytes) {
+        match msg.inner {
+            MsgInner::Content(b) => (msg.seqn, b),
+            _ => panic!("expected content message"),
+        }
+    }
+
+    #[test]
+    fn eof_through_full_queue() {
+        let dir = std::env::temp_dir().join(format!("stdintap-spill-eof-{}", std::process::id()));
+        let mut bc = broadcaster(None);
+        bc.spill = Some(Spill::new(dir.clone(), Instant::now()).unwrap());
+        bc.channel.lock().unwrap().replace(4);
+        let mut rx = bc.channel.lock().unwrap().tx.subscribe();
+        let bc = Mutex::new(bc);
+        for i in 0..10 {
+            Broadcaster::send_line(&bc, Bytes::from(format!("{i}\n")));
+        }
+        bc.lock().unwrap().send_eof();
+        assert!(bc.lock().unwrap().spill_pending());
+
+        // What shutdown does: drain the spill as the client reads, and only then close the channel
+        let mut received = vec![];
+        while bc.lock().unwrap().spill_pending() {
+            received.push(rx.try_recv().unwrap());
+            bc.lock().unwrap().drain_spill();
+        }
+        bc.lock().unwrap().channel.lock().unwrap().close();
+        while let Ok(msg) = rx.try_recv() {
+            received.push(msg);
+        }
+        let eof = received.pop().unwrap();
+        assert!(matches!(eof.inner, MsgInner::Eof));
+        assert_eq!(eof.seqn, 10);
+        let lines: Vec<_> = received.into_iter().map(content).collect();
+        let expected: Vec<_> = (0..10)
+            .map(|i| (i, Bytes::from(format!("{i}\n"))))
+            .collect();
+        assert_eq!(lines, expected);
+        let _ = std::fs::remove_dir(dir);
+    }
+
+    #[test]
+    fn priority_line_bypasses_throttle_wait() {
+        let bc = broadcaster(Some(TokenBucket::new(4.0, 1.0)));
+        let mut rx = bc.channel.lock().unwrap().tx.subscribe();
+        let bc = Arc::new(Mutex::new(bc));
+
+        let sender = {
+            let bc = bc.clone();
+            std::thread::spawn(move || {
+                Broadcaster::send_line(&bc, Bytes::from_static(b"first\n"));
+                // Waits about 250 ms for a token
+                Broadcaster::send_line(&bc, Bytes::from_static(b"second\n"));
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        bc.lock()
+            .unwrap()
+            .send_priority_line(Bytes::from_static(b"priority\n"));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        sender.join().unwrap();
+
+        assert_eq!(content(rx.try_recv().unwrap()), (0, "first\n".into()));
+        assert_eq!(content(rx.try_recv().unwrap()), (1, "priority\n".into()));
+        assert_eq!(content(rx.try_recv().unwrap()), (2, "second\n".into()));
+    }
+
+    #[test]
+    fn tier_histories_are_bounded_separately() {
+        let mut bc = broadcaster(None);
+        let history = |n| Arc::new(Mutex::new(History::new(n, None, false, false)));
+        bc.history = Some(history(5));
+        bc.tier_histories = vec![history(2), history(3)];
+        let bc = Mutex::new(bc);
+        for line in ["1\n", "2\n", "3\n", "4\n", "5\n", "6\n"] {
+            Broadcaster::send_line(&bc, Bytes::from_static(line.as_bytes()));
+        }
+        let bc = bc.lock().unwrap();
+        let oldest = |hb: &Arc<Mutex<History>>| {
+            let hb = hb.lock().unwrap();
+            (hb.len(), hb.front().unwrap().seqn)
+        };
+        assert_eq!(oldest(bc.history.as_ref().unwrap()), (5, 1));
+        assert_eq!(oldest(&bc.tier_histories[0]), (2, 4));
+        assert_eq!(oldest(&bc.tier_histories[1]), (3, 3));
+    }
+
+    #[test]
+    fn close_disconnects_receivers_while_broadcaster_is_alive() {
+        let bc = Mutex::new(broadcaster(None));
+        let channel = bc.lock().unwrap().channel.clone();
+        let mut rx = channel.lock().unwrap().tx.subscribe();
+        Broadcaster::send_line(&bc, Bytes::from_static(b"last\n"));
+        bc.lock().unwrap().send_eof();
+        channel.lock().unwrap().close();
+
+        assert_eq!(content(rx.try_recv().unwrap()), (0, "last\n".into()));
+        assert!(matches!(rx.try_recv().unwrap().inner, MsgInner::Eof));
+        assert!(matches!(
+            rx.try_recv(),
+            Err(broadcast::error::TryRecvError::Closed)
+        ));
+        let ch = channel.lock().unwrap();
+        assert!(ch.closed);
+        assert_eq!(ch.generation, 0);
+    }
+}
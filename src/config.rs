@@ -0,0 +1,500 @@
+//! `--config-file`: command line options from a TOML file
+
+use std::{ffi::OsString, fmt::Write, path::Path};
+
+use clap::{ArgMatches, Command};
+
+/// Value of a top-level TOML key. Numbers are kept as their textual representation.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Str(String),
+    Bool(bool),
+    Array(Vec<String>),
+}
+
+/// Parse a basic or literal TOML string at the beginning of `s`, returning it and the rest of input
+fn parse_string(s: &str) -> Result<(String, &str), String> {
+    let quote = s.chars().next().ok_or("expected a string")?;
+    let mut out = String::new();
+    let mut chars = s[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((out, &s[1 + i + 1..])),
+            '\\' if quote == '"' => match chars.next().map(|x| x.1) {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('r') => out.push('\r'),
+                Some('0') => out.push('\0'),
+                Some('\\') => out.push('\\'),
+                Some('"') => out.push('"'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).map(|x| x.1).collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid unicode escape \\u{hex}"))?;
+                    out.push(c);
+                }
+                x => {
+                    return Err(format!(
+                        "unsupported escape sequence \\{}",
+                        x.unwrap_or(' ')
+                    ))
+                }
+            },
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_owned())
+}
+
+/// Parse a scalar value (string, number or boolean) at the beginning of `s`
+fn parse_scalar(s: &str) -> Result<(Value, &str), String> {
+    if s.starts_with('"') || s.starts_with('\'') {
+        let (x, rest) = parse_string(s)?;
+        return Ok((Value::Str(x), rest));
+    }
+    let end = s
+        .find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace())
+        .unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        w if !w.is_empty() && w.replace('_', "").parse::<f64>().is_ok() => {
+            Value::Str(w.replace('_', ""))
+        }
+        w => return Err(format!("invalid value `{w}`")),
+    };
+    Ok((value, rest))
+}
+
+fn parse_value(s: &str) -> Result<(Value, &str), String> {
+    let Some(mut s) = s.strip_prefix('[') else {
+        return parse_scalar(s);
+    };
+    let mut items = Vec::new();
+    loop {
+        s = s.trim_start();
+        if let Some(rest) = s.strip_prefix(']') {
+            return Ok((Value::Array(items), rest));
+        }
+        if s.is_empty() {
+            return Err("unterminated array".to_owned());
+        }
+        let (item, rest) = parse_scalar(s)?;
+        items.push(match item {
+            Value::Str(x) => x,
+            Value::Bool(x) => x.to_string(),
+            Value::Array(_) => unreachable!(),
+        });
+        s = rest.trim_start();
+        s = s.strip_prefix(',').unwrap_or(s);
+    }
+}
+
+/// Parse a bare or quoted key at the beginning of `s`, returning it and the rest of input
+fn parse_key(s: &str) -> Result<(String, &str), String> {
+    if s.starts_with('"') || s.starts_with('\'') {
+        return parse_string(s);
+    }
+    let end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(s.len());
+    if end == 0 {
+        return Err("expected a key".to_owned());
+    }
+    Ok((s[..end].to_owned(), &s[end..]))
+}
+
+/// Parse a flat TOML document. Tables, dotted keys and multi-line values are not supported.
+fn parse_toml(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut entries = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let err = |e: String| format!("line {}: {e}", n + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(err("tables are not supported".to_owned()));
+        }
+        let (key, rest) = parse_key(line).map_err(err)?;
+        let value = rest
+            .trim_start()
+            .strip_prefix('=')
+            .ok_or_else(|| err("expected `key = value`".to_owned()))?;
+        let key = key.replace('_', "-");
+        let (value, rest) = parse_value(value.trim_start()).map_err(err)?;
+        let rest = rest.trim_start();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(err(format!("unexpected `{rest}`")));
+        }
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+/// Find value of `--config-file` among raw command line arguments
+fn find_config_file(args: &[OsString]) -> Option<OsString> {
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        if a == "--" {
+            break;
+        }
+        if a == "--config-file" {
+            return it.next().cloned();
+        }
+        if let Some(x) = a.to_str().and_then(|a| a.strip_prefix("--config-file=")) {
+            return Some(x.into());
+        }
+    }
+    None
+}
+
+/// Convert config file entries to command line arguments: options and positional values separately
+fn to_args(
+    cmd: &Command,
+    entries: Vec<(String, Value)>,
+) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    for (key, value) in entries {
+        let Some(arg) = cmd
+            .get_arguments()
+            .find(|a| a.get_long() == Some(&key) || a.get_id().as_str().replace('_', "-") == key)
+        else {
+            anyhow::bail!("unknown config file key `{key}`");
+        };
+        if key == "config-file" || key == "dump-config" {
+            anyhow::bail!("`{key}` cannot be set in a config file");
+        }
+        let values = match value {
+            Value::Str(x) => vec![x],
+            Value::Array(x) => x,
+            Value::Bool(x) if !arg.get_action().takes_values() => {
+                if x {
+                    options.push(format!("--{}", arg.get_long().unwrap_or(&key)));
+                }
+                continue;
+            }
+            Value::Bool(x) => vec![x.to_string()],
+        };
+        if arg.is_positional() {
+            positionals.extend(values);
+            continue;
+        }
+        // Repeat the option for each group of values it takes, e.g. for each `--output-file`
+        let per_occurrence = arg.get_num_args().map_or(1, |n| n.max_values()).max(1);
+        for chunk in values.chunks(per_occurrence) {
+            options.push(format!("--{}", arg.get_long().unwrap_or(&key)));
+            options.extend_from_slice(chunk);
+        }
+    }
+    Ok((options, positionals))
+}
+
+/// Parse command line, merging options from `--config-file` if specified. Command line options take precedence.
+pub fn get_matches(cmd: Command) -> anyhow::Result<ArgMatches> {
+    get_matches_from(cmd, std::env::args_os().collect())
+}
+
+fn get_matches_from(cmd: Command, mut args: Vec<OsString>) -> anyhow::Result<ArgMatches> {
+    let cmd = cmd.args_override_self(true);
+    let Some(path) = find_config_file(&args[1..]) else {
+        return Ok(cmd.get_matches_from(args));
+    };
+    let path = Path::new(&path);
+    let text =
+        std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+    let entries = parse_toml(&text).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+    let (options, positionals) = to_args(&cmd, entries)?;
+    args.splice(1..1, options.into_iter().map(OsString::from));
+
+    // Positional values from the config file apply unless given on the command line
+    let from_command_line = |m: &ArgMatches| {
+        cmd.get_positionals().any(|a| {
+            m.value_source(a.get_id().as_str()) == Some(clap::parser::ValueSource::CommandLine)
+        })
+    };
+    match cmd.clone().try_get_matches_from(&args) {
+        Ok(m) if positionals.is_empty() || from_command_line(&m) => Ok(m),
+        Err(e)
+            if positionals.is_empty()
+                || e.kind() != clap::error::ErrorKind::MissingRequiredArgument =>
+        {
+            e.exit()
+        }
+        _ => {
+            args.extend(positionals.into_iter().map(OsString::from));
+            Ok(cmd.get_matches_from(args))
+        }
+    }
+}
+
+fn toml_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn toml_value(s: &str) -> String {
+    if s == "true" || s == "false" || s.parse::<f64>().is_ok_and(f64::is_finite) {
+        s.to_owned()
+    } else {
+        toml_string(s)
+    }
+}
+
+/// Print resolved options as a config file
+pub fn dump(cmd: &Command, matches: &ArgMatches) {
+    print!("{}", render(cmd, matches));
+}
+
+fn render(cmd: &Command, matches: &ArgMatches) -> String {
+    let mut out = String::new();
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches!(id, "help" | "version" | "config_file" | "dump_config") {
+            continue;
+        }
+        let key = arg
+            .get_long()
+            .map(str::to_owned)
+            .unwrap_or_else(|| id.replace('_', "-"));
+        if !arg.get_action().takes_values() {
+            let _ = writeln!(out, "{key} = {}", matches.get_flag(id));
+            continue;
+        }
+        let Some(values) = matches.get_raw(id) else {
+            continue;
+        };
+        let values: Vec<String> = values.map(|v| v.to_string_lossy().into_owned()).collect();
+        if values.len() == 1 && arg.get_num_args().is_none_or(|n| n.max_values() == 1) {
+            let _ = writeln!(out, "{key} = {}", toml_value(&values[0]));
+        } else {
+            let items: Vec<String> = values.iter().map(|v| toml_value(v)).collect();
+            let _ = writeln!(out, "{key} = [{}]", items.join(", "));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    fn str(s: &str) -> Value {
+        Value::Str(s.to_owned())
+    }
+
+    fn parse_one(line: &str) -> Result<(String, Value), String> {
+        let mut entries = parse_toml(line)?;
+        assert_eq!(entries.len(), 1);
+        Ok(entries.remove(0))
+    }
+
+    fn command() -> Command {
+        crate::Args::command().args_override_self(true)
+    }
+
+    /// Command line arguments equivalent to the config file
+    fn config_args(text: &str) -> anyhow::Result<Vec<String>> {
+        let (mut options, positionals) = to_args(&command(), parse_toml(text).unwrap())?;
+        options.insert(0, "stdintap".to_owned());
+        options.extend(positionals);
+        Ok(options)
+    }
+
+    #[test]
+    fn strings_and_escapes() {
+        assert_eq!(
+            parse_one(r#"a = "x\ty\n\"q\" \\ \u00e9\u2603""#),
+            Ok(("a".to_owned(), str("x\ty\n\"q\" \\ é☃")))
+        );
+        assert_eq!(parse_one(r#"a = "\r\0""#).unwrap().1, str("\r\0"));
+        assert_eq!(parse_one(r#"a = """#).unwrap().1, str(""));
+        assert!(parse_one(r#"a = "\q""#).is_err());
+        assert!(parse_one(r#"a = "\u00zz""#).is_err());
+        assert!(parse_one(r#"a = "\ud800""#).is_err());
+        assert!(parse_one(r#"a = "unterminated"#).is_err());
+    }
+
+    #[test]
+    fn literal_strings() {
+        assert_eq!(parse_one(r"a = 'C:\path\n'").unwrap().1, str(r"C:\path\n"));
+        assert_eq!(
+            parse_one(r#"a = 'say "hi"'"#).unwrap().1,
+            str(r#"say "hi""#)
+        );
+        assert!(parse_one("a = 'unterminated").is_err());
+    }
+
+    #[test]
+    fn scalars_and_arrays() {
+        assert_eq!(parse_one("a = 1_000").unwrap().1, str("1000"));
+        assert_eq!(parse_one("a = -0.5").unwrap().1, str("-0.5"));
+        assert_eq!(parse_one("a = true").unwrap().1, Value::Bool(true));
+        assert_eq!(parse_one("a = false").unwrap().1, Value::Bool(false));
+        assert_eq!(
+            parse_one(r#"a = ["x", 'y', 3, true,]"#).unwrap().1,
+            Value::Array(vec!["x".into(), "y".into(), "3".into(), "true".into()])
+        );
+        assert_eq!(parse_one("a = []").unwrap().1, Value::Array(vec![]));
+        assert!(parse_one("a = [1, 2").is_err());
+        assert!(parse_one("a = yes").is_err());
+        assert!(parse_one("a =").is_err());
+    }
+
+    #[test]
+    fn comments_and_layout() {
+        let entries = parse_toml(
+            "# comment\n\n  qlen = 4 # after a number\ntimestamps = true# after a bool\n\
+             overrun_message = \"a # b\" # after a string\nsub = [1, 2] # after an array\n",
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            [
+                ("qlen".to_owned(), str("4")),
+                ("timestamps".to_owned(), Value::Bool(true)),
+                ("overrun-message".to_owned(), str("a # b")),
+                ("sub".to_owned(), Value::Array(vec!["1".into(), "2".into()])),
+            ]
+        );
+        assert!(parse_toml("qlen = 4 5").is_err());
+        assert!(parse_toml("[table]").is_err());
+        assert!(parse_toml("qlen").is_err());
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_one("a-b_c = 1").unwrap().0, "a-b-c");
+        assert_eq!(parse_one(r#""a=b" = 1"#).unwrap().0, "a=b");
+        assert_eq!(parse_one("'a b' = 1").unwrap().0, "a b");
+        assert_eq!(
+            parse_one(r#""qlen"=1"#).unwrap(),
+            ("qlen".to_owned(), str("1"))
+        );
+        assert!(parse_one("a.b = 1").is_err());
+        assert!(parse_one("= 1").is_err());
+        assert!(parse_one(r#""a = 1"#).is_err());
+    }
+
+    #[test]
+    fn booleans_on_flags_and_valued_options() {
+        // `--timestamps` is a flag, `--forward-stdin-eof-as-disconnect` takes a value
+        assert_eq!(
+            config_args("timestamps = true\nseqn = false\nforward_stdin_eof_as_disconnect = false")
+                .unwrap(),
+            [
+                "stdintap",
+                "--timestamps",
+                "--forward-stdin-eof-as-disconnect",
+                "false"
+            ]
+        );
+        assert_eq!(
+            config_args("qlen = 4\noutput_file = [\"a\", \"b\"]\nlisten_address = \"x.sock\"")
+                .unwrap(),
+            [
+                "stdintap",
+                "--qlen",
+                "4",
+                "--output-file",
+                "a",
+                "--output-file",
+                "b",
+                "x.sock"
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_and_forbidden_keys() {
+        let e = config_args("no_such_option = 1").unwrap_err().to_string();
+        assert!(
+            e.contains("unknown config file key `no-such-option`"),
+            "{e}"
+        );
+        assert!(config_args("config_file = \"x\"").is_err());
+        assert!(config_args("dump-config = true").is_err());
+    }
+
+    #[test]
+    fn positional_from_config_file() {
+        let path = std::env::temp_dir().join(format!("stdintap-test-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "listen-address = \"/tmp/from-config.sock\"\nqlen = 4\n",
+        )
+        .unwrap();
+        let listen = |extra: &[&str]| {
+            let mut args: Vec<OsString> = vec![
+                "stdintap".into(),
+                "--config-file".into(),
+                path.clone().into(),
+            ];
+            args.extend(extra.iter().map(OsString::from));
+            let m = get_matches_from(command(), args).unwrap();
+            let listen = m
+                .get_raw("listen_address")
+                .unwrap()
+                .next()
+                .unwrap()
+                .to_owned();
+            (listen, m.get_one::<usize>("qlen").copied())
+        };
+        assert_eq!(listen(&[]), ("/tmp/from-config.sock".into(), Some(4)));
+        assert_eq!(
+            listen(&["--qlen", "5", "/tmp/cli.sock"]),
+            ("/tmp/cli.sock".into(), Some(5))
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dump_round_trip() {
+        let matches = command()
+            .try_get_matches_from([
+                "stdintap",
+                "--timestamps",
+                "--qlen",
+                "4",
+                "--overrun-message",
+                "missed \"{n}\"\\n\ttab #x",
+                "--output-file",
+                "/tmp/a",
+                "--output-file",
+                "/tmp/b c",
+                "--forward-stdin-eof-as-disconnect",
+                "false",
+                "--replace-field",
+                "2",
+                "a(.)",
+                "$1",
+                "/tmp/x.sock",
+            ])
+            .unwrap();
+        let dumped = render(&command(), &matches);
+        assert!(dumped.contains("qlen = 4\n"), "{dumped}");
+        assert!(dumped.contains("timestamps = true\n"), "{dumped}");
+
+        let args = config_args(&dumped).unwrap();
+        let reparsed = command().try_get_matches_from(args).unwrap();
+        assert_eq!(render(&command(), &reparsed), dumped);
+    }
+}
@@ -0,0 +1,25 @@
+//! `stdintap diagnose` - health check client for a running instance
+
+use std::io::{BufRead, BufReader, Write};
+
+use clap::Parser;
+
+/// Connect to a running stdintap instance (started with `--diag`) and print its health report as JSON
+#[derive(Parser)]
+#[command(name = "stdintap diagnose", version)]
+pub struct DiagnoseArgs {
+    /// Address of the stdintap instance: TCP socket address, UNIX socket path or @abstract address
+    address: tokio_listener::ListenerAddress,
+}
+
+pub fn run(args: DiagnoseArgs) -> anyhow::Result<()> {
+    let mut conn = crate::input::connect(&args.address)?;
+    conn.write_all(b"DIAG\n\n")?;
+    let mut report = String::new();
+    BufReader::new(conn).read_line(&mut report)?;
+    if !report.starts_with('{') {
+        anyhow::bail!("No health report received. Is the instance started with --diag?");
+    }
+    print!("{report}");
+    Ok(())
+}
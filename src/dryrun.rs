@@ -0,0 +1,72 @@
+//! `--dry-run`: reporting lines that would be broadcast instead of serving clients
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{broadcaster::Channel, json, log, MsgInner};
+
+/// Subscribe to the channel right away, so that no lines are missed, and return the future
+/// printing `[seqn=N ts=X.XXXXXX bytes=N]` to stderr for each content line until EOF
+pub fn run(channel: &Arc<Mutex<Channel>>, begin: Instant) -> impl Future<Output = ()> {
+    let (mut rx, mut generation) = {
+        let ch = channel.lock().unwrap();
+        (ch.tx.subscribe(), ch.generation)
+    };
+    let channel = Arc::downgrade(channel);
+    async move {
+        loop {
+            let msg = match rx.recv().await {
+                Ok(msg) => msg,
+                Err(RecvError::Lagged(n)) => {
+                    log::event(
+                        log::Level::Warn,
+                        "dry_run_overrun",
+                        &[("n", json::Field::Num(n))],
+                        Some(format_args!("Warning: dry run missed {n} lines")),
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    // The channel may have been replaced by `SET QLEN` admin command
+                    let Some(ch) = channel.upgrade() else {
+                        break;
+                    };
+                    let ch = ch.lock().unwrap();
+                    if ch.generation == generation {
+                        break;
+                    }
+                    rx = ch.tx.subscribe();
+                    generation = ch.generation;
+                    continue;
+                }
+            };
+            let b = match msg.inner {
+                MsgInner::Content(b) => b,
+                MsgInner::Eof => break,
+                _ => continue,
+            };
+            let ts = msg.ts.saturating_duration_since(begin);
+            log::event(
+                log::Level::Info,
+                "dry_run_line",
+                &[
+                    ("seqn", json::Field::Num(msg.seqn)),
+                    ("line_ts", json::Field::Float(ts.as_secs_f64())),
+                    ("bytes", json::Field::Num(b.len() as u64)),
+                ],
+                Some(format_args!(
+                    "[seqn={} ts={}.{:06} bytes={}]",
+                    msg.seqn,
+                    ts.as_secs(),
+                    ts.subsec_micros(),
+                    b.len()
+                )),
+            );
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! `--encoding`: re-encoding line content for clients that cannot handle binary data
+
+use std::borrow::Cow;
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// Send lines as is
+    Raw,
+    /// Standard base64 with padding
+    Base64,
+    /// Lowercase hexadecimal
+    Hex,
+}
+
+/// Base64-encode `data`, passing each output character to `push`
+pub fn base64_into(data: &[u8], mut push: impl FnMut(u8)) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize]);
+            } else {
+                push(b'=');
+            }
+        }
+    }
+}
+
+fn hex_into(buf: &mut Vec<u8>, data: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for &b in data {
+        buf.push(DIGITS[usize::from(b >> 4)]);
+        buf.push(DIGITS[usize::from(b & 15)]);
+    }
+}
+
+impl Encoding {
+    /// Encode line content, which should not include the separator
+    pub fn encode(self, data: &[u8]) -> Cow<'_, [u8]> {
+        let mut buf = Vec::with_capacity(data.len() * 2);
+        match self {
+            Encoding::Raw => return Cow::Borrowed(data),
+            Encoding::Base64 => base64_into(data, |c| buf.push(c)),
+            Encoding::Hex => hex_into(&mut buf, data),
+        }
+        Cow::Owned(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base64(data: &[u8]) -> String {
+        let mut buf = String::new();
+        base64_into(data, |c| buf.push(char::from(c)));
+        buf
+    }
+
+    #[test]
+    fn base64_padding() {
+        // RFC 4648 test vectors
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn base64_alphabet() {
+        assert_eq!(base64(&[0, 0, 0]), "AAAA");
+        assert_eq!(base64(&[0xff, 0xff, 0xff]), "////");
+        assert_eq!(base64(&[0xfb, 0xef, 0xbe]), "++++");
+        assert_eq!(base64(&[0x00, 0x10, 0x83]), "ABCD");
+    }
+
+    #[test]
+    fn encodings() {
+        assert!(matches!(
+            Encoding::Raw.encode(b"\xff\n"),
+            Cow::Borrowed(b"\xff\n")
+        ));
+        assert_eq!(&*Encoding::Hex.encode(b""), b"");
+        assert_eq!(&*Encoding::Hex.encode(b"\x00\x0f\xa5\xff"), b"000fa5ff");
+        assert_eq!(&*Encoding::Base64.encode(b"\xff\n"), b"/wo=");
+    }
+}
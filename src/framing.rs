@@ -0,0 +1,135 @@
+//! `--binary-framing` output mode: length-prefixed frames instead of separated lines
+//!
+//! Content frame: 4-byte big-endian length, then payload (timestamp, sequence number and line content).
+//! Special frame: 4 zero bytes, then 1-byte tag, then 8-byte big-endian value.
+//! Content frames are never empty, so zero length unambiguously denotes a special frame.
+//!
+//! `--netstring` output mode uses the same payloads wrapped as `<length>:<payload>,`, with decimal length.
+//! Special messages are netstrings with text payloads like `OVERRUN 3` or `EOF`, which lack the trailing
+//! separator of content payloads.
+
+use std::{io::Write, pin::Pin};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Stdin EOF, value is exit code of `--exec` command or 0
+pub const TAG_EOF: u8 = b'E';
+/// Backpressure applied, value is its duration in milliseconds if known or 0
+pub const TAG_BACKPRESSURE: u8 = b'B';
+/// Lines missed by slow reading, value is their number
+pub const TAG_OVERRUN: u8 = b'O';
+/// Hello message, value is client number
+pub const TAG_HELLO: u8 = b'H';
+/// Start of lines re-sent on `REPLAY` request, value is their number
+pub const TAG_REPLAY: u8 = b'P';
+/// No lines for `--heartbeat` interval, value is sequence number of the last line sent
+pub const TAG_HEARTBEAT: u8 = b'K';
+/// Previous line was repeated in compacted history, value is number of repetitions
+pub const TAG_REPEATED: u8 = b'R';
+
+/// Text payload of a special netstring
+fn special_text(tag: u8, value: u64) -> String {
+    let name = match tag {
+        TAG_EOF if value == 0 => return "EOF".to_owned(),
+        TAG_EOF => "EOF",
+        TAG_BACKPRESSURE => "BACKPRESSURE",
+        TAG_OVERRUN => "OVERRUN",
+        TAG_HELLO => "HELLO",
+        TAG_REPLAY => "REPLAY",
+        TAG_HEARTBEAT => "HEARTBEAT",
+        TAG_REPEATED => "REPEATED",
+        _ => unreachable!("unknown special tag"),
+    };
+    format!("{name} {value}")
+}
+
+/// Write the payload as a frame, truncating it if it does not fit in binary frame length
+pub fn write_frame_sync(
+    w: &mut impl Write,
+    netstring: bool,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    if netstring {
+        write!(w, "{}:", payload.len())?;
+        w.write_all(payload)?;
+        return w.write_all(b",");
+    }
+    let len = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(&payload[..len as usize])
+}
+
+pub async fn write_frame(
+    mut conn: Pin<&mut impl AsyncWrite>,
+    netstring: bool,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    if netstring {
+        conn.write_all(format!("{}:", payload.len()).as_bytes())
+            .await?;
+        conn.write_all(payload).await?;
+        return conn.write_all(b",").await;
+    }
+    let len = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+    conn.write_all(&len.to_be_bytes()).await?;
+    conn.write_all(&payload[..len as usize]).await
+}
+
+pub async fn write_special(
+    mut conn: Pin<&mut impl AsyncWrite>,
+    netstring: bool,
+    tag: u8,
+    value: u64,
+) -> std::io::Result<()> {
+    if netstring {
+        return write_frame(conn, true, special_text(tag, value).as_bytes()).await;
+    }
+    let mut buf = [0u8; 4 + 1 + 8];
+    buf[4] = tag;
+    buf[5..].copy_from_slice(&value.to_be_bytes());
+    conn.write_all(&buf).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn binary_frames() {
+        let mut out = Vec::new();
+        write_frame(Pin::new(&mut out), false, b"hello\n")
+            .await
+            .unwrap();
+        write_special(Pin::new(&mut out), false, TAG_OVERRUN, 0x0102)
+            .await
+            .unwrap();
+        assert_eq!(
+            out,
+            b"\0\0\0\x06hello\n\0\0\0\0O\0\0\0\0\0\0\x01\x02".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn netstrings() {
+        let mut out = Vec::new();
+        write_frame(Pin::new(&mut out), true, b"hello\n")
+            .await
+            .unwrap();
+        write_frame(Pin::new(&mut out), true, b"").await.unwrap();
+        write_special(Pin::new(&mut out), true, TAG_OVERRUN, 3)
+            .await
+            .unwrap();
+        write_special(Pin::new(&mut out), true, TAG_EOF, 0)
+            .await
+            .unwrap();
+        write_special(Pin::new(&mut out), true, TAG_EOF, 2)
+            .await
+            .unwrap();
+        assert_eq!(out, b"6:hello\n,0:,9:OVERRUN 3,3:EOF,5:EOF 2,".as_slice());
+
+        let mut sync_out = Vec::new();
+        write_frame_sync(&mut sync_out, true, b"hello\n").unwrap();
+        write_frame_sync(&mut sync_out, false, b"hi").unwrap();
+        assert_eq!(sync_out, b"6:hello\n,\0\0\0\x02hi".as_slice());
+    }
+}
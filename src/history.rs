@@ -0,0 +1,664 @@
+//! Buffer of recent lines to be replayed to connecting clients
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::File,
+    io::{BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+
+use crate::{topic, Msg, MsgInner};
+
+pub struct History {
+    max_lines: usize,
+    max_bytes: Option<usize>,
+    entries: VecDeque<Msg>,
+    /// Total size of lines in `entries`
+    bytes: usize,
+    /// Optional index from seqn to absolute position (counting evicted entries) in the buffer
+    index: Option<BTreeMap<u64, u64>>,
+    /// Number of entries evicted so far
+    evicted: u64,
+    compact_identical_runs: bool,
+}
+
+fn msg_bytes(msg: &Msg) -> usize {
+    match msg.inner {
+        MsgInner::Content(ref b) => b.len(),
+        _ => 0,
+    }
+}
+
+impl History {
+    pub fn new(
+        max_lines: usize,
+        max_bytes: Option<usize>,
+        with_index: bool,
+        compact_identical_runs: bool,
+    ) -> Self {
+        Self {
+            max_lines,
+            max_bytes,
+            // Line count is not a meaningful bound with byte limit (it may be `usize::MAX`)
+            entries: VecDeque::with_capacity(if max_bytes.is_some() { 0 } else { max_lines }),
+            bytes: 0,
+            index: with_index.then(BTreeMap::new),
+            evicted: 0,
+            compact_identical_runs,
+        }
+    }
+
+    pub fn push(&mut self, msg: Msg) {
+        if self.compact_identical_runs {
+            if let Some(last) = self.entries.back_mut() {
+                if let (MsgInner::Content(a), MsgInner::Content(b)) = (&last.inner, &msg.inner) {
+                    if a == b && last.repeat_count < u32::MAX {
+                        last.repeat_count += 1;
+                        return;
+                    }
+                }
+            }
+        }
+        let size = msg_bytes(&msg);
+        let max_bytes = self.max_bytes.unwrap_or(usize::MAX);
+        while self.entries.len() >= self.max_lines || self.bytes + size > max_bytes {
+            if !self.evict_front() {
+                break;
+            }
+        }
+        if self.max_lines == 0 || size > max_bytes {
+            return;
+        }
+        // Other messages carry the sequence number of the next line, which is indexed instead
+        if let (Some(ref mut index), MsgInner::Content(_)) = (&mut self.index, &msg.inner) {
+            index.insert(msg.seqn, self.evicted + self.entries.len() as u64);
+        }
+        self.bytes += size;
+        self.entries.push_back(msg);
+    }
+
+    /// Remove the oldest entry. Returns false if there are none.
+    fn evict_front(&mut self) -> bool {
+        let Some(old) = self.entries.pop_front() else {
+            return false;
+        };
+        if let (Some(ref mut index), MsgInner::Content(_)) = (&mut self.index, &old.inner) {
+            index.remove(&old.seqn);
+        }
+        self.bytes -= msg_bytes(&old);
+        self.evicted += 1;
+        true
+    }
+
+    /// `--history-ttl`: remove entries older than `ttl`
+    pub fn expire(&mut self, ttl: Duration) {
+        while self.entries.front().is_some_and(|m| m.ts.elapsed() > ttl) {
+            self.evict_front();
+        }
+    }
+
+    /// Remove all entries. Sequence numbers continue from where they were.
+    pub fn clear(&mut self) {
+        self.evicted += self.entries.len() as u64;
+        self.entries.clear();
+        if let Some(ref mut index) = self.index {
+            index.clear();
+        }
+        self.bytes = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn front(&self) -> Option<&Msg> {
+        self.entries.front()
+    }
+
+    pub fn back(&self) -> Option<&Msg> {
+        self.entries.back()
+    }
+
+    /// Total size of lines stored in the buffer
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Index of the first entry containing lines with sequence number at least `seqn`
+    fn position(&self, seqn: u64) -> usize {
+        match self.index {
+            Some(ref index) => {
+                let Some((_, &abs)) = index.range(..=seqn).next_back() else {
+                    return 0;
+                };
+                let i = (abs - self.evicted) as usize;
+                if self.entries[i].last_seqn() >= seqn {
+                    i
+                } else {
+                    i + 1
+                }
+            }
+            None => self
+                .entries
+                .iter()
+                .take_while(|m| m.last_seqn() < seqn)
+                .count(),
+        }
+    }
+
+    /// Add lines read by `load_file`. Lines without sequence numbers get ones following the previous line.
+    /// Returns sequence number for the next line.
+    pub fn restore(
+        &mut self,
+        lines: Vec<SavedLine>,
+        mut next_seqn: u64,
+        topics: Option<&topic::Router>,
+    ) -> u64 {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        for line in lines {
+            let seqn = line.seqn.unwrap_or(next_seqn);
+            next_seqn = next_seqn.max(seqn + 1);
+            let age = line
+                .wall
+                .and_then(|w| wall_now.duration_since(w).ok())
+                .unwrap_or_default();
+            self.push(Msg {
+                ts: now.checked_sub(age).unwrap_or(now),
+                topic: topics.and_then(|t| t.topic(&line.content)),
+                inner: MsgInner::Content(line.content),
+                seqn,
+                target: None,
+                repeat_count: 0,
+            });
+        }
+        next_seqn
+    }
+
+    /// Write all lines to a file readable by `load_file`, replacing it atomically
+    pub fn save_file(&self, path: &Path, separator: u8) -> std::io::Result<()> {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(FILE_HEADER)?;
+        w.write_all(&[separator])?;
+        for msg in &self.entries {
+            let MsgInner::Content(ref content) = msg.inner else {
+                continue;
+            };
+            let wall = (wall_now - (now - msg.ts))
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            for seqn in msg.seqn..=msg.last_seqn() {
+                write!(
+                    w,
+                    "{seqn}\t{}.{:06}\t",
+                    wall.as_secs(),
+                    wall.subsec_micros()
+                )?;
+                w.write_all(content)?;
+            }
+        }
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Copy of entries containing lines with sequence number at least `seqn`.
+    /// Cloning is cheap, as line contents are reference-counted.
+    pub fn snapshot_from(&self, seqn: u64) -> VecDeque<Msg> {
+        self.entries.range(self.position(seqn)..).cloned().collect()
+    }
+
+    /// Sequence number after the line `n`-th from the end, where `--history-tail` replay starts.
+    /// `None` if there are not that many lines.
+    pub fn tail_seqn(&self, n: usize) -> Option<u64> {
+        tail_seqn(&self.entries, n)
+    }
+}
+
+/// [`History::tail_seqn`] for a copy of entries
+pub fn tail_seqn(entries: &VecDeque<Msg>, n: usize) -> Option<u64> {
+    entries
+        .iter()
+        .rev()
+        .filter(|msg| matches!(msg.inner, MsgInner::Content(_)))
+        .nth(n)
+        .map(|msg| msg.last_seqn() + 1)
+}
+
+/// `--history-snapshot-interval`: copy of history entries refreshed in background, so that
+/// connecting clients hold the history lock only to copy entries added since the last refresh
+#[derive(Default)]
+pub struct Snapshot(RwLock<Arc<Frozen>>);
+
+#[derive(Default)]
+struct Frozen {
+    entries: VecDeque<Msg>,
+    /// Absolute position (counting evicted entries) after the last entry
+    end: u64,
+}
+
+impl Snapshot {
+    pub fn refresh(&self, history: &Mutex<History>) {
+        let frozen = {
+            let hb = history.lock().unwrap();
+            Frozen {
+                entries: hb.entries.clone(),
+                end: hb.evicted + hb.entries.len() as u64,
+            }
+        };
+        *self.0.write().unwrap() = Arc::new(frozen);
+    }
+
+    /// Same as [`History::snapshot_from`]. Entries evicted since the last refresh are skipped.
+    /// The last entry is copied from history again, as newer lines may have been compacted into it.
+    pub fn copy_from(&self, history: &Mutex<History>, seqn: u64) -> VecDeque<Msg> {
+        let frozen = self.0.read().unwrap().clone();
+        let (evicted, start, delta) = {
+            let hb = history.lock().unwrap();
+            let start = frozen.end.saturating_sub(1).max(hb.evicted);
+            let delta: Vec<Msg> = hb
+                .entries
+                .range((start - hb.evicted) as usize..)
+                .cloned()
+                .collect();
+            (hb.evicted, start, delta)
+        };
+        let first = frozen.end - frozen.entries.len() as u64;
+        frozen
+            .entries
+            .iter()
+            .take(start.saturating_sub(first) as usize)
+            .skip(evicted.saturating_sub(first) as usize)
+            .chain(&delta)
+            .filter(|msg| msg.last_seqn() >= seqn)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Most topics getting their own history buffer
+const MAX_TOPICS: usize = 1024;
+
+/// History of each topic, the key is `None` for the default topic
+type TopicBuffers = HashMap<Option<Arc<str>>, Arc<Mutex<History>>>;
+
+/// `--topic-prefix` and `--route`: history buffer of each topic, so that lines of a busy topic
+/// do not evict lines of others. The shared history still has lines of all topics.
+pub struct TopicHistories {
+    max_lines: usize,
+    max_bytes: Option<usize>,
+    with_index: bool,
+    compact_identical_runs: bool,
+    buffers: Mutex<TopicBuffers>,
+}
+
+impl TopicHistories {
+    /// Each topic buffer has the limits of `History::new`
+    pub fn new(
+        max_lines: usize,
+        max_bytes: Option<usize>,
+        with_index: bool,
+        compact_identical_runs: bool,
+    ) -> Self {
+        Self {
+            max_lines,
+            max_bytes,
+            with_index,
+            compact_identical_runs,
+            buffers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Buffer of the topic, `None` if it had no lines yet
+    pub fn get(&self, topic: &Option<Arc<str>>) -> Option<Arc<Mutex<History>>> {
+        self.buffers.lock().unwrap().get(topic).cloned()
+    }
+
+    /// Buffer of the topic, created for its first line. Lines of topics beyond `MAX_TOPICS`
+    /// are only in the shared history.
+    fn get_or_create(&self, topic: &Option<Arc<str>>) -> Option<Arc<Mutex<History>>> {
+        let mut buffers = self.buffers.lock().unwrap();
+        if let Some(hb) = buffers.get(topic) {
+            return Some(hb.clone());
+        }
+        if buffers.len() >= MAX_TOPICS {
+            return None;
+        }
+        let hb = Arc::new(Mutex::new(History::new(
+            self.max_lines,
+            self.max_bytes,
+            self.with_index,
+            self.compact_identical_runs,
+        )));
+        buffers.insert(topic.clone(), hb.clone());
+        Some(hb)
+    }
+
+    /// Add a line to the buffer of its topic, or other message to all buffers
+    pub fn push(&self, msg: &Msg) {
+        if let MsgInner::Content(_) = msg.inner {
+            if let Some(hb) = self.get_or_create(&msg.topic) {
+                hb.lock().unwrap().push(msg.clone());
+            }
+            return;
+        }
+        let buffers: Vec<_> = self.buffers.lock().unwrap().values().cloned().collect();
+        for hb in buffers {
+            hb.lock().unwrap().push(msg.clone());
+        }
+    }
+
+    /// Same as [`History::restore`], for each line in the buffer of its topic
+    pub fn restore(&self, lines: &[SavedLine], mut next_seqn: u64, topics: &topic::Router) {
+        for line in lines {
+            let seqn = line.seqn.unwrap_or(next_seqn);
+            next_seqn = next_seqn.max(seqn + 1);
+            let line = SavedLine {
+                seqn: Some(seqn),
+                ..line.clone()
+            };
+            if let Some(hb) = self.get_or_create(&topics.topic(&line.content)) {
+                hb.lock().unwrap().restore(vec![line], seqn, Some(topics));
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        for hb in self.buffers.lock().unwrap().values() {
+            hb.lock().unwrap().clear();
+        }
+    }
+}
+
+/// First line of files written by `History::save_file`
+const FILE_HEADER: &[u8] = b"#stdintap-history v1";
+
+/// Line read from a history file
+#[derive(Clone)]
+pub struct SavedLine {
+    pub seqn: Option<u64>,
+    pub wall: Option<SystemTime>,
+    /// Content including the separator
+    pub content: Bytes,
+}
+
+fn parse_field<T: std::str::FromStr>(line: &[u8]) -> Option<(T, usize)> {
+    let t = line.iter().position(|&b| b == b'\t')?;
+    Some((std::str::from_utf8(&line[..t]).ok()?.parse().ok()?, t + 1))
+}
+
+/// Read saved history lines from a file.
+///
+/// Files written by `History::save_file` start with a header and have sequence number and
+/// wall clock time (seconds since UNIX epoch) before each line, separated by tabs.
+/// Otherwise each line may be prefixed by its sequence number and a tab, the same way as
+/// client output with `--seqn`. Lines without such prefix get no sequence number.
+pub fn load_file(path: &Path, separator: u8) -> std::io::Result<Vec<SavedLine>> {
+    let mut data = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut data)?;
+    if data.last().is_some_and(|&b| b != separator) {
+        data.push(separator);
+    }
+    let mut lines = split_lines(Bytes::from(data), separator);
+    if lines
+        .first()
+        .is_some_and(|l| l[..l.len() - 1] == *FILE_HEADER)
+    {
+        return lines
+            .drain(1..)
+            .map(|line| {
+                let (seqn, a) = parse_field::<u64>(&line).ok_or(ErrorKind::InvalidData)?;
+                let (wall, b) = parse_field::<f64>(&line[a..]).ok_or(ErrorKind::InvalidData)?;
+                let wall = Duration::try_from_secs_f64(wall).map_err(|_| ErrorKind::InvalidData)?;
+                Ok(SavedLine {
+                    seqn: Some(seqn),
+                    wall: Some(UNIX_EPOCH + wall),
+                    content: line.slice(a + b..),
+                })
+            })
+            .collect();
+    }
+    Ok(lines
+        .into_iter()
+        .map(|line| match parse_field::<u64>(&line) {
+            Some((seqn, t)) => SavedLine {
+                seqn: Some(seqn),
+                wall: None,
+                content: line.slice(t..),
+            },
+            None => SavedLine {
+                seqn: None,
+                wall: None,
+                content: line,
+            },
+        })
+        .collect())
+}
+
+/// Split data into lines including the separator. Trailing data without a separator is dropped.
+pub fn split_lines(data: Bytes, separator: u8) -> Vec<Bytes> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while let Some(i) = data[start..].iter().position(|&b| b == separator) {
+        lines.push(data.slice(start..start + i + 1));
+        start += i + 1;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(history: &Mutex<History>, seqn: u64, content: &'static str) {
+        history.lock().unwrap().push(Msg {
+            ts: Instant::now(),
+            inner: MsgInner::Content(Bytes::from_static(content.as_bytes())),
+            seqn,
+            target: None,
+            topic: None,
+            repeat_count: 0,
+        });
+    }
+
+    fn lines(entries: &VecDeque<Msg>) -> Vec<(u64, u32, Bytes)> {
+        entries
+            .iter()
+            .map(|msg| match msg.inner {
+                MsgInner::Content(ref b) => (msg.seqn, msg.repeat_count, b.clone()),
+                _ => panic!("expected content message"),
+            })
+            .collect()
+    }
+
+    /// Check `copy_from` against `snapshot_from` for all sequence numbers up to `max_seqn`
+    fn check(snapshot: &Snapshot, history: &Mutex<History>, max_seqn: u64) {
+        for seqn in 0..=max_seqn {
+            let expected = lines(&history.lock().unwrap().snapshot_from(seqn));
+            assert_eq!(
+                lines(&snapshot.copy_from(history, seqn)),
+                expected,
+                "seqn {seqn}"
+            );
+        }
+    }
+
+    fn topic_msg(seqn: u64, topic: Option<&str>, inner: MsgInner) -> Msg {
+        Msg {
+            ts: Instant::now(),
+            inner,
+            seqn,
+            target: None,
+            topic: topic.map(Arc::from),
+            repeat_count: 0,
+        }
+    }
+
+    fn seqns(hb: Option<Arc<Mutex<History>>>) -> Vec<u64> {
+        let hb = hb.expect("topic has history");
+        let hb = hb.lock().unwrap();
+        hb.snapshot_from(0).iter().map(|m| m.seqn).collect()
+    }
+
+    #[test]
+    fn busy_topic_does_not_evict_others() {
+        let th = TopicHistories::new(2, None, false, false);
+        let content = || MsgInner::Content(Bytes::from_static(b"x\n"));
+        th.push(&topic_msg(0, Some("quiet"), content()));
+        th.push(&topic_msg(1, None, content()));
+        for seqn in 2..10 {
+            th.push(&topic_msg(seqn, Some("busy"), content()));
+        }
+        th.push(&topic_msg(10, None, MsgInner::Eof));
+        assert_eq!(seqns(th.get(&Some("quiet".into()))), [0, 10]);
+        assert_eq!(seqns(th.get(&None)), [1, 10]);
+        assert_eq!(seqns(th.get(&Some("busy".into()))), [9, 10]);
+        assert!(th.get(&Some("other".into())).is_none());
+        th.clear();
+        assert_eq!(seqns(th.get(&None)), []);
+    }
+
+    #[test]
+    fn topic_count_is_limited() {
+        let th = TopicHistories::new(2, None, false, false);
+        for seqn in 0..MAX_TOPICS as u64 + 10 {
+            let topic = seqn.to_string();
+            let content = MsgInner::Content(Bytes::from_static(b"x\n"));
+            th.push(&topic_msg(seqn, Some(&topic), content));
+        }
+        assert!(th.get(&Some("0".into())).is_some());
+        assert!(th.get(&Some((MAX_TOPICS - 1).to_string().into())).is_some());
+        assert!(th.get(&Some(MAX_TOPICS.to_string().into())).is_none());
+    }
+
+    #[test]
+    fn restore_into_topics() {
+        let th = TopicHistories::new(10, None, false, false);
+        let line = |seqn, content: &'static str| SavedLine {
+            seqn,
+            wall: None,
+            content: Bytes::from_static(content.as_bytes()),
+        };
+        let lines = [
+            line(None, "a:1\n"),
+            line(None, "b:2\n"),
+            line(Some(7), "a:3\n"),
+            line(None, "4\n"),
+        ];
+        th.restore(&lines, 5, &topic::Router::Prefix(b':'));
+        assert_eq!(seqns(th.get(&Some("a".into()))), [5, 7]);
+        assert_eq!(seqns(th.get(&Some("b".into()))), [6]);
+        assert_eq!(seqns(th.get(&None)), [8]);
+        let hb = th.get(&Some("a".into())).unwrap();
+        assert_eq!(
+            hb.lock().unwrap().back().unwrap().topic.as_deref(),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn restore_into_routes() {
+        let th = TopicHistories::new(10, None, false, false);
+        let line = |content: &'static str| SavedLine {
+            seqn: None,
+            wall: None,
+            content: Bytes::from_static(content.as_bytes()),
+        };
+        let router = topic::Router::Routes {
+            routes: vec![(crate::regex::Regex::new("ERROR$").unwrap(), "errors".into())],
+            separator: b"\n".to_vec(),
+        };
+        th.restore(&[line("disk ERROR\n"), line("ok\n")], 0, &router);
+        assert_eq!(seqns(th.get(&Some("errors".into()))), [0]);
+        assert_eq!(seqns(th.get(&None)), [1]);
+    }
+
+    #[test]
+    fn snapshot_before_refresh() {
+        let history = Mutex::new(History::new(4, None, false, false));
+        let snapshot = Snapshot::default();
+        push(&history, 0, "a\n");
+        push(&history, 1, "b\n");
+        check(&snapshot, &history, 3);
+    }
+
+    #[test]
+    fn snapshot_with_pushes_and_evictions() {
+        let history = Mutex::new(History::new(4, None, true, false));
+        let snapshot = Snapshot::default();
+        let contents = ["a\n", "b\n", "c\n", "d\n", "e\n", "f\n", "g\n"];
+        for (seqn, content) in contents.iter().enumerate() {
+            push(&history, seqn as u64, content);
+            check(&snapshot, &history, 8);
+            if seqn % 3 == 0 {
+                snapshot.refresh(&history);
+                check(&snapshot, &history, 8);
+            }
+        }
+        // Everything in the snapshot has been evicted since the refresh
+        snapshot.refresh(&history);
+        for seqn in 7..12 {
+            push(&history, seqn, "h\n");
+        }
+        check(&snapshot, &history, 12);
+    }
+
+    #[test]
+    fn snapshot_after_clear() {
+        let history = Mutex::new(History::new(8, None, false, false));
+        let snapshot = Snapshot::default();
+        push(&history, 0, "a\n");
+        push(&history, 1, "b\n");
+        snapshot.refresh(&history);
+        history.lock().unwrap().clear();
+        check(&snapshot, &history, 3);
+        push(&history, 2, "c\n");
+        check(&snapshot, &history, 3);
+        snapshot.refresh(&history);
+        history.lock().unwrap().clear();
+        push(&history, 3, "d\n");
+        check(&snapshot, &history, 4);
+    }
+
+    #[test]
+    fn snapshot_with_compacted_runs() {
+        let history = Mutex::new(History::new(3, None, false, true));
+        let snapshot = Snapshot::default();
+        push(&history, 0, "a\n");
+        push(&history, 1, "b\n");
+        push(&history, 2, "b\n");
+        snapshot.refresh(&history);
+        // Compacted into the last entry of the snapshot
+        push(&history, 3, "b\n");
+        push(&history, 4, "b\n");
+        check(&snapshot, &history, 6);
+        assert_eq!(lines(&snapshot.copy_from(&history, 0))[1].1, 3);
+        push(&history, 5, "c\n");
+        push(&history, 6, "c\n");
+        push(&history, 7, "d\n");
+        check(&snapshot, &history, 9);
+    }
+
+    #[test]
+    fn copy_is_usable_without_history_lock() {
+        let history = Mutex::new(History::new(4, None, false, false));
+        let snapshot = Snapshot::default();
+        push(&history, 0, "a\n");
+        snapshot.refresh(&history);
+        push(&history, 1, "b\n");
+        let copy = snapshot.copy_from(&history, 0);
+        {
+            let _guard = history.try_lock().expect("history lock is still held");
+        }
+        push(&history, 2, "c\n");
+        history.lock().unwrap().clear();
+        assert_eq!(lines(&copy), [(0, 0, "a\n".into()), (1, 0, "b\n".into())]);
+    }
+}
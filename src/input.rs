@@ -0,0 +1,455 @@
+//! Alternative sources of input data instead of stdin
+
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::{
+        net::{UnixListener, UnixStream},
+        process::ExitStatusExt,
+    },
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use tokio_listener::ListenerAddress;
+
+use crate::{
+    json::Field,
+    log::{self, Level},
+};
+
+/// Delay between attempts to (re)connect to input socket
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// First delay before reconnecting with `--connect`, doubled after each failed attempt
+const CONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+
+/// Delay between attempts to open input file that does not exist yet
+const FILE_WAIT_DELAY: Duration = Duration::from_millis(100);
+
+/// Delay before restarting failed `--exec` child with `--exec-restart`
+const EXEC_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum SocketMode {
+    /// Connect to the address, reconnecting on failures
+    Connect,
+    /// Listen the address and accept one connection
+    Listen,
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn abstract_addr(name: &str) -> std::io::Result<std::os::unix::net::SocketAddr> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+    std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn abstract_addr(_name: &str) -> std::io::Result<std::os::unix::net::SocketAddr> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "abstract UNIX sockets are not supported on this platform",
+    ))
+}
+
+fn unsupported_address() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::Unsupported,
+        "only TCP and UNIX socket addresses are supported for input",
+    )
+}
+
+pub fn connect(addr: &ListenerAddress) -> std::io::Result<Stream> {
+    Ok(match addr {
+        ListenerAddress::Tcp(a) => Stream::Tcp(TcpStream::connect(a)?),
+        ListenerAddress::Path(p) => Stream::Unix(UnixStream::connect(p)?),
+        ListenerAddress::Abstract(a) => Stream::Unix(UnixStream::connect_addr(&abstract_addr(a)?)?),
+        _ => return Err(unsupported_address()),
+    })
+}
+
+fn bind(addr: &ListenerAddress) -> std::io::Result<Listener> {
+    Ok(match addr {
+        ListenerAddress::Tcp(a) => Listener::Tcp(TcpListener::bind(a)?),
+        ListenerAddress::Path(p) => Listener::Unix(UnixListener::bind(p)?),
+        ListenerAddress::Abstract(a) => {
+            Listener::Unix(UnixListener::bind_addr(&abstract_addr(a)?)?)
+        }
+        _ => return Err(unsupported_address()),
+    })
+}
+
+/// Accepts one connection on first read and reads from it till EOF
+struct AcceptOnce {
+    listener: Option<Listener>,
+    stream: Option<Stream>,
+}
+
+impl Read for AcceptOnce {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(ref mut s) = self.stream {
+            return s.read(buf);
+        }
+        let Some(listener) = self.listener.take() else {
+            return Ok(0);
+        };
+        let s = match listener {
+            Listener::Tcp(l) => Stream::Tcp(l.accept()?.0),
+            Listener::Unix(l) => Stream::Unix(l.accept()?.0),
+        };
+        self.stream.insert(s).read(buf)
+    }
+}
+
+/// Connects on first read and reconnects on failures
+struct ReconnectingSocket {
+    addr: ListenerAddress,
+    stream: Option<Stream>,
+    /// Delay before the next connection attempt
+    delay: Duration,
+    min_delay: Duration,
+    max_delay: Duration,
+    /// Whether to wait for `delay` before connecting
+    retry: bool,
+    /// `--connect`: reconnect on EOF from the peer instead of passing it through
+    reconnect_on_eof: bool,
+    /// Line separator for `DISCONNECTED` and `RECONNECTED` lines, if they are announced
+    announce: Option<u8>,
+    /// Whether the last byte returned was the separator
+    at_line_start: bool,
+    /// Whether the connection was lost, so that the next one is a reconnection
+    lost: bool,
+    /// Announcement line to return before reading further
+    pending: Vec<u8>,
+}
+
+impl ReconnectingSocket {
+    fn new(addr: ListenerAddress, min_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            addr,
+            stream: None,
+            delay: min_delay,
+            min_delay,
+            max_delay,
+            retry: false,
+            reconnect_on_eof: false,
+            announce: None,
+            at_line_start: true,
+            lost: false,
+            pending: Vec::new(),
+        }
+    }
+
+    fn push_announcement(&mut self, text: &[u8]) {
+        if let Some(sep) = self.announce {
+            if !self.at_line_start {
+                self.pending.push(sep);
+            }
+            self.pending.extend_from_slice(text);
+            self.pending.push(sep);
+        }
+    }
+
+    fn disconnected(&mut self) {
+        self.stream = None;
+        self.retry = true;
+        self.lost = true;
+        self.push_announcement(b"DISCONNECTED");
+    }
+}
+
+impl Read for ReconnectingSocket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if !self.pending.is_empty() {
+                let n = self.pending.len().min(buf.len());
+                buf[..n].copy_from_slice(&self.pending[..n]);
+                self.pending.drain(..n);
+                self.at_line_start = true;
+                return Ok(n);
+            }
+            let Some(ref mut stream) = self.stream else {
+                if self.retry {
+                    std::thread::sleep(self.delay);
+                    self.delay = (self.delay * 2).min(self.max_delay);
+                }
+                match connect(&self.addr) {
+                    Ok(s) => {
+                        self.stream = Some(s);
+                        self.retry = false;
+                        self.delay = self.min_delay;
+                        if self.lost {
+                            self.lost = false;
+                            log::event(
+                                Level::Info,
+                                "input_reconnected",
+                                &[],
+                                Some(format_args!("Reconnected to {}", self.addr)),
+                            );
+                            self.push_announcement(b"RECONNECTED");
+                        }
+                    }
+                    Err(e) => {
+                        log::event(
+                            Level::Warn,
+                            "input_connect_error",
+                            &[("error", Field::Str(&e.to_string()))],
+                            Some(format_args!("Connecting to {}: {e}", self.addr)),
+                        );
+                        self.retry = true;
+                    }
+                }
+                continue;
+            };
+            match stream.read(buf) {
+                Ok(0) if self.reconnect_on_eof && !buf.is_empty() => {
+                    log::event(
+                        Level::Warn,
+                        "input_disconnected",
+                        &[],
+                        Some(format_args!(
+                            "{} closed the connection. Reconnecting.",
+                            self.addr
+                        )),
+                    );
+                    self.disconnected();
+                }
+                Ok(n) => {
+                    if let (Some(sep), Some(&last)) = (self.announce, buf[..n].last()) {
+                        self.at_line_start = last == sep;
+                    }
+                    return Ok(n);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock) => {
+                    return Err(e)
+                }
+                Err(e) => {
+                    log::event(
+                        Level::Warn,
+                        "input_read_error",
+                        &[("error", Field::Str(&e.to_string()))],
+                        Some(format_args!(
+                            "Reading from {}: {e}. Reconnecting.",
+                            self.addr
+                        )),
+                    );
+                    self.disconnected();
+                }
+            }
+        }
+    }
+}
+
+/// Opens the file on first read, waiting for it to be created if needed
+struct OpenOnRead {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Read for OpenOnRead {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(ref mut f) = self.file {
+            return f.read(buf);
+        }
+        let mut warned = false;
+        let f = loop {
+            match File::open(&self.path) {
+                Ok(f) => break f,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    if !warned {
+                        log::event(
+                            Level::Info,
+                            "input_waiting",
+                            &[("path", Field::Str(&self.path.to_string_lossy()))],
+                            Some(format_args!(
+                                "Waiting for {} to appear",
+                                self.path.display()
+                            )),
+                        );
+                        warned = true;
+                    }
+                    std::thread::sleep(FILE_WAIT_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        self.file.insert(f).read(buf)
+    }
+}
+
+/// Output of `--exec` child process, restarted on failure if requested
+struct ChildOutput {
+    command: String,
+    merge_stderr: bool,
+    restart: bool,
+    child: Child,
+    output: Box<dyn Read + Send>,
+    /// Set when the child exits for the last time, to be announced with EOF
+    exit_code: Arc<OnceLock<u64>>,
+}
+
+/// Run the command with the shell, with stdout and possibly stderr going to the returned pipe
+fn spawn_child(
+    command: &str,
+    merge_stderr: bool,
+) -> std::io::Result<(Child, Box<dyn Read + Send>)> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command).stdin(Stdio::null());
+    if merge_stderr {
+        let (r, w) = std::io::pipe()?;
+        cmd.stdout(w.try_clone()?).stderr(w);
+        // Write ends of the pipe are closed with `cmd`, so that EOF is seen when the child exits
+        let child = cmd.spawn()?;
+        return Ok((child, Box::new(r)));
+    }
+    let mut child = cmd.stdout(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok((child, Box::new(stdout)))
+}
+
+impl ChildOutput {
+    /// Reap the child after EOF from it and report its exit code, like shell does for signals
+    fn wait(&mut self) -> std::io::Result<(u64, bool)> {
+        let status = self.child.wait()?;
+        let code = match (status.code(), status.signal()) {
+            (Some(c), _) => c as u64,
+            (None, Some(s)) => 128 + s as u64,
+            (None, None) => 0,
+        };
+        log::event(
+            if status.success() {
+                Level::Info
+            } else {
+                Level::Warn
+            },
+            "exec_exited",
+            &[("exit_code", Field::Num(code))],
+            Some(format_args!("{:?} exited: {status}", self.command)),
+        );
+        Ok((code, status.success()))
+    }
+}
+
+impl Read for ChildOutput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.output.read(buf) {
+                Ok(0) => (),
+                x => return x,
+            }
+            let (code, success) = self.wait()?;
+            if success || !self.restart {
+                let _ = self.exit_code.set(code);
+                return Ok(0);
+            }
+            std::thread::sleep(EXEC_RESTART_DELAY);
+            match spawn_child(&self.command, self.merge_stderr) {
+                Ok((child, output)) => {
+                    self.child = child;
+                    self.output = output;
+                }
+                Err(e) => {
+                    let _ = self.exit_code.set(code);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Start `--exec` child process and prepare reading its output.
+/// `exit_code` is set when the child exits and is not going to be restarted.
+pub fn open_exec(
+    command: String,
+    merge_stderr: bool,
+    restart: bool,
+    exit_code: Arc<OnceLock<u64>>,
+) -> std::io::Result<Box<dyn Read + Send>> {
+    let (child, output) = spawn_child(&command, merge_stderr)?;
+    Ok(Box::new(ChildOutput {
+        command,
+        merge_stderr,
+        restart,
+        child,
+        output,
+        exit_code,
+    }))
+}
+
+/// Prepare reading input from a file or FIFO. Opening is deferred until the first read,
+/// as opening a FIFO blocks until there is a writer.
+pub fn open_file(path: PathBuf) -> Box<dyn Read + Send> {
+    Box::new(OpenOnRead { path, file: None })
+}
+
+/// Prepare reading input from a socket. In listen mode the socket is bound immediately.
+/// `--connect`: read from the address, reconnecting with increasing delays whenever the connection
+/// ends. With `announce` separator, `DISCONNECTED` and `RECONNECTED` lines are inserted into input.
+pub fn open_connect(
+    addr: ListenerAddress,
+    max_delay: Duration,
+    announce: Option<u8>,
+) -> Box<dyn Read + Send> {
+    let mut s = ReconnectingSocket::new(addr, CONNECT_MIN_DELAY.min(max_delay), max_delay);
+    s.reconnect_on_eof = true;
+    s.announce = announce;
+    Box::new(s)
+}
+
+pub fn open_socket(
+    addr: ListenerAddress,
+    mode: SocketMode,
+) -> std::io::Result<Box<dyn Read + Send>> {
+    Ok(match mode {
+        SocketMode::Connect => Box::new(ReconnectingSocket::new(
+            addr,
+            RECONNECT_DELAY,
+            RECONNECT_DELAY,
+        )),
+        SocketMode::Listen => Box::new(AcceptOnce {
+            listener: Some(bind(&addr)?),
+            stream: None,
+        }),
+    })
+}
@@ -0,0 +1,160 @@
+//! `--allow` and `--deny`: filtering clients by remote IP address
+
+use std::{net::IpAddr, str::FromStr};
+
+/// IP network like `10.0.0.0/8` or `2001:db8::/32`. Plain address means a single-host network.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid IP address `{addr}`"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max_len)
+                .ok_or_else(|| format!("invalid prefix length `{p}`"))?,
+            None => max_len,
+        };
+        // Client addresses are canonicalized, so IPv4-mapped networks must be too
+        if let IpAddr::V6(v6) = addr {
+            if let (Some(v4), Some(len)) = (v6.to_ipv4_mapped(), prefix_len.checked_sub(96)) {
+                return Ok(Cidr {
+                    addr: v4.into(),
+                    prefix_len: len,
+                });
+            }
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        fn prefix_eq(a: u128, b: u128, bits: u8, width: u8) -> bool {
+            bits == 0 || (a ^ b) >> (width - bits) == 0
+        }
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                self.prefix_len,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(net.into(), ip.into(), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    /// Whether a client from `ip` may connect. Deny rules take precedence;
+    /// with no allow rules, all addresses not denied are allowed.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(cidr("10.0.0.0/8").prefix_len, 8);
+        assert_eq!(cidr("10.1.2.3").prefix_len, 32);
+        assert_eq!(cidr("2001:db8::/32").prefix_len, 32);
+        assert_eq!(cidr("::1").prefix_len, 128);
+        assert_eq!(cidr("0.0.0.0/0").prefix_len, 0);
+        assert_eq!(cidr("::ffff:10.0.0.0/104").addr, ip("10.0.0.0"));
+        assert_eq!(cidr("::ffff:10.0.0.0/104").prefix_len, 8);
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+        assert!("".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn matching() {
+        let net = cidr("10.0.0.0/8");
+        assert!(net.contains(ip("10.0.0.0")));
+        assert!(net.contains(ip("10.255.255.255")));
+        assert!(!net.contains(ip("11.0.0.0")));
+        assert!(!net.contains(ip("9.255.255.255")));
+        // IPv4-mapped client addresses match IPv4 networks
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("::a01:203")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+
+        let net = cidr("192.168.1.130/25");
+        assert!(net.contains(ip("192.168.1.128")));
+        assert!(!net.contains(ip("192.168.1.127")));
+
+        assert!(cidr("0.0.0.0/0").contains(ip("1.2.3.4")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("::1")));
+        assert!(cidr("1.2.3.4").contains(ip("1.2.3.4")));
+        assert!(!cidr("1.2.3.4").contains(ip("1.2.3.5")));
+
+        let net = cidr("2001:db8::/32");
+        assert!(net.contains(ip("2001:db8:ffff::1")));
+        assert!(!net.contains(ip("2001:db9::")));
+        assert!(!net.contains(ip("10.0.0.1")));
+        assert!(cidr("::1").contains(ip("::1")));
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let filter = |allow: &[&str], deny: &[&str]| IpFilter {
+            allow: allow.iter().map(|s| cidr(s)).collect(),
+            deny: deny.iter().map(|s| cidr(s)).collect(),
+        };
+        let f = filter(&[], &[]);
+        assert!(f.permits(ip("1.2.3.4")));
+
+        let f = filter(&[], &["10.0.0.0/8"]);
+        assert!(!f.permits(ip("10.0.0.1")));
+        assert!(f.permits(ip("11.0.0.1")));
+
+        let f = filter(&["10.0.0.0/8", "::1"], &["10.0.0.1"]);
+        assert!(f.permits(ip("10.0.0.2")));
+        assert!(f.permits(ip("::1")));
+        assert!(!f.permits(ip("10.0.0.1")));
+        assert!(!f.permits(ip("::ffff:10.0.0.1")));
+        assert!(!f.permits(ip("192.168.0.1")));
+    }
+}
@@ -0,0 +1,380 @@
+//! `--json` output mode: each message is sent to clients as a JSON object on its own line,
+//! or indented over several lines with `--output-pretty-json`
+
+use std::{
+    fmt::Write,
+    pin::Pin,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::encoding;
+
+/// Value of an additional field of a special message
+pub enum Field<'a> {
+    Num(u64),
+    Float(f64),
+    Str(&'a str),
+}
+
+pub struct JsonPrinter {
+    begin: Instant,
+    /// Wall clock time corresponding to `begin`, for `wall_ts` field
+    begin_wall: Option<SystemTime>,
+    buf: String,
+    /// `--prefix` and `--suffix`, sent as separate fields of content messages
+    prefix: Bytes,
+    suffix: Bytes,
+    /// `--seqn-modulus`
+    seqn_modulus: Option<u64>,
+    /// `--output-pretty-json`: terminator written after each indented object
+    pretty: Option<&'static str>,
+    pretty_buf: String,
+}
+
+pub fn escape_into(buf: &mut String, s: &str) {
+    buf.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+/// Re-indent compact JSON `s` with two spaces per level, one field or element per line
+pub fn pretty_into(buf: &mut String, s: &str) {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        buf.push(c);
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+        } else {
+            match c {
+                '"' => in_string = true,
+                ':' => buf.push(' '),
+                // Empty object or array stays on one line
+                '{' | '[' if matches!(chars.peek(), Some('}' | ']')) => buf.extend(chars.next()),
+                '{' | '[' => {
+                    depth += 1;
+                    newline_into(buf, depth);
+                }
+                ',' => newline_into(buf, depth),
+                _ => (),
+            }
+        }
+        if !in_string && matches!(chars.peek(), Some('}' | ']')) {
+            depth -= 1;
+            newline_into(buf, depth);
+        }
+    }
+}
+
+fn newline_into(buf: &mut String, depth: usize) {
+    buf.push('\n');
+    for _ in 0..depth {
+        buf.push_str("  ");
+    }
+}
+
+/// Write `fields` as `,"name":value` pairs
+pub fn fields_into(buf: &mut String, fields: &[(&str, Field<'_>)]) {
+    for (name, value) in fields {
+        let _ = write!(buf, ",\"{name}\":");
+        match value {
+            Field::Num(x) => {
+                let _ = write!(buf, "{x}");
+            }
+            // JSON has no NaN or infinity
+            Field::Float(x) if !x.is_finite() => buf.push_str("null"),
+            Field::Float(x) => {
+                let _ = write!(buf, "{x}");
+            }
+            Field::Str(x) => escape_into(buf, x),
+        }
+    }
+}
+
+impl JsonPrinter {
+    pub fn new(
+        begin: Instant,
+        begin_wall: Option<SystemTime>,
+        prefix: Bytes,
+        suffix: Bytes,
+        seqn_modulus: Option<u64>,
+        pretty: Option<&'static str>,
+    ) -> Self {
+        Self {
+            begin,
+            begin_wall,
+            prefix,
+            suffix,
+            seqn_modulus,
+            pretty,
+            buf: String::with_capacity(256),
+            pretty_buf: String::new(),
+        }
+    }
+
+    fn start(&mut self, kind: &str, ts: Instant) {
+        let x = ts - self.begin;
+        self.buf.clear();
+        let _ = write!(
+            self.buf,
+            "{{\"kind\":\"{kind}\",\"ts\":{}.{:06}",
+            x.as_secs(),
+            x.subsec_micros()
+        );
+        if let Some(begin_wall) = self.begin_wall {
+            let t = (begin_wall + x)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            self.buf.push_str(",\"wall_ts\":\"");
+            crate::write_rfc3339(&mut self.buf, t);
+            self.buf.push('"');
+        }
+    }
+
+    async fn finish(&mut self, mut conn: Pin<&mut impl AsyncWrite>) -> std::io::Result<()> {
+        self.buf.push('}');
+        let Some(terminator) = self.pretty else {
+            self.buf.push('\n');
+            return conn.write_all(self.buf.as_bytes()).await;
+        };
+        self.pretty_buf.clear();
+        pretty_into(&mut self.pretty_buf, &self.buf);
+        self.pretty_buf.push_str(terminator);
+        conn.write_all(self.pretty_buf.as_bytes()).await
+    }
+
+    /// Write a content line. `data` should not include the line separator.
+    /// Lines re-sent on `REPLAY` request get `"replay":true` field.
+    pub async fn content(
+        &mut self,
+        conn: Pin<&mut impl AsyncWrite>,
+        ts: Instant,
+        seqn: u64,
+        data: &[u8],
+        replay: bool,
+    ) -> std::io::Result<()> {
+        self.start("content", ts);
+        let seqn = crate::wrap_seqn(seqn, self.seqn_modulus);
+        let _ = write!(self.buf, ",\"seqn\":{seqn}");
+        if replay {
+            self.buf.push_str(",\"replay\":true");
+        }
+        if !self.prefix.is_empty() {
+            self.buf.push_str(",\"prefix\":");
+            escape_into(&mut self.buf, &String::from_utf8_lossy(&self.prefix));
+        }
+        if !self.suffix.is_empty() {
+            self.buf.push_str(",\"suffix\":");
+            escape_into(&mut self.buf, &String::from_utf8_lossy(&self.suffix));
+        }
+        match std::str::from_utf8(data) {
+            Ok(s) => {
+                self.buf.push_str(",\"encoding\":\"utf8\",\"data\":");
+                escape_into(&mut self.buf, s);
+            }
+            Err(_) => {
+                self.buf.push_str(",\"encoding\":\"base64\",\"data\":");
+                self.buf.push('"');
+                encoding::base64_into(data, |c| self.buf.push(char::from(c)));
+                self.buf.push('"');
+            }
+        }
+        self.finish(conn).await
+    }
+
+    /// Write a special message like `overrun` or `eof` with additional fields
+    pub async fn special(
+        &mut self,
+        conn: Pin<&mut impl AsyncWrite>,
+        ts: Instant,
+        kind: &str,
+        fields: &[(&str, Field<'_>)],
+    ) -> std::io::Result<()> {
+        self.start(kind, ts);
+        fields_into(&mut self.buf, fields);
+        self.finish(conn).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn escaped(s: &str) -> String {
+        let mut buf = String::new();
+        escape_into(&mut buf, s);
+        buf
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escaped(""), r#""""#);
+        assert_eq!(escaped("plain text"), r#""plain text""#);
+        assert_eq!(escaped(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(escaped("\n\r\t"), r#""\n\r\t""#);
+        assert_eq!(escaped("\0\x1b\x1f "), r#""\u0000\u001b\u001f ""#);
+        // Non-ASCII and DEL are valid inside JSON strings
+        assert_eq!(escaped("ü€\x7f"), "\"ü€\x7f\"");
+    }
+
+    fn printer(prefix: &'static [u8], wall: bool) -> (JsonPrinter, Instant) {
+        let begin = Instant::now();
+        let begin_wall = wall.then(|| UNIX_EPOCH + Duration::from_secs(951782400));
+        let p = JsonPrinter::new(
+            begin,
+            begin_wall,
+            Bytes::from_static(prefix),
+            Bytes::new(),
+            Some(5),
+            None,
+        );
+        (p, begin)
+    }
+
+    #[tokio::test]
+    async fn content_messages() {
+        let (mut p, begin) = printer(b"", false);
+        let mut out = Vec::new();
+        let ts = begin + Duration::from_micros(1_500_042);
+        p.content(Pin::new(&mut out), ts, 7, b"say \"hi\"", false)
+            .await
+            .unwrap();
+        p.content(Pin::new(&mut out), ts, 8, b"\xff\x00a", true)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"kind":"content","ts":1.500042,"seqn":2,"encoding":"utf8","data":"say \"hi\""}"#,
+                "\n",
+                r#"{"kind":"content","ts":1.500042,"seqn":3,"replay":true,"encoding":"base64","data":"/wBh"}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn prefix_and_wall_clock() {
+        let (mut p, begin) = printer(b"[a]", true);
+        let mut out = Vec::new();
+        p.content(
+            Pin::new(&mut out),
+            begin + Duration::from_secs(61),
+            0,
+            b"x",
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"kind":"content","ts":61.000000,"wall_ts":"2000-02-29T00:01:01.000000Z","#,
+                r#""seqn":0,"prefix":"[a]","encoding":"utf8","data":"x"}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn special_messages() {
+        let (mut p, begin) = printer(b"", false);
+        let mut out = Vec::new();
+        let fields = [
+            ("count", Field::Num(3)),
+            ("rate", Field::Float(0.25)),
+            ("avg", Field::Float(f64::NAN)),
+            ("peer", Field::Str("a\"b")),
+        ];
+        p.special(Pin::new(&mut out), begin, "overrun", &fields)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"kind":"overrun","ts":0.000000,"count":3,"rate":0.25,"avg":null,"peer":"a\"b"}"#,
+                "\n",
+            )
+        );
+    }
+
+    fn pretty(s: &str) -> String {
+        let mut buf = String::new();
+        pretty_into(&mut buf, s);
+        buf
+    }
+
+    #[test]
+    fn pretty_printing() {
+        assert_eq!(pretty("{}"), "{}");
+        assert_eq!(
+            pretty(r#"{"a":1,"b":"x,y:{z}\"","c":[],"d":{"e":[1,2]}}"#),
+            concat!(
+                "{\n",
+                "  \"a\": 1,\n",
+                "  \"b\": \"x,y:{z}\\\"\",\n",
+                "  \"c\": [],\n",
+                "  \"d\": {\n",
+                "    \"e\": [\n",
+                "      1,\n",
+                "      2\n",
+                "    ]\n",
+                "  }\n",
+                "}",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn pretty_messages() {
+        let begin = Instant::now();
+        let mut p = JsonPrinter::new(begin, None, Bytes::new(), Bytes::new(), None, Some("\0"));
+        let mut out = Vec::new();
+        p.content(Pin::new(&mut out), begin, 1, b"a", false)
+            .await
+            .unwrap();
+        p.special(Pin::new(&mut out), begin, "eof", &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "{\n",
+                "  \"kind\": \"content\",\n",
+                "  \"ts\": 0.000000,\n",
+                "  \"seqn\": 1,\n",
+                "  \"encoding\": \"utf8\",\n",
+                "  \"data\": \"a\"\n",
+                "}\0",
+                "{\n",
+                "  \"kind\": \"eof\",\n",
+                "  \"ts\": 0.000000\n",
+                "}\0",
+            )
+        );
+    }
+}
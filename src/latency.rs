@@ -0,0 +1,77 @@
+//! `--latency-tracking`: time from reading a line to writing it to a client
+
+use std::time::Duration;
+
+/// Number of samples kept for each client
+pub const CLIENT_SAMPLES: usize = 1024;
+/// Number of samples kept across all clients
+pub const TOTAL_SAMPLES: usize = 8192;
+
+/// Ring buffer of recent latency samples
+pub struct Samples {
+    buf: Vec<Duration>,
+    capacity: usize,
+    /// Index of the oldest sample once the buffer is full
+    pos: usize,
+}
+
+impl Samples {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            pos: 0,
+        }
+    }
+
+    pub fn record(&mut self, d: Duration) {
+        if self.buf.len() < self.capacity {
+            self.buf.push(d);
+        } else {
+            self.buf[self.pos] = d;
+            self.pos = (self.pos + 1) % self.capacity;
+        }
+    }
+
+    /// 50th, 95th and 99th percentiles in milliseconds, `None` if there are no samples
+    pub fn percentiles(&self) -> Option<[f64; 3]> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let mut sorted = self.buf.clone();
+        sorted.sort_unstable();
+        let at = |p: usize| {
+            let i = (sorted.len() * p / 100).min(sorted.len() - 1);
+            sorted[i].as_secs_f64() * 1000.0
+        };
+        Some([at(50), at(95), at(99)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut s = Samples::new(100);
+        assert_eq!(s.percentiles(), None);
+        s.record(Duration::from_millis(7));
+        assert_eq!(s.percentiles(), Some([7.0, 7.0, 7.0]));
+        for ms in (1..=100).rev() {
+            s.record(Duration::from_millis(ms));
+        }
+        assert_eq!(s.percentiles(), Some([51.0, 96.0, 100.0]));
+    }
+
+    #[test]
+    fn oldest_samples_are_replaced() {
+        let mut s = Samples::new(4);
+        for ms in [100, 100, 100, 100, 1, 2, 3] {
+            s.record(Duration::from_millis(ms));
+        }
+        assert_eq!(s.percentiles(), Some([3.0, 100.0, 100.0]));
+        s.record(Duration::from_millis(4));
+        assert_eq!(s.percentiles(), Some([3.0, 4.0, 4.0]));
+    }
+}
@@ -0,0 +1,39 @@
+//! `--loadbalance`: delivering each line to one client in turn instead of all of them
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering::Relaxed},
+    Arc, Mutex,
+};
+
+/// Registry of clients ready to receive lines
+#[derive(Default)]
+pub struct LoadBalancer {
+    clients: Mutex<Vec<u64>>,
+    next: AtomicUsize,
+}
+
+impl LoadBalancer {
+    /// Choose the client to receive the next line
+    pub fn pick(&self) -> Option<u64> {
+        let clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return None;
+        }
+        let i = self.next.fetch_add(1, Relaxed) % clients.len();
+        Some(clients[i])
+    }
+
+    /// Include the client in rotation until the returned guard is dropped
+    pub fn register(self: &Arc<Self>, client_id: u64) -> Registration {
+        self.clients.lock().unwrap().push(client_id);
+        Registration(self.clone(), client_id)
+    }
+}
+
+pub struct Registration(Arc<LoadBalancer>, u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.0.clients.lock().unwrap().retain(|&x| x != self.1);
+    }
+}
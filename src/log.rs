@@ -0,0 +1,93 @@
+//! Server event messages on stderr: plain text, or JSON lines with `--log-json`
+
+use std::{
+    fmt::{Arguments, Write},
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::json::{escape_into, fields_into, Field};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn enable_json() {
+    JSON.store(true, Relaxed);
+}
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+/// Log an event. `text` is the plain-text message; events without one are only logged in JSON mode.
+pub fn event(level: Level, event: &str, fields: &[(&str, Field)], text: Option<Arguments>) {
+    if !JSON.load(Relaxed) {
+        if let Some(text) = text {
+            eprintln!("{text}");
+        }
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    eprintln!("{}", json_line(level, event, fields, text, now));
+}
+
+/// `--log-json` line for an event at `now` since UNIX epoch
+fn json_line(
+    level: Level,
+    event: &str,
+    fields: &[(&str, Field)],
+    text: Option<Arguments>,
+    now: Duration,
+) -> String {
+    let level = match level {
+        Level::Info => "info",
+        Level::Warn => "warn",
+        Level::Error => "error",
+    };
+    let mut buf = String::with_capacity(128);
+    let _ = write!(
+        buf,
+        "{{\"level\":\"{level}\",\"event\":\"{event}\",\"ts\":\""
+    );
+    crate::write_rfc3339(&mut buf, now);
+    buf.push('"');
+    fields_into(&mut buf, fields);
+    if let Some(text) = text {
+        buf.push_str(",\"message\":");
+        escape_into(&mut buf, &text.to_string());
+    }
+    buf.push('}');
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lines() {
+        let now = Duration::from_secs(951782400) + Duration::from_micros(5);
+        assert_eq!(
+            json_line(Level::Info, "start", &[], None, now),
+            r#"{"level":"info","event":"start","ts":"2000-02-29T00:00:00.000005Z"}"#
+        );
+        let peer = "[::1]:80";
+        assert_eq!(
+            json_line(
+                Level::Warn,
+                "client_overrun",
+                &[("client", Field::Num(3)), ("peer", Field::Str(peer))],
+                Some(format_args!("Client {} \"{peer}\" fell behind", 3)),
+                now,
+            ),
+            concat!(
+                r#"{"level":"warn","event":"client_overrun","ts":"2000-02-29T00:00:00.000005Z","#,
+                r#""client":3,"peer":"[::1]:80","message":"Client 3 \"[::1]:80\" fell behind"}"#
+            )
+        );
+    }
+}
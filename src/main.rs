@@ -1,34 +1,139 @@
 use std::{
     collections::VecDeque,
+    future::Future,
     io::{ErrorKind, Read},
+    path::PathBuf,
     pin::Pin,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    task::Poll,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+mod accesslog;
+mod admin;
+mod announce;
+mod broadcaster;
+mod config;
+mod diagnose;
+mod dryrun;
+mod encoding;
+mod framing;
+mod history;
+mod input;
+mod ipfilter;
+mod json;
+mod latency;
+mod loadbalance;
+mod log;
+mod metrics;
+mod multiline;
+mod outputfile;
+mod pidfile;
+mod regex;
+mod seqnfile;
+mod session;
+mod signals;
+mod spill;
+mod stats;
+mod systemd;
+mod teesocket;
+mod topic;
+
 use bytes::{Bytes, BytesMut};
-use clap::Parser;
+use clap::{builder::BoolishValueParser, CommandFactory, FromArgMatches, Parser};
 use std::fmt::Write;
 use tokio::{
-    io::{AsyncWrite, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::broadcast::error::RecvError,
 };
 
+/// How long to wait for next handshake line from a client before starting streaming
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Reply `ERROR <code>` to a malformed handshake line and log it
+async fn handshake_error(
+    mut conn: Pin<&mut impl AsyncWrite>,
+    client_id: u64,
+    line: &str,
+    code: &str,
+    line_terminator: &str,
+) -> std::io::Result<()> {
+    log::event(
+        log::Level::Warn,
+        "handshake_error",
+        &[
+            ("client_id", json::Field::Num(client_id)),
+            ("line", json::Field::Str(line)),
+            ("error", json::Field::Str(code)),
+        ],
+        Some(format_args!(
+            "client_id={client_id}: {code} in handshake line {line:?}"
+        )),
+    );
+    let reply = format!("ERROR {code}{line_terminator}");
+    conn.write_all(reply.as_bytes()).await?;
+    conn.flush().await
+}
+
 /// Accept lines from stdin and allow socket clients to tap into them
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Use `stdintap diagnose --help` for the health check client."
+)]
 struct Args {
+    /// Socket address to listen for incoming connections: TCP address like `127.0.0.1:8080`,
+    /// UNIX socket path, Linux abstract address like `@abstract`, `inetd` for serving one connection
+    /// from stdin/stdout or `sd-listen` for systemd socket activation.
+    ///
+    /// May be omitted with `--systemd` or when started by systemd socket activation with one socket.
+    listen_address: Option<tokio_listener::ListenerAddress>,
+
     #[clap(flatten)]
-    listener: tokio_listener::ListenerAddressPositional,
+    listener_options: tokio_listener::UserOptions,
+
+    /// Accept connections on the socket passed by systemd socket activation (`sd-listen` address).
+    ///
+    /// This is also the default when no address is given and `LISTEN_FDS=1` is set for this process.
+    /// Readiness is reported to systemd whenever `NOTIFY_SOCKET` is set.
+    #[clap(long, env = "STDINTAP_SYSTEMD", value_parser = BoolishValueParser::new())]
+    systemd: bool,
+
+    /// Write process ID to this file after starting to listen, and remove it on exit.
+    /// Refuse to start if the file names another running process.
+    #[clap(long, env = "STDINTAP_PID_FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// Don't listen for clients. Print `[seqn=N ts=X.XXXXXX bytes=N]` to stderr for each line
+    /// that would be broadcast, after filtering and other transformations, and exit on end of input.
+    /// Useful to check options before deploying. Listen address may be omitted.
+    #[clap(long, env = "STDINTAP_DRY_RUN", value_parser = BoolishValueParser::new())]
+    dry_run: bool,
 
     /// Size of broadcast channel for serving the lines
-    #[clap(long, short = 'q', default_value = "16")]
+    #[clap(long, short = 'q', default_value = "16", env = "STDINTAP_QLEN")]
     qlen: usize,
 
     /// Slow down reading from stdin if connected clients are slow in reading output
-    #[clap(long)]
+    #[clap(long, env = "STDINTAP_BACKPRESSURE", value_parser = BoolishValueParser::new())]
     backpressure: bool,
 
+    /// Fraction of `--qlen` filled with unread lines at which `--backpressure` activates, from 0 to 1.
+    /// The default 1 means almost full queue.
+    #[clap(long, default_value = "1.0", env = "STDINTAP_BACKPRESSURE_THRESHOLD")]
+    backpressure_threshold: f64,
+
+    /// How `--backpressure` sleeps while waiting for clients to catch up: `exponential` (from 1 µs,
+    /// doubling up to 65536 µs), `linear:<START_US>:<STEP_US>` (growing up to the same limit)
+    /// or `constant:<US>`. Parameters may also be separated by spaces.
+    #[clap(long, default_value = "exponential", value_parser = parse_sleep_strategy, env = "STDINTAP_BACKPRESSURE_SLEEP_STRATEGY")]
+    backpressure_sleep_strategy: broadcaster::SleepStrategy,
+
     /// Inject special lines that denote missed content due to slow reading
     /// In `--backpressure` mode, it will insert announcements that backpressure is applied
     /// Additionally, stdin EOFs will also be announced.
@@ -37,55 +142,857 @@ struct Args {
     /// spaces instead of tabs.
     ///
     /// Note that overrun announcements may exacerbate overruns.
-    #[clap(long, short = 'x')]
+    #[clap(long, short = 'x', env = "STDINTAP_ANNOUNCE_OVERRUNS", value_parser = BoolishValueParser::new())]
     announce_overruns: bool,
 
     /// Disconnect clients when they are too slow to read lines
-    #[clap(long)]
+    #[clap(long, env = "STDINTAP_DISCONNECT_ON_OVERRUNS", value_parser = BoolishValueParser::new())]
     disconnect_on_overruns: bool,
 
     /// Prefix messages with a monotone timestamps
-    #[clap(long, short = 't')]
+    #[clap(long, short = 't', env = "STDINTAP_TIMESTAMPS", value_parser = BoolishValueParser::new())]
     timestamps: bool,
 
+    /// Prefix messages with wall-clock UTC timestamps like `2024-05-10T14:32:01.123456Z`.
+    ///
+    /// Can be combined with `--timestamps`, in which case the monotone timestamp goes first.
+    #[clap(long, env = "STDINTAP_WALL_TIMESTAMPS", value_parser = BoolishValueParser::new())]
+    wall_timestamps: bool,
+
+    /// Prefix messages with UNIX epoch timestamps with microseconds, like `1715348521.123456`,
+    /// instead of monotone `--timestamps`
+    #[clap(long, env = "STDINTAP_UNIX_TIMESTAMPS", value_parser = BoolishValueParser::new())]
+    unix_timestamps: bool,
+
+    /// Prefix messages with time elapsed since the previous message sent to the client, like
+    /// `+0.001234`, instead of monotone `--timestamps`. The first one is relative to startup.
+    #[clap(long, env = "STDINTAP_DELTA_TIMESTAMPS", value_parser = BoolishValueParser::new())]
+    delta_timestamps: bool,
+
     /// Inject initial message at the beginning of each client connection
     ///
     /// With --history option, the hello message appears after the history, before the "online" content.
-    #[clap(long, short = 'H')]
+    #[clap(long, short = 'H', env = "STDINTAP_HELLO_MESSAGE", value_parser = BoolishValueParser::new())]
     hello_message: bool,
 
-    /// Automatically split lines longer than this
-    #[clap(long, default_value = "65536")]
-    max_line_size: usize,
+    /// Echo the client's address back in the hello message, which becomes
+    /// `HELLO from=<peer address> id=<client number> server=<hostname>`,
+    /// with `local` as peer address for UNIX socket clients.
+    #[clap(long, env = "STDINTAP_CLIENT_GREETING_ECHO", value_parser = BoolishValueParser::new())]
+    client_greeting_echo: bool,
+
+    /// Custom text of the hello message. Implies `--hello-message`.
+    ///
+    /// Supports `\n`, `\t`, `\0` and `\\` escapes and placeholders `{seqn}` (sequence number of the next line
+    /// the client will receive), `{from}`, `{id}` and `{server}`.
+    #[clap(long, value_parser = parse_escapes, env = "STDINTAP_HELLO_TEXT")]
+    hello_text: Option<String>,
+
+    /// Automatically split lines longer than this. 65536 by default.
+    #[clap(long, env = "STDINTAP_MAX_LINE_SIZE")]
+    max_line_size: Option<usize>,
+
+    /// What to do with lines longer than `--max-line-size`. The cut point is moved back
+    /// so that it does not fall inside a UTF-8 sequence.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "split",
+        env = "STDINTAP_MAX_LINE_ACTION"
+    )]
+    max_line_action: MaxLineAction,
 
     /// Separata lines by zero byte instead of \n
-    #[clap(long, short='0')]
+    #[clap(long, short = '0', env = "STDINTAP_ZERO_SEPARATED", value_parser = BoolishValueParser::new())]
     zero_separated: bool,
 
+    /// Treat `\r\n` as line separator in input and end all lines sent to clients with `\r\n`.
+    ///
+    /// `\r` is stripped from stored lines, so `--history` and `--history-file` contain bare `\n`.
+    #[clap(long, env = "STDINTAP_CRLF", value_parser = BoolishValueParser::new())]
+    crlf: bool,
+
+    /// Line separator as hex bytes, e.g. `1e` or `1c1d`, instead of `\n`.
+    ///
+    /// All bytes of a multi-byte separator must be present to end a line. Lines are stored
+    /// with only the last byte of the separator and sent to clients with the full separator.
+    #[clap(long, value_parser = parse_hex, env = "STDINTAP_SEPARATOR")]
+    separator: Option<HexBytes>,
+
+    /// End lines sent to clients, including special messages, with these hex bytes (e.g. `0d0a`)
+    /// instead of the input separator. Must be valid UTF-8.
+    #[clap(long, value_parser = parse_hex, env = "STDINTAP_OUTPUT_SEPARATOR")]
+    output_separator: Option<HexBytes>,
+
     /// Also copy stdin to stdout
-    #[clap(long, short = 'T')]
+    #[clap(long, short = 'T', env = "STDINTAP_TEE", value_parser = BoolishValueParser::new())]
     tee: bool,
 
     /// Print sequence numbers of lines
-    #[clap(long)]
+    #[clap(long, env = "STDINTAP_SEQN", value_parser = BoolishValueParser::new())]
     seqn: bool,
 
     /// Remember and this number of lines and replay them to each connecting client
-    #[clap(long)]
+    ///
+    /// Connected clients can also send `REPLAY <seqn>` line to get lines from history again,
+    /// starting from the given sequence number. The lines are preceded by `REPLAY <count>` line
+    /// and always have sequence numbers.
+    #[clap(long, env = "STDINTAP_HISTORY")]
     history: Option<usize>,
 
+    /// Limit `--history` buffer by total size of lines in bytes. Can be used instead of `--history`
+    /// or together with it, in which case both limits apply.
+    #[clap(long, env = "STDINTAP_HISTORY_BYTES")]
+    history_bytes: Option<usize>,
+
+    /// Keep separate history buffers of these sizes for three client tiers, e.g. `100:1000:10000`,
+    /// instead of `--history`. Each client replays the buffer of its tier. The largest buffer is
+    /// the one used by `--history-file`, admin and metrics reports.
+    ///
+    /// Clients are in the largest tier unless they send `TIER <0|1|2>` line or `--tier-threshold` applies.
+    #[clap(long, value_parser = parse_tiers, env = "STDINTAP_HISTORY_TIERS")]
+    history_tiers: Option<[usize; 3]>,
+
+    /// Put clients connecting after the first N into the smallest of `--history-tiers`,
+    /// unless they request a tier with `TIER <0|1|2>` line
+    #[clap(long, env = "STDINTAP_TIER_THRESHOLD")]
+    tier_threshold: Option<u64>,
+
     /// Don't read from stdin unless at least one client is connected.
-    /// 
+    /// Reading pauses each time the last client disconnects and resumes as soon as a client connects.
+    ///
     /// Does not gurantee lack of dropped lines on disconnections.
-    #[clap(long)]
-    require_observer: bool,
+    #[clap(long, visible_alias = "require-observer", env = "STDINTAP_PAUSE_STDIN_ON_NO_CLIENTS", value_parser = BoolishValueParser::new())]
+    pause_stdin_on_no_clients: bool,
+
+    /// How often paused `--pause-stdin-on-no-clients` reader checks for shutdown, in milliseconds
+    #[clap(long, default_value = "200", env = "STDINTAP_OBSERVER_POLL_MS")]
+    observer_poll_ms: u64,
+
+    /// Keep reading stdin for this number of milliseconds after the last client disconnects
+    /// before `--pause-stdin-on-no-clients` pauses, so that brief reconnections don't interrupt input
+    #[clap(long, default_value = "0", env = "STDINTAP_OBSERVER_HYSTERESIS_MS")]
+    observer_hysteresis_ms: u64,
+
+    /// Allow clients to request shorter lines by sending `MAX_LINE <N>` line in the beginning of connection.
+    ///
+    /// Lines longer than that are truncated (not split) for that client. Values above `--max-line-size` are capped.
+    /// Malformed values are answered with `ERROR BAD_MAX_LINE` line and ignored.
+    /// Handshake lines are accepted until an empty line, unrecognized line or a short pause.
+    #[clap(long, env = "STDINTAP_LINE_LENGTH_LIMIT_PER_CLIENT", value_parser = BoolishValueParser::new())]
+    line_length_limit_per_client: bool,
+
+    /// Close the broadcast channel as soon as stdin EOF is received, so that all connected clients
+    /// finish their streams instead of waiting for content that would never come.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set, env = "STDINTAP_FORWARD_STDIN_EOF_AS_DISCONNECT", value_parser = BoolishValueParser::new())]
+    forward_stdin_eof_as_disconnect: bool,
+
+    /// Measure stdin input rate (bytes and lines per second) over a rolling window of this duration, e.g. `1s` or `500ms`
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_INPUT_RATE_MEASURE_WINDOW")]
+    input_rate_measure_window: Option<Duration>,
+
+    /// Print measured input rate to stderr with this interval
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_RATE_LOG_INTERVAL")]
+    rate_log_interval: Option<Duration>,
+
+    /// Read input lines from this socket address instead of stdin
+    #[clap(long, env = "STDINTAP_STDIN_FROM_SOCKET")]
+    stdin_from_socket: Option<tokio_listener::ListenerAddress>,
+
+    /// Read input lines from TCP connection to this address instead of stdin, turning stdintap into
+    /// a TCP-to-broadcast proxy. Reconnect whenever the connection ends or fails, with delays doubling
+    /// from 100 ms up to `--reconnect-max-delay`. With `--announce-overruns`, `DISCONNECTED` and
+    /// `RECONNECTED` lines are inserted into the stream.
+    #[clap(long, env = "STDINTAP_CONNECT")]
+    connect: Option<tokio_listener::ListenerAddress>,
+
+    /// Maximum delay between `--connect` attempts, in milliseconds
+    #[clap(long, default_value = "30000", env = "STDINTAP_RECONNECT_MAX_DELAY")]
+    reconnect_max_delay: u64,
+
+    /// Read input lines from this file or FIFO instead of stdin.
+    /// If the path does not exist yet, wait for it to appear.
+    #[clap(long, env = "STDINTAP_INPUT")]
+    input: Option<PathBuf>,
+
+    /// Run this shell command and read its stdout instead of stdin. The command's stdin is empty.
+    ///
+    /// Its exit code is included in `--announce-overruns` EOF announcement.
+    #[clap(long, value_name = "CMD", env = "STDINTAP_EXEC")]
+    exec: Option<String>,
+
+    /// Also read stderr of `--exec` command, interleaved with its stdout
+    #[clap(long, env = "STDINTAP_EXEC_MERGE_STDERR", value_parser = BoolishValueParser::new())]
+    exec_merge_stderr: bool,
+
+    /// Restart `--exec` command after a second if it exits unsuccessfully
+    #[clap(long, env = "STDINTAP_EXEC_RESTART", value_parser = BoolishValueParser::new())]
+    exec_restart: bool,
+
+    /// Whether `--stdin-from-socket` should connect to the address or listen on it
+    #[clap(
+        long,
+        value_enum,
+        default_value = "connect",
+        env = "STDINTAP_STDIN_SOCKET_MODE"
+    )]
+    stdin_socket_mode: input::SocketMode,
+
+    /// Remove leading ASCII whitespace from each line before broadcasting it
+    #[clap(long, env = "STDINTAP_STRIP_LEADING_WHITESPACE", value_parser = BoolishValueParser::new())]
+    strip_leading_whitespace: bool,
+
+    /// Apply regex substitution only to N-th (1-based) field of each line.
+    ///
+    /// Replacement can refer to capture groups as `$1` or `${1}`.
+    /// Lines with fewer than N fields are passed through unchanged.
+    #[clap(
+        long,
+        visible_alias = "line-regex-replace-nth",
+        num_args = 3,
+        value_names = ["N", "REGEX", "REPLACEMENT"]
+    )]
+    replace_field: Option<Vec<String>>,
+
+    /// Field separator for `--replace-field`. Tab by default.
+    #[clap(long, env = "STDINTAP_FIELD_SEPARATOR")]
+    field_separator: Option<String>,
+
+    /// Print number of lines and bytes in history buffer to stderr on SIGUSR1
+    #[clap(long, env = "STDINTAP_HISTORY_SIZE_REPORT", value_parser = BoolishValueParser::new())]
+    history_size_report: bool,
+
+    /// In `--backpressure` mode, announce backpressure after it ends, including its duration:
+    /// `BACKPRESSURE duration_ms=<N>`
+    #[clap(long, env = "STDINTAP_ANNOUNCE_BACKPRESSURE_DURATION", value_parser = BoolishValueParser::new())]
+    announce_backpressure_duration: bool,
+
+    /// Flush buffered client output at least this often, even if lines keep coming without pauses
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_CLIENT_WRITE_BATCH_TIMEOUT")]
+    client_write_batch_timeout: Option<Duration>,
+
+    /// When to flush buffered client output: `idle` (when no more lines are pending, default),
+    /// `always` (after every message), `bytes:<N>` (when at least N bytes are buffered, or when
+    /// `--write-buffer` is full) or `timer:<MS>` (only periodically, like `--client-write-batch-timeout`).
+    /// `bytes` mode delays the last lines until more come, unless combined with `--client-write-batch-timeout`.
+    #[clap(long, default_value = "idle", value_parser = parse_flush_mode, env = "STDINTAP_FLUSH_MODE")]
+    flush_mode: FlushMode,
+
+    /// Maintain an index of sequence numbers in `--history` buffer for fast lookups of replay starting points
+    #[clap(long, env = "STDINTAP_HISTORY_SEQN_INDEX", value_parser = BoolishValueParser::new())]
+    history_seqn_index: bool,
+
+    /// Respond to `DIAG` handshake line from clients with a JSON health report (see `stdintap diagnose`)
+    #[clap(long, env = "STDINTAP_DIAG", value_parser = BoolishValueParser::new())]
+    diag: bool,
+
+    /// Limit reading rate to this number of lines per second in the long term (token bucket)
+    #[clap(long, env = "STDINTAP_LINE_THROTTLE_RATE")]
+    line_throttle_rate: Option<f64>,
+
+    /// Number of lines that can be read at full speed above `--line-throttle-rate` limit
+    #[clap(long, default_value = "1", env = "STDINTAP_LINE_THROTTLE_BURST")]
+    line_throttle_burst: f64,
+
+    /// Store consecutive identical lines in `--history` as one entry with a repeat counter.
+    ///
+    /// Replayed as the line followed by `REPEATED <count> times` announcement.
+    #[clap(long, env = "STDINTAP_HISTORY_COMPACT_IDENTICAL_RUNS", value_parser = BoolishValueParser::new())]
+    history_compact_identical_runs: bool,
+
+    /// Treat continuation lines (e.g. of stack traces) specially: join them into preceding record or tag them
+    #[clap(long, value_enum, env = "STDINTAP_INPUT_MULTILINE_MODE")]
+    input_multiline_mode: Option<multiline::MultilineMode>,
+
+    /// Regex matching continuation lines for `--input-multiline-mode`.
+    /// By default, lines starting with a space or a tab are continuations.
+    #[clap(long, env = "STDINTAP_MULTILINE_CONTINUATION_PATTERN")]
+    multiline_continuation_pattern: Option<String>,
+
+    /// With `--seqn`, prefix sequence numbers with N (up to 16) hex characters of a hash
+    /// of sequence number and random instance ID, making message IDs globally unique: `<hash>-<seqn>`
+    #[clap(long, env = "STDINTAP_SEQN_HASH_PREFIX")]
+    seqn_hash_prefix: Option<usize>,
+
+    /// Base of sequence numbers printed with `--seqn`. `REPLAY` and `RESUME` commands still take decimal numbers.
+    #[clap(long, value_enum, default_value = "dec", env = "STDINTAP_SEQN_BASE")]
+    seqn_base: SeqnBase,
+
+    /// Pad sequence numbers printed with `--seqn` with zeros to this width (up to 32)
+    #[clap(long, env = "STDINTAP_SEQN_WIDTH")]
+    seqn_width: Option<usize>,
+
+    /// Wrap sequence numbers shown to clients around to 0 when they reach N, so that they count
+    /// from 0 to N-1. Sequence numbers are not wrapped internally, so `--seqn-file` and history
+    /// keep counting. Numbers in `REPLAY` and `RESUME` commands are translated to the latest
+    /// line with that wrapped number, so they must be less than N lines old.
+    #[clap(long, env = "STDINTAP_SEQN_MODULUS")]
+    seqn_modulus: Option<u64>,
+
+    /// Print statistics of each client session to stderr when the client disconnects
+    #[clap(long, env = "STDINTAP_CLIENT_STATS_ON_DISCONNECT", value_parser = BoolishValueParser::new())]
+    client_stats_on_disconnect: bool,
+
+    /// Include `bytes_sent_total` and `lines_sent_total` (in addition to `bytes_total` read from input)
+    /// in `STATUS` and `DIAG` reports, and print client statistics on disconnect
+    /// like `--client-stats-on-disconnect`
+    #[clap(long, env = "STDINTAP_BYTE_STATS", value_parser = BoolishValueParser::new())]
+    byte_stats: bool,
+
+    /// Measure time from reading each line to writing it to a client. Percentiles are logged
+    /// on client disconnect and included in `STATUS` and `DIAG` reports across all clients.
+    #[clap(long, env = "STDINTAP_LATENCY_TRACKING", value_parser = BoolishValueParser::new())]
+    latency_tracking: bool,
+
+    /// Pre-populate `--history` buffer with lines from this file at startup.
+    ///
+    /// Lines may be prefixed with sequence number and a tab (as printed with `--seqn`).
+    /// Only the most recent lines fitting in `--history` are kept. New lines get sequence numbers
+    /// following the maximum loaded one.
+    #[clap(long, env = "STDINTAP_HISTORY_LOAD_FILE")]
+    history_load_file: Option<PathBuf>,
+
+    /// Don't split input into lines. Instead read and broadcast fixed-size chunks of this many bytes.
+    ///
+    /// The last chunk before EOF may be shorter. Sequence numbers count chunks.
+    /// Chunks are sent to clients as is, without line terminators.
+    /// Incompatible with options controlling line splitting, like `--max-line-size` or `-0`.
+    #[clap(
+        long,
+        visible_alias = "chunk-size",
+        env = "STDINTAP_BROADCAST_CHUNK_SIZE"
+    )]
+    broadcast_chunk_size: Option<usize>,
+
+    /// Don't spawn a task for each client. Instead drive all client connections from the accept loop.
+    ///
+    /// Intended for resource-constrained systems. Clients are still polled independently,
+    /// so a slow client does not block the others.
+    #[clap(long, visible_alias = "no-spawn", env = "STDINTAP_NO_CLIENT_TASK_SPAWN", value_parser = BoolishValueParser::new())]
+    no_client_task_spawn: bool,
+
+    /// Read options from this TOML file. Keys are long option names, e.g. `history = 100`
+    /// or `replace-field = ["2", "^a", "b"]`. Options from command line take precedence.
+    #[clap(long, env = "STDINTAP_CONFIG_FILE")]
+    config_file: Option<PathBuf>,
+
+    /// Print resolved options (from config file and command line) as TOML and exit
+    #[clap(long, env = "STDINTAP_DUMP_CONFIG", value_parser = BoolishValueParser::new())]
+    dump_config: bool,
+
+    /// On SIGUSR1, read lines from this file and broadcast them to all clients immediately,
+    /// bypassing `--line-throttle-rate` and `--backpressure` waits. The file is re-read on each signal.
+    ///
+    /// Injected lines get sequence numbers and are stored in `--history` like regular lines.
+    #[clap(long, env = "STDINTAP_PRIORITY_MESSAGE_FILE")]
+    priority_message_file: Option<PathBuf>,
+
+    /// Send each message to clients as a JSON object on its own line, e.g.
+    /// `{"kind":"content","ts":1.234567,"seqn":42,"encoding":"utf8","data":"..."}`.
+    ///
+    /// Line data is UTF-8 if valid, base64 otherwise, as indicated by `encoding` field. Separators are stripped.
+    /// Special messages (`overrun`, `backpressure`, `eof`, `hello`, `repeated`) use their own `kind`.
+    /// `--timestamps` and `--seqn` have no effect, as the fields are always present.
+    #[clap(long, env = "STDINTAP_JSON", value_parser = BoolishValueParser::new())]
+    json: bool,
+
+    /// With `--json`, indent each object over multiple lines for reading by eye, e.g. with `nc`.
+    ///
+    /// Each object is followed by a single output separator (newline, or NUL with `--zero-separated`).
+    /// Reformatting every message is CPU-intensive; avoid it for high line rates.
+    #[clap(long, env = "STDINTAP_OUTPUT_PRETTY_JSON", value_parser = BoolishValueParser::new())]
+    output_pretty_json: bool,
+
+    /// Send messages to clients as length-prefixed binary frames instead of separated lines.
+    ///
+    /// Each line is sent as 4-byte big-endian length followed by the payload, which includes
+    /// `--timestamps` and `--seqn` prefixes if enabled. Special messages are sent as a zero length
+    /// followed by 1-byte tag (`E`of, `B`ackpressure, `O`verrun, `H`ello, `R`epeated)
+    /// and 8-byte big-endian value (duration in milliseconds, count, client number or `--exec` exit code).
+    #[clap(long, env = "STDINTAP_BINARY_FRAMING", value_parser = BoolishValueParser::new())]
+    binary_framing: bool,
+
+    /// Send each line as a netstring `<length>:<payload>,`, with the same payload as `--binary-framing`.
+    ///
+    /// Special messages are netstrings with payloads `EOF`, `OVERRUN <count>`, `BACKPRESSURE <ms>`,
+    /// `HELLO <client number>`, `REPLAY <count>`, `HEARTBEAT <seqn>` or `REPEATED <count>`.
+    /// Nonzero `--exec` exit code is appended to `EOF` the same way.
+    /// Unlike content payloads, they don't end with the separator.
+    #[clap(long, env = "STDINTAP_NETSTRING", value_parser = BoolishValueParser::new())]
+    netstring: bool,
+
+    /// Disconnect a client if writing to it makes no progress for this long, e.g. `30s`.
+    ///
+    /// Unlike `--disconnect-on-overruns`, this also catches stuck clients when input is slow
+    /// and the queue does not overflow.
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_CLIENT_TIMEOUT")]
+    client_timeout: Option<Duration>,
+
+    /// Only forward lines matching this regular expression. Other lines are dropped before
+    /// reaching clients and `--history`.
+    #[clap(long, env = "STDINTAP_FILTER")]
+    filter: Option<String>,
+
+    /// Whether lines dropped by `--filter` still consume sequence numbers,
+    /// so that `--seqn` reflects positions in the unfiltered input
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set, env = "STDINTAP_FILTER_SEQN", value_parser = BoolishValueParser::new())]
+    filter_seqn: bool,
+
+    /// Additional address to accept clients on, e.g. a UNIX socket next to the main TCP one. Can be repeated.
+    ///
+    /// All listeners serve the same stream with the same options.
+    #[clap(long, env = "STDINTAP_LISTEN")]
+    listen: Vec<tokio_listener::ListenerAddress>,
+
+    /// Save `--history` buffer to this file on exit (stdin EOF, SIGTERM or SIGINT) and load it back on startup
+    #[clap(long, env = "STDINTAP_HISTORY_FILE")]
+    history_file: Option<PathBuf>,
+
+    /// Continue sequence numbers from this file on startup and keep it updated, so that they stay
+    /// unique across restarts. The file is written every `--seqn-sync-interval` lines
+    /// with the number that many lines ahead, and with the exact next number on exit.
+    ///
+    /// A missing file starts from 0. If the file cannot be read or does not hold a number, stdintap refuses to start.
+    #[clap(long, env = "STDINTAP_SEQN_FILE")]
+    seqn_file: Option<PathBuf>,
+
+    /// Number of lines between `--seqn-file` writes. Must be positive.
+    #[clap(long, default_value = "1000", env = "STDINTAP_SEQN_SYNC_INTERVAL")]
+    seqn_sync_interval: u64,
+
+    /// Serve Prometheus metrics over HTTP at `/metrics` on this address
+    #[clap(long, env = "STDINTAP_METRICS_ADDR")]
+    metrics_addr: Option<tokio_listener::ListenerAddress>,
+
+    /// On SIGTERM, SIGINT or stdin EOF, wait this long for clients to receive remaining data before exiting.
+    /// Exit is immediate when all clients have disconnected. 500ms by default.
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<Duration>,
+
+    /// Same as `--shutdown-timeout`, in milliseconds
+    #[clap(long, value_name = "MS", env = "STDINTAP_SHUTDOWN_WAIT")]
+    shutdown_wait: Option<u64>,
+
+    /// Deliver each line to only one of connected clients in turn instead of broadcasting it to all of them.
+    ///
+    /// Useful for feeding a pool of workers. Special messages and `--history` replay still go to every client.
+    /// Lines are not redistributed if the chosen client disconnects or overruns.
+    #[clap(long, env = "STDINTAP_LOADBALANCE", value_parser = BoolishValueParser::new())]
+    loadbalance: bool,
+
+    /// Reject new clients while this many clients are connected
+    #[clap(long, env = "STDINTAP_MAX_CLIENTS")]
+    max_clients: Option<usize>,
+
+    /// Message sent to clients rejected due to `--max-clients` before disconnecting them. Supports `\n` escapes.
+    #[clap(long, value_parser = parse_escapes, default_value = "MAX_CLIENTS\\n", env = "STDINTAP_MAX_CLIENTS_MSG")]
+    max_clients_msg: String,
+
+    /// Send `HEARTBEAT` line to clients that got no lines for this long, e.g. `5s`.
+    ///
+    /// With `--seqn`, the heartbeat carries sequence number of the last line sent to the client.
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_HEARTBEAT")]
+    heartbeat: Option<Duration>,
+
+    /// Only accept clients from this IP network, like `192.168.0.0/16` or `::1`. Can be specified multiple times.
+    ///
+    /// UNIX socket clients are not affected.
+    #[clap(long, env = "STDINTAP_ALLOW")]
+    allow: Vec<ipfilter::Cidr>,
+
+    /// Reject clients from this IP network. Can be specified multiple times. Takes precedence over `--allow`.
+    ///
+    /// Rejected clients are disconnected silently, or get `FORBIDDEN` line with `--announce-overruns`.
+    #[clap(long, env = "STDINTAP_DENY")]
+    deny: Vec<ipfilter::Cidr>,
+
+    /// Only accept UNIX socket clients running as this user ID, checked with `SO_PEERCRED`.
+    ///
+    /// Rejected clients get `FORBIDDEN` line and are disconnected. TCP clients are not affected.
+    #[clap(long, env = "STDINTAP_REQUIRE_UID")]
+    require_uid: Option<u32>,
+
+    /// Only accept UNIX socket clients running as this group ID, like `--require-uid`
+    #[clap(long, env = "STDINTAP_REQUIRE_GID")]
+    require_gid: Option<u32>,
+
+    /// Read stdin in an async task on the runtime instead of a dedicated reader thread.
+    ///
+    /// Throttling and backpressure then wait asynchronously. Not compatible with `--input`,
+    /// `--stdin-from-socket` and `--broadcast-chunk-size`, which use the blocking reader.
+    #[clap(long, env = "STDINTAP_ASYNC_STDIN", value_parser = BoolishValueParser::new())]
+    async_stdin: bool,
+
+    /// Re-encode content of each line before sending it to clients, for binary input and line-oriented clients.
+    ///
+    /// The line separator, timestamps and sequence numbers are not encoded. Ignored with `--json`.
+    #[clap(long, value_enum, default_value = "raw", env = "STDINTAP_ENCODING")]
+    encoding: encoding::Encoding,
+
+    /// Create UNIX socket at this path accepting control commands, one connection at a time:
+    ///
+    /// `STATUS` (JSON report like `DIAG`), `DISCONNECT ALL`, `RESET HISTORY`, `SET QLEN <N>` and `QUIT`
+    /// (graceful shutdown like SIGTERM). After `SET QLEN` clients may miss lines sent while they
+    /// switch to the new queue.
+    #[clap(long, env = "STDINTAP_ADMIN_SOCKET")]
+    admin_socket: Option<PathBuf>,
+
+    /// Write server events and warnings to stderr as JSON lines with `level`, `event` and `ts` fields.
+    ///
+    /// Also logs client connections and disconnections, overruns, backpressure and stdin EOF,
+    /// which are not reported in plain text mode.
+    #[clap(long, env = "STDINTAP_LOG_JSON", value_parser = BoolishValueParser::new())]
+    log_json: bool,
+
+    /// Route lines starting with a topic name followed by this character (e.g. `:` for `metrics:cpu=80`)
+    /// only to clients subscribed to the topic.
+    ///
+    /// Clients subscribe by sending `SUBSCRIBE <topic>` line in the beginning of connection, or `SUBSCRIBE *`
+    /// for all topics. Other clients get only lines without a topic. Lines are delivered unchanged,
+    /// including the prefix.
+    ///
+    /// `--history` limits apply to each topic separately, so that a busy topic does not evict lines of others.
+    /// Only the first 1024 topics get their own history; clients subscribed to other topics and to `*`
+    /// replay the shared history of all topics, filtered by subscription.
+    #[clap(long, env = "STDINTAP_TOPIC_PREFIX")]
+    topic_prefix: Option<char>,
+
+    /// Route lines matching the regex to clients subscribed to the group with `SUBSCRIBE <GROUP>`,
+    /// like `--topic-prefix` topics. Can be specified multiple times, the first matching route wins.
+    ///
+    /// Lines matching no route belong to the `default` group, which clients get if they don't subscribe.
+    /// `--history` limits apply to each group separately, the same way as for `--topic-prefix` topics.
+    #[clap(long, value_name = "REGEX:GROUP", env = "STDINTAP_ROUTE")]
+    route: Vec<String>,
+
+    /// Deliver at most this many lines per second to each client. Lines above the limit are
+    /// skipped and counted as overruns, or delayed with `--backpressure`.
+    #[clap(long, env = "STDINTAP_RATE_LIMIT")]
+    rate_limit: Option<f64>,
+
+    /// Number of lines that can be delivered to a client at full speed above `--rate-limit`.
+    /// Defaults to twice `--qlen`.
+    #[clap(long, env = "STDINTAP_RATE_LIMIT_BURST")]
+    rate_limit_burst: Option<f64>,
+
+    /// Replay `--history` to each client at most at this number of bytes of line content per second,
+    /// with a burst of one second worth of bytes. Live lines are delivered after the replay, as usual.
+    #[clap(long, env = "STDINTAP_HISTORY_REPLAY_RATE")]
+    history_replay_rate: Option<f64>,
+
+    /// Replay only the last N lines of `--history` to connecting clients, regardless of how many
+    /// are buffered. `REPLAY` command and session resumption are not limited.
+    #[clap(long, env = "STDINTAP_HISTORY_TAIL")]
+    history_tail: Option<usize>,
+
+    /// Copy `--history` every this number of milliseconds in background. Connecting clients
+    /// start from the copy and lock the history only to take lines added since, so that
+    /// a long history doesn't stall reading stdin while it is copied for each client.
+    #[clap(long, env = "STDINTAP_HISTORY_SNAPSHOT_INTERVAL")]
+    history_snapshot_interval: Option<u64>,
+
+    /// Drop `--history` lines older than this, e.g. `1h`, before replaying history to a connecting
+    /// client, so that it doesn't get stale lines after a quiet period
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_HISTORY_TTL")]
+    history_ttl: Option<Duration>,
+
+    /// Drop lines identical to the previous line (or one of `--dedup-window` recent distinct lines)
+    /// before sending them to clients and `--history`
+    #[clap(long, env = "STDINTAP_DEDUP", value_parser = BoolishValueParser::new())]
+    dedup: bool,
+
+    /// Number of recent distinct lines to compare new lines against with `--dedup`
+    #[clap(long, env = "STDINTAP_DEDUP_WINDOW")]
+    dedup_window: Option<usize>,
+
+    /// Don't consume sequence numbers for lines dropped by `--dedup`
+    #[clap(long, env = "STDINTAP_DEDUP_NO_SEQN", value_parser = BoolishValueParser::new())]
+    dedup_no_seqn: bool,
+
+    /// Inject `STATS lines=<N> bytes=<N> clients=<N> overruns=<N>` line into the stream
+    /// at this interval, e.g. `10s`. Counters are totals since startup.
+    /// With `--input-rate-measure-window`, `rate_bps=<X> rate_lps=<X>` fields with the current
+    /// input rate in bytes and lines per second follow.
+    ///
+    /// With `--json`, stats are sent as objects with `"kind":"stats"`.
+    /// Not sent with `--binary-framing`, as its special frames carry a single value.
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_STATS_INTERVAL")]
+    stats_interval: Option<Duration>,
+
+    /// Print `clients=<N>` with the number of connected clients to stderr at this interval,
+    /// e.g. `10s`. Prefixed with a monotone timestamp if `--timestamps` is active.
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_PRINT_CLIENT_COUNT")]
+    print_client_count: Option<Duration>,
+
+    /// Number of runtime worker threads serving clients. With more than 1, client connections
+    /// can be written in parallel. Reading stdin always happens in a separate thread.
+    #[clap(long, default_value = "1", env = "STDINTAP_THREADS")]
+    threads: usize,
+
+    /// Prepend this text to each content line sent to clients, after timestamp and sequence number.
+    /// Supports `\n` escapes.
+    ///
+    /// With `--json`, it is sent as a separate `prefix` field instead.
+    #[clap(long, value_parser = parse_escapes, env = "STDINTAP_PREFIX")]
+    prefix: Option<String>,
+
+    /// Append this text to each content line sent to clients, before the line terminator.
+    /// Supports `\n` escapes. `--max-line-size` limits only the line content.
+    ///
+    /// With `--json`, it is sent as a separate `suffix` field instead.
+    #[clap(long, value_parser = parse_escapes, env = "STDINTAP_SUFFIX")]
+    suffix: Option<String>,
+
+    /// Regex substitution `<REGEX>/<REPLACEMENT>` applied to each line before it is stored
+    /// and broadcast. Can be specified multiple times; substitutions are applied in order.
+    ///
+    /// Write `\/` for a literal `/` in the regex. Replacement can refer to capture groups
+    /// as `$1` or `${1}`. Lines are truncated to `--max-line-size` after substitution.
+    #[clap(long, env = "STDINTAP_SUB")]
+    sub: Vec<String>,
+
+    /// Send `SESSION <TOKEN>` line to each client first. A reconnecting client can send
+    /// `RESUME <TOKEN> <LAST_SEQN>` handshake line to get only history lines after `LAST_SEQN`
+    /// instead of the whole history. Each token can be used for resuming once.
+    ///
+    /// With `--json`, the token is sent as `session` message. Not supported with `--binary-framing`.
+    #[clap(long, env = "STDINTAP_SESSION_TOKENS", value_parser = BoolishValueParser::new())]
+    session_tokens: bool,
+
+    /// How long `--session-tokens` remain valid after the client disconnects, e.g. `10m`.
+    /// Forever by default.
+    #[clap(long, value_parser = parse_duration, env = "STDINTAP_SESSION_TTL")]
+    session_ttl: Option<Duration>,
+
+    /// When the broadcast queue is full, write new lines to a file in this directory instead of
+    /// letting slow clients miss them. Spilled lines are moved back to the queue in order as soon
+    /// as there is room, and the file is removed after that.
+    ///
+    /// Alternative to `--backpressure` and `--disconnect-on-overruns`. Slow clients still overrun
+    /// if other clients read much faster.
+    #[clap(long, env = "STDINTAP_SPILL_DIR")]
+    spill_dir: Option<PathBuf>,
+
+    /// Size of per-client write buffer in bytes. Larger buffers mean fewer syscalls
+    /// with high throughput, smaller ones send the first bytes of long lines sooner.
+    #[clap(long, default_value = "8192", env = "STDINTAP_WRITE_BUFFER")]
+    write_buffer: usize,
+
+    /// Inject `CLIENT_CONNECTED <ADDR>` and `CLIENT_DISCONNECTED <ADDR> reason=<REASON>` lines into the stream
+    /// when clients connect and disconnect. Reason is `eof`, `overrun`, `timeout`, `admin` or `error`.
+    ///
+    /// Clients don't get announcements about themselves. With `--json`, announcements are sent as
+    /// `client_connected` and `client_disconnected` messages. Not sent with `--binary-framing`.
+    #[clap(long, env = "STDINTAP_ANNOUNCE_CLIENTS", value_parser = BoolishValueParser::new())]
+    announce_clients: bool,
+
+    /// Also store `--announce-clients` announcements in `--history`
+    #[clap(long, env = "STDINTAP_ANNOUNCE_CLIENTS_HISTORY", value_parser = BoolishValueParser::new())]
+    announce_clients_history: bool,
+
+    /// Also read lines from this file or FIFO, in a separate thread. Can be specified multiple times.
+    ///
+    /// Lines from all sources are interleaved in arrival order and share sequence numbers.
+    /// EOF is announced and forwarded to clients only after all sources reach EOF.
+    /// Not compatible with `--async-stdin` and `--broadcast-chunk-size`.
+    #[clap(long, env = "STDINTAP_EXTRA_INPUT")]
+    extra_input: Vec<PathBuf>,
+
+    /// Prefix each line with the path of its source and a tab, to tell `--extra-input` sources apart.
+    /// Stdin is named `-`.
+    #[clap(long, env = "STDINTAP_SOURCE_TAG", value_parser = BoolishValueParser::new())]
+    source_tag: bool,
+
+    /// Use `<TAG>` instead of `<PATH>` for `--source-tag` of the source. Can be specified multiple times.
+    #[clap(long, value_name = "TAG:PATH", env = "STDINTAP_LABEL")]
+    label: Vec<String>,
+
+    /// Text of `--announce-overruns` line about missed lines. Supports `\n` escapes and placeholders
+    /// `{n}` (number of missed lines), `{seqn}` (sequence number of the next line) and
+    /// `{ts}` (seconds since startup).
+    #[clap(long, value_parser = parse_escapes, default_value = "OVERRUN {n}", env = "STDINTAP_OVERRUN_MESSAGE")]
+    overrun_message: String,
+
+    /// Text of `--announce-overruns` line about backpressure, with the same placeholders
+    /// as `--overrun-message`. `{n}` is the duration in milliseconds, or 0 if not known.
+    /// By default `BACKPRESSURE`, followed by ` duration_ms=<N>` if the duration is known.
+    #[clap(long, value_parser = parse_escapes, env = "STDINTAP_BACKPRESSURE_MESSAGE")]
+    backpressure_message: Option<String>,
+
+    /// Text of `--announce-overruns` line about EOF, with the same placeholders as `--overrun-message`.
+    /// `{n}` is the exit code of `--exec` command, or 0.
+    /// By default `EOF`, followed by ` exit_code=<N>` with `--exec`.
+    #[clap(long, value_parser = parse_escapes, env = "STDINTAP_EOF_MESSAGE")]
+    eof_message: Option<String>,
+
+    /// Append a JSON line to this file for each client connection and disconnection.
+    /// Disconnection records include bytes and lines sent and overruns.
+    ///
+    /// The file is reopened on SIGHUP, for external log rotation.
+    #[clap(long, env = "STDINTAP_ACCESS_LOG")]
+    access_log: Option<PathBuf>,
+
+    /// Rotate `--access-log` when it would grow above this size: rename it
+    /// to `<PATH>.1`, replacing the previous one, and start a new file
+    #[clap(long, env = "STDINTAP_ACCESS_LOG_MAX_BYTES")]
+    access_log_max_bytes: Option<u64>,
+
+    /// Append all lines to this file, formatted the same way as for clients (e.g. with `--timestamps`,
+    /// `--seqn` or `--json`). Can be specified multiple times.
+    ///
+    /// Files get every line regardless of `--loadbalance` and `--topic-prefix`, but no special messages.
+    /// On write error the file is abandoned with a warning.
+    #[clap(long, env = "STDINTAP_OUTPUT_FILE")]
+    output_file: Vec<PathBuf>,
+
+    /// Connect to this UNIX socket, e.g. of a downstream stdintap instance reading it with
+    /// `--stdin-from-socket --stdin-socket-mode listen`, and write all lines to it like `--output-file`.
+    /// On errors, reconnect with delays doubling from 100 ms up to 30 s, skipping lines meanwhile.
+    #[clap(long, env = "STDINTAP_TEE_SOCKET")]
+    tee_socket: Option<PathBuf>,
+
+    /// Replace separator bytes inside records (e.g. joined by `--input-multiline-mode join`)
+    /// with `--escape-seq`, so that each record is delivered as one line
+    #[clap(long, env = "STDINTAP_ESCAPE_SEPARATOR", value_parser = BoolishValueParser::new())]
+    escape_separator: bool,
+
+    /// Reverse `--escape-separator`: replace `--escape-seq` in input lines with the separator byte.
+    /// Useful with `--json` or `--binary-framing`, which delimit messages without separators.
+    #[clap(long, env = "STDINTAP_UNESCAPE", value_parser = BoolishValueParser::new())]
+    unescape: bool,
+
+    /// Escape sequence for `--escape-separator` and `--unescape` as hex bytes. `006e` (zero byte and `n`) by default.
+    ///
+    /// Lines should not contain the sequence itself, as it would be unescaped too.
+    #[clap(long, value_parser = parse_hex, env = "STDINTAP_ESCAPE_SEQ")]
+    escape_seq: Option<HexBytes>,
+}
+
+/// Byte string given in hex. A plain `Vec<u8>` would make clap expect multiple values.
+type HexBytes = Vec<u8>;
+
+/// Parse non-empty hex string like `0d0a`
+fn parse_hex(s: &str) -> Result<HexBytes, String> {
+    // `from_str_radix` alone would also accept a sign like in `+f`
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_hexdigit()) || !s.len().is_multiple_of(2) {
+        return Err("expected even number of hex digits".to_owned());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Expand backslash escapes `\n`, `\t`, `\r`, `\0` and `\\`
+fn parse_escapes(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('\\') => out.push('\\'),
+            Some(c) => return Err(format!("unknown escape sequence \\{c}")),
+            None => return Err("trailing backslash".to_owned()),
+        }
+    }
+    Ok(out)
+}
+
+/// Parse durations like `1.5`, `200ms`, `10s`, `5m` or `1h`. Bare numbers are seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, multiplier) = if let Some(x) = s.strip_suffix("ms") {
+        (x, 0.001)
+    } else if let Some(x) = s.strip_suffix('s') {
+        (x, 1.0)
+    } else if let Some(x) = s.strip_suffix('m') {
+        (x, 60.0)
+    } else if let Some(x) = s.strip_suffix('h') {
+        (x, 3600.0)
+    } else {
+        (s, 1.0)
+    };
+    let num: f64 = num
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid duration: {s}"))?;
+    Duration::try_from_secs_f64(num * multiplier).map_err(|e| e.to_string())
+}
+
+/// `--flush-mode`
+#[derive(Clone, Copy)]
+enum FlushMode {
+    Always,
+    Idle,
+    Bytes(usize),
+    Timer(Duration),
+}
+
+fn parse_flush_mode(s: &str) -> Result<FlushMode, String> {
+    let (kind, param) = match s.split_once([':', ' ']) {
+        Some((kind, param)) => (kind, Some(param.trim())),
+        None => (s, None),
+    };
+    let param = param
+        .map(|x| x.parse::<u64>().map_err(|_| format!("Invalid number: {x}")))
+        .transpose()?;
+    match (kind, param) {
+        ("always", None) => Ok(FlushMode::Always),
+        ("idle", None) => Ok(FlushMode::Idle),
+        ("bytes", Some(n)) if n > 0 => Ok(FlushMode::Bytes(n as usize)),
+        ("timer", Some(ms)) if ms > 0 => Ok(FlushMode::Timer(Duration::from_millis(ms))),
+        _ => Err(format!(
+            "Expected `always`, `idle`, `bytes:<N>` or `timer:<MS>` with positive number, got {s}"
+        )),
+    }
+}
+
+fn parse_tiers(s: &str) -> Result<[usize; 3], String> {
+    let tiers = s
+        .split(':')
+        .map(|x| x.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    match tiers[..] {
+        [a, b, c] if a <= b && b <= c => Ok([a, b, c]),
+        _ => Err(format!(
+            "Expected three increasing counts like 100:1000:10000, got {s}"
+        )),
+    }
+}
+
+fn parse_sleep_strategy(s: &str) -> Result<broadcaster::SleepStrategy, String> {
+    let mut parts = s.split([':', ' ']).filter(|x| !x.is_empty());
+    let kind = parts.next().unwrap_or_default();
+    let params = parts
+        .map(|x| x.parse::<u64>().map_err(|_| format!("Invalid number: {x}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    match (kind, &params[..]) {
+        ("exponential", []) => Ok(broadcaster::SleepStrategy::Exponential),
+        ("linear", &[start, step]) if start > 0 || step > 0 => Ok(broadcaster::SleepStrategy::Linear { start, step }),
+        ("constant", &[us]) if us > 0 => Ok(broadcaster::SleepStrategy::Constant(us)),
+        _ => Err(format!(
+            "Expected `exponential`, `linear:<START_US>:<STEP_US>` or `constant:<US>` with nonzero sleep, got {s}"
+        )),
+    }
 }
 
 #[derive(Clone)]
 enum MsgInner {
     Content(Bytes),
     Eof,
-    Backpressure,
+    /// Optionally with duration of the backpressure that already happened
+    Backpressure(Option<Duration>),
+    /// Periodic `--stats-interval` report
+    Stats(stats::StatsSnapshot),
+    /// `--announce-clients` notification
+    ClientEvent(announce::ClientEvent),
 }
 
 #[derive(Clone)]
@@ -93,274 +1000,3042 @@ struct Msg {
     ts: Instant,
     inner: MsgInner,
     seqn: u64,
+    /// With `--loadbalance`, the only client that should receive this message
+    target: Option<u64>,
+    /// With `--topic-prefix`, topic of the line. `None` is the default topic.
+    topic: Option<Arc<str>>,
+    /// Number of additional identical lines following this one (for compacted history)
+    repeat_count: u32,
 }
 
-struct TimestampPrinter {
-    begin: Instant,
-    buf: String,
+/// Rolling window of recent stdin reads, for measuring input rate
+struct RateMeter {
+    window: Duration,
+    entries: VecDeque<(Instant, usize, usize)>,
 }
 
-impl TimestampPrinter {
-    fn new(begin: Instant) -> Self {
+impl RateMeter {
+    fn new(window: Duration) -> Self {
         Self {
-            begin,
-            buf: String::with_capacity(6 + 1 + 6 + 1),
+            window,
+            entries: VecDeque::new(),
         }
     }
 
-    async fn print(
-        &mut self,
-        mut conn: Pin<&mut impl AsyncWrite>,
-        ts: Instant,
-        sep: char,
-    ) -> std::io::Result<()> {
-        let x = ts - self.begin;
-        let s = x.as_secs();
-        let m = x.subsec_micros();
-        self.buf.clear();
-        let _ = write!(self.buf, "{s:06}.{m:06}{sep}");
-        conn.write_all(self.buf.as_bytes()).await
+    fn evict(&mut self, now: Instant) {
+        while let Some(&(ts, _, _)) = self.entries.front() {
+            if now.duration_since(ts) <= self.window {
+                break;
+            }
+            self.entries.pop_front();
+        }
     }
-}
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> anyhow::Result<()> {
-    let Args {
-        listener,
-        qlen,
-        backpressure,
-        announce_overruns,
-        disconnect_on_overruns,
-        timestamps,
-        hello_message,
-        max_line_size,
-        zero_separated,
-        tee,
-        seqn: print_seqn,
-        history,
-        require_observer,
-    } = Args::parse();
+    fn record(&mut self, now: Instant, bytes: usize, lines: usize) {
+        self.entries.push_back((now, bytes, lines));
+        self.evict(now);
+    }
 
-    if qlen < 2 && backpressure {
-        anyhow::bail!("backpressure requires qlen at least 2");
+    /// Returns bytes per second and lines per second
+    fn rates(&mut self, now: Instant) -> (f64, f64) {
+        self.evict(now);
+        let (bytes, lines) = self
+            .entries
+            .iter()
+            .fold((0, 0), |(b, l), &(_, b2, l2)| (b + b2, l + l2));
+        let secs = self.window.as_secs_f64();
+        (bytes as f64 / secs, lines as f64 / secs)
     }
+}
 
-    let tx = tokio::sync::broadcast::Sender::<Msg>::new(qlen);
-    let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+/// Token bucket rate limiter
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
 
-    let tx2 = tx.clone();
+impl TokenBucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
 
-    let begin = Instant::now();
-    let byte_to_look_at = if zero_separated { b'\0' } else { b'\n' };
-    let separator_char = if zero_separated { '\0' } else { '\n' };
+    /// Take one token or return how long to wait until it becomes available
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + self.rate * elapsed).min(self.burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
 
-    let history_buffer = if let Some(hl) = history {
-        Some((hl, Arc::new(Mutex::new(VecDeque::<Msg>::with_capacity(hl)))))
-    } else {
-        None
-    };
-    let history_buffer2 = history_buffer.clone();
+    /// Take `n` tokens, going into debt if there are not enough,
+    /// and return how long to wait until the debt is repaid
+    fn take_n(&mut self, now: Instant, n: f64) -> Option<Duration> {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + self.rate * elapsed).min(self.burst) - n;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+}
 
-    std::thread::spawn(move || {
-        let _shutdown_tx = shutdown_tx;
-        let si = std::io::stdin();
-        let mut si = si.lock();
-        let tx = tx2;
+/// Remove leading ASCII whitespace from the line, keeping the separator
+fn strip_leading_ascii_whitespace(line: Bytes, separator: u8) -> Bytes {
+    let n = line
+        .iter()
+        .take_while(|&&b| b.is_ascii_whitespace() && b != separator)
+        .count();
+    line.slice(n..)
+}
 
-        let so_;
-        let mut so = if tee {
-            so_ = std::io::stdout();
-            Some(so_.lock())
-        } else {
-            None
+/// Regex substitution applied to one field of a line
+struct FieldReplacer {
+    /// 0-based field index
+    field: usize,
+    separator: Vec<u8>,
+    regex: regex::Regex,
+    replacement: regex::Replacement,
+}
+
+impl FieldReplacer {
+    fn new(args: &[String], separator: &str) -> anyhow::Result<Self> {
+        let [n, re, rep] = args else {
+            anyhow::bail!("--replace-field requires three arguments");
         };
+        let field = match n.parse::<usize>() {
+            Ok(n) if n > 0 => n - 1,
+            _ => anyhow::bail!("--replace-field: field number must be a positive integer, not {n}"),
+        };
+        if separator.is_empty() {
+            anyhow::bail!("--field-separator must not be empty");
+        }
+        let regex = regex::Regex::new(re).map_err(|e| anyhow::anyhow!("{re}: {e}"))?;
+        let replacement = regex::Replacement::new(rep, &regex).map_err(anyhow::Error::msg)?;
+        Ok(Self {
+            field,
+            separator: separator.as_bytes().to_vec(),
+            regex,
+            replacement,
+        })
+    }
 
-        let history_buffer = history_buffer2;
-        let mut buf = BytesMut::with_capacity(8192 * 2);
+    /// Byte range of the field within `line`, if the line has enough fields
+    fn field_range(&self, line: &[u8]) -> Option<(usize, usize)> {
+        let sep = &self.separator[..];
+        let mut start = 0;
+        for _ in 0..self.field {
+            let off = line[start..].windows(sep.len()).position(|w| w == sep)?;
+            start += off + sep.len();
+        }
+        let end = line[start..]
+            .windows(sep.len())
+            .position(|w| w == sep)
+            .map_or(line.len(), |off| start + off);
+        Some((start, end))
+    }
 
-        let mut noticed_about_nonblocking_stdin = false;
-        let mut debt = 0usize;
-        let mut seqn = 0u64;
-        loop {
-            buf.reserve((8192 + debt).saturating_sub(buf.capacity()));
-            buf.resize(buf.capacity(), 0);
+    fn apply(&self, line: Bytes, line_separator: u8) -> Bytes {
+        let body_len = line.len() - usize::from(line.last() == Some(&line_separator));
+        let Some((start, end)) = self.field_range(&line[..body_len]) else {
+            return line;
+        };
+        let Some(replaced) = self
+            .regex
+            .replace(&line[start..end], &self.replacement, true)
+        else {
+            return line;
+        };
+        let mut out = BytesMut::with_capacity(line.len() - (end - start) + replaced.len());
+        out.extend_from_slice(&line[..start]);
+        out.extend_from_slice(&replaced);
+        out.extend_from_slice(&line[end..]);
+        out.freeze()
+    }
+}
+
+/// `--sub` regex substitution applied to whole lines
+struct Substitution {
+    regex: regex::Regex,
+    replacement: regex::Replacement,
+}
+
+impl Substitution {
+    /// Parse `<REGEX>/<REPLACEMENT>`, where `\/` in the regex is a literal `/`
+    fn new(rule: &str) -> anyhow::Result<Self> {
+        let mut re = String::with_capacity(rule.len());
+        let mut chars = rule.char_indices();
+        let rep = loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, '/')) => re.push('/'),
+                    Some((_, c)) => {
+                        re.push('\\');
+                        re.push(c);
+                    }
+                    None => re.push('\\'),
+                },
+                Some((i, '/')) => break &rule[i + 1..],
+                Some((_, c)) => re.push(c),
+                None => anyhow::bail!("--sub {rule}: expected <REGEX>/<REPLACEMENT>"),
+            }
+        };
+        let regex = regex::Regex::new(&re).map_err(|e| anyhow::anyhow!("{re}: {e}"))?;
+        let replacement = regex::Replacement::new(rep, &regex).map_err(anyhow::Error::msg)?;
+        Ok(Self { regex, replacement })
+    }
+}
+
+/// Apply `--sub` substitutions to the line, then truncate it to `max_line_size` bytes,
+/// keeping the separator
+fn substitute(line: Bytes, subs: &[Substitution], separator: u8, max_line_size: usize) -> Bytes {
+    let terminated = line.last() == Some(&separator);
+    let mut body = line.slice(..line.len() - usize::from(terminated));
+    let mut changed = false;
+    for s in subs {
+        if let Some(x) = s.regex.replace(&body, &s.replacement, true) {
+            body = Bytes::from(x);
+            changed = true;
+        }
+    }
+    if !changed {
+        return line;
+    }
+    let mut out = BytesMut::from(&body[..body.len().min(max_line_size)]);
+    if terminated {
+        out.extend_from_slice(&[separator]);
+    }
+    out.freeze()
+}
+
+/// `--escape-separator` or `--unescape` transformation of line contents
+struct Escape {
+    separator: u8,
+    seq: Vec<u8>,
+    /// Whether to unescape instead of escaping
+    reverse: bool,
+}
+
+impl Escape {
+    /// Transform the line, keeping its trailing separator
+    fn apply(&self, line: Bytes) -> Bytes {
+        let terminated = line.last() == Some(&self.separator);
+        let body = &line[..line.len() - usize::from(terminated)];
+        let (from, to) = if self.reverse {
+            (&self.seq[..], &[self.separator][..])
+        } else {
+            (&[self.separator][..], &self.seq[..])
+        };
+        let Some(first) = body.windows(from.len()).position(|w| w == from) else {
+            return line;
+        };
+        let mut out = BytesMut::with_capacity(line.len() + to.len());
+        out.extend_from_slice(&body[..first]);
+        let mut i = first;
+        while i < body.len() {
+            if body[i..].starts_with(from) {
+                out.extend_from_slice(to);
+                i += from.len();
+            } else {
+                out.extend_from_slice(&body[i..i + 1]);
+                i += 1;
+            }
+        }
+        if terminated {
+            out.extend_from_slice(&[self.separator]);
+        }
+        out.freeze()
+    }
+}
+
+/// `--max-line-action`: what to do with lines longer than `--max-line-size`
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum MaxLineAction {
+    /// Send the line in several parts, only the last one terminated
+    Split,
+    /// Send the beginning of the line, terminated, and discard the rest
+    Truncate,
+    /// Log a warning and discard the whole line
+    Error,
+}
+
+/// Length of `b` without an incomplete UTF-8 sequence at the end, if there is one
+/// and something precedes it. Other bytes are not checked.
+fn utf8_boundary(b: &[u8]) -> usize {
+    for p in (b.len().saturating_sub(3)..b.len()).rev() {
+        let width = match b[p] {
+            0x00..=0x7f => return b.len(),
+            0x80..=0xbf => continue,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4,
+        };
+        return if p > 0 && p + width > b.len() {
+            p
+        } else {
+            b.len()
+        };
+    }
+    b.len()
+}
+
+/// Splits input into lines and applies per-line transformations
+struct LineSplitter {
+    buf: BytesMut,
+    /// Length of incomplete line at the beginning of `buf`
+    debt: usize,
+    /// Last byte of the separator
+    separator: u8,
+    max_line_size: usize,
+    max_line_action: MaxLineAction,
+    /// Whether the rest of a line exceeding `max_line_size` is being skipped
+    discarding: bool,
+    /// Bytes preceding `separator` in multi-byte separator, stripped from stored lines
+    separator_prefix: Vec<u8>,
+    /// Whether lines may also end with bare `separator` (`--crlf`)
+    separator_prefix_optional: bool,
+    strip_leading_whitespace: bool,
+    field_replacer: Option<FieldReplacer>,
+    substitutions: Vec<Substitution>,
+    multiline: Option<multiline::Multiline>,
+    escape: Option<Escape>,
+    /// `--source-tag` prefix of each line
+    tag: Option<Bytes>,
+}
+
+impl LineSplitter {
+    /// Buffer to read the next portion of input into
+    fn read_buf(&mut self) -> &mut [u8] {
+        self.buf
+            .reserve((8192 + self.debt).saturating_sub(self.buf.capacity()));
+        self.buf.resize(self.buf.capacity(), 0);
+        &mut self.buf[self.debt..]
+    }
+
+    /// Data just read into `read_buf`
+    fn just_read(&self, n: usize) -> &[u8] {
+        &self.buf[self.debt..(self.debt + n)]
+    }
+
+    /// Process `n` bytes just read into `read_buf`, appending complete lines to `lines`
+    fn split(&mut self, n: usize, lines: &mut Vec<Bytes>) {
+        let mut n = n;
+        assert!(self.buf.len() >= self.debt + n);
+        while let Some(i) = (0..n).find(|&i| {
+            let end = self.debt + i;
+            (self.buf[end] == self.separator
+                && (self.separator_prefix_optional
+                    || self.buf[..end].ends_with(&self.separator_prefix)))
+                || end == self.max_line_size
+        }) {
+            let end = self.debt + i;
+            let terminated = self.buf[end] == self.separator
+                && (self.separator_prefix_optional
+                    || self.buf[..end].ends_with(&self.separator_prefix));
+            let len = if terminated {
+                end + 1
+            } else {
+                utf8_boundary(&self.buf[..=end])
+            };
+            let mut content = self.buf.split_to(len).freeze();
+            // Bytes after the UTF-8 boundary start the next part
+            self.debt = end + 1 - len;
+            n -= i + 1;
+
+            if self.discarding {
+                self.discarding = !terminated;
+                continue;
+            }
+            if !terminated {
+                match self.max_line_action {
+                    MaxLineAction::Split => (),
+                    MaxLineAction::Truncate => {
+                        let mut x = BytesMut::from(&content[..]);
+                        x.extend_from_slice(&[self.separator]);
+                        content = x.freeze();
+                        self.discarding = true;
+                    }
+                    MaxLineAction::Error => {
+                        log::event(
+                            log::Level::Warn,
+                            "line_too_long",
+                            &[("max_line_size", json::Field::Num(self.max_line_size as u64))],
+                            Some(format_args!(
+                                "Warning: discarding line longer than {} bytes",
+                                self.max_line_size
+                            )),
+                        );
+                        self.discarding = true;
+                        continue;
+                    }
+                }
+            }
+
+            let plen = self.separator_prefix.len();
+            if plen > 0
+                && content.last() == Some(&self.separator)
+                && content[..content.len() - 1].ends_with(&self.separator_prefix)
+            {
+                let mut x = BytesMut::from(&content[..content.len() - 1 - plen]);
+                x.extend_from_slice(&[self.separator]);
+                content = x.freeze();
+            }
+            if self.strip_leading_whitespace {
+                content = strip_leading_ascii_whitespace(content, self.separator);
+            }
+            if let Some(ref fr) = self.field_replacer {
+                content = fr.apply(content, self.separator);
+            }
+            if !self.substitutions.is_empty() {
+                content = substitute(
+                    content,
+                    &self.substitutions,
+                    self.separator,
+                    self.max_line_size,
+                );
+            }
+
+            if let Some(ref mut ml) = self.multiline {
+                match ml.process(content) {
+                    Some(x) => content = x,
+                    None => continue,
+                }
+            }
+
+            lines.push(self.tagged(content));
+        }
+        self.debt += n;
+    }
+
+    /// Apply `--escape-separator` and `--source-tag` to a complete line or record
+    fn tagged(&self, content: Bytes) -> Bytes {
+        let content = match self.escape {
+            Some(ref e) => e.apply(content),
+            None => content,
+        };
+        let Some(ref tag) = self.tag else {
+            return content;
+        };
+        let mut x = BytesMut::with_capacity(tag.len() + content.len());
+        x.extend_from_slice(tag);
+        x.extend_from_slice(&content);
+        x.freeze()
+    }
+
+    /// Pending multi-line record at EOF
+    fn finish(&mut self) -> Option<Bytes> {
+        let record = self.multiline.as_mut().and_then(|ml| ml.finish())?;
+        Some(self.tagged(record))
+    }
+}
+
+/// Everything threads reading input sources need to broadcast lines
+#[derive(Clone)]
+struct InputReader {
+    bc: Arc<Mutex<broadcaster::Broadcaster>>,
+    stats: Arc<stats::Stats>,
+    presence: Arc<ClientPresence>,
+    /// `--pause-stdin-on-no-clients`
+    pause_on_no_clients: bool,
+    /// `--observer-poll-ms`
+    observer_poll: Duration,
+    cancelled: Arc<AtomicBool>,
+    rate_meter: Option<Arc<Mutex<RateMeter>>>,
+    /// Number of input sources that have not reached EOF yet
+    remaining: Arc<AtomicUsize>,
+}
+
+impl InputReader {
+    /// Read lines from `si` until EOF, error or shutdown and broadcast them, copying input to `so`
+    fn read_lines(
+        &self,
+        si: &mut dyn Read,
+        mut so: Option<&mut dyn std::io::Write>,
+        splitter: &mut LineSplitter,
+    ) {
+        let mut noticed_about_nonblocking_stdin = false;
+        let mut lines = Vec::new();
+        loop {
+            if self.pause_on_no_clients
+                && !self
+                    .presence
+                    .wait_for_client(&self.cancelled, self.observer_poll)
+            {
+                break;
+            }
+
+            let n = match si.read(splitter.read_buf()) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {
+                    dbg!();
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    dbg!();
+                    if !noticed_about_nonblocking_stdin {
+                        log::event(
+                            log::Level::Warn,
+                            "stdin_nonblocking",
+                            &[],
+                            Some(format_args!(
+                                "Warning: stdin is set to nonblocking mode. Using a timer to poll it."
+                            )),
+                        );
+                        noticed_about_nonblocking_stdin = true;
+                    }
+                    std::thread::sleep(Duration::from_millis(20));
+                    continue;
+                }
+                Err(e) => {
+                    log::event(
+                        log::Level::Error,
+                        "stdin_read_error",
+                        &[("error", json::Field::Str(&e.to_string()))],
+                        Some(format_args!("Reading from stdio: {e}")),
+                    );
+                    break;
+                }
+            };
+            if self.cancelled.load(Relaxed) {
+                break;
+            }
+            if let Some(ref mut so) = so {
+                if so.write_all(splitter.just_read(n)).is_err() {
+                    log::event(
+                        log::Level::Error,
+                        "stdout_write_error",
+                        &[],
+                        Some(format_args!("Writing to stdout failed")),
+                    );
+                    break;
+                }
+            }
+            self.stats.bytes_total.fetch_add(n as u64, Relaxed);
+            splitter.split(n, &mut lines);
+            let lines_read = lines.len();
+            for content in lines.drain(..) {
+                broadcaster::Broadcaster::send_line(&self.bc, content);
+            }
+
+            if let Some(ref rm) = self.rate_meter {
+                rm.lock().unwrap().record(Instant::now(), n, lines_read);
+            }
+        }
+
+        if let Some(record) = splitter.finish() {
+            broadcaster::Broadcaster::send_line(&self.bc, record);
+        }
+    }
+
+    /// Mark one input source as finished, sending EOF after the last one
+    fn finish_source(&self) {
+        if self.remaining.fetch_sub(1, Relaxed) == 1 {
+            self.bc.lock().unwrap().send_eof();
+        }
+    }
+}
+
+/// Read exactly `size` bytes unless EOF is reached earlier
+fn read_chunk(r: &mut dyn Read, size: usize) -> std::io::Result<Bytes> {
+    let mut buf = BytesMut::zeroed(size);
+    let mut filled = 0;
+    while filled < size {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf.freeze())
+}
+
+impl Msg {
+    /// EOF sent on shutdown signal from outside the stdin reader. The maximum sequence number
+    /// ensures it is not skipped by clients that are still replaying history.
+    fn shutdown_eof() -> Msg {
+        Msg {
+            ts: Instant::now(),
+            inner: MsgInner::Eof,
+            seqn: u64::MAX,
+            target: None,
+            topic: None,
+            repeat_count: 0,
+        }
+    }
+
+    /// `--stats-interval` report. It carries the sequence number of the next line.
+    fn stats(snapshot: stats::StatsSnapshot, next_seqn: u64) -> Msg {
+        Msg {
+            ts: Instant::now(),
+            inner: MsgInner::Stats(snapshot),
+            seqn: next_seqn,
+            target: None,
+            topic: None,
+            repeat_count: 0,
+        }
+    }
+
+    /// Sequence number of the last line represented by this message
+    fn last_seqn(&self) -> u64 {
+        self.seqn + u64::from(self.repeat_count)
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum SeqnBase {
+    Dec,
+    Hex,
+    Oct,
+}
+
+struct SeqnPrinter {
+    /// Number of hex digits of the hash and the instance ID to hash together with seqn
+    hash_prefix: Option<(usize, u128)>,
+    base: SeqnBase,
+    /// Minimal number of digits, padded with zeros
+    width: usize,
+    /// `--seqn-modulus`
+    modulus: Option<u64>,
+    buf: String,
+}
+
+impl SeqnPrinter {
+    fn new(
+        hash_prefix: Option<(usize, u128)>,
+        base: SeqnBase,
+        width: usize,
+        modulus: Option<u64>,
+    ) -> Self {
+        Self {
+            hash_prefix,
+            base,
+            width,
+            modulus,
+            buf: String::with_capacity(16 + 1 + 32 + 1),
+        }
+    }
+
+    async fn print(
+        &mut self,
+        mut conn: Pin<&mut impl AsyncWrite>,
+        seqn: u64,
+    ) -> std::io::Result<()> {
+        let seqn = wrap_seqn(seqn, self.modulus);
+        self.buf.clear();
+        if let Some((n, instance_id)) = self.hash_prefix {
+            // FNV-1a
+            let mut h: u64 = 0xcbf29ce484222325;
+            for b in instance_id
+                .to_le_bytes()
+                .into_iter()
+                .chain(seqn.to_le_bytes())
+            {
+                h = (h ^ u64::from(b)).wrapping_mul(0x100000001b3);
+            }
+            let _ = write!(self.buf, "{:016x}", h);
+            self.buf.truncate(n);
+            self.buf.push('-');
+        }
+        let width = self.width;
+        let _ = match self.base {
+            SeqnBase::Dec => write!(self.buf, "{seqn:0width$}\t"),
+            SeqnBase::Hex => write!(self.buf, "{seqn:0width$x}\t"),
+            SeqnBase::Oct => write!(self.buf, "{seqn:0width$o}\t"),
+        };
+        conn.write_all(self.buf.as_bytes()).await
+    }
+}
+
+/// Format of the first timestamp field
+#[derive(Clone, Copy)]
+enum TimestampMode {
+    /// Seconds since startup, `--timestamps`
+    Monotone,
+    /// Seconds since UNIX epoch, `--unix-timestamps`
+    Unix,
+    /// Seconds since the previous message, `--delta-timestamps`
+    Delta,
+}
+
+struct TimestampPrinter {
+    begin: Instant,
+    mode: Option<TimestampMode>,
+    /// Wall clock time corresponding to `begin`
+    begin_wall: SystemTime,
+    /// Whether RFC 3339 wall-clock timestamps are enabled
+    rfc3339: bool,
+    /// Time of the previous message for `--delta-timestamps`
+    last_ts: Option<Instant>,
+    buf: String,
+}
+
+impl TimestampPrinter {
+    fn new(
+        begin: Instant,
+        mode: Option<TimestampMode>,
+        begin_wall: SystemTime,
+        rfc3339: bool,
+    ) -> Self {
+        Self {
+            begin,
+            mode,
+            begin_wall,
+            rfc3339,
+            last_ts: None,
+            buf: String::with_capacity(10 + 1 + 6 + 1 + 27 + 1),
+        }
+    }
+
+    async fn print(
+        &mut self,
+        mut conn: Pin<&mut impl AsyncWrite>,
+        ts: Instant,
+        sep: char,
+    ) -> std::io::Result<()> {
+        let x = ts - self.begin;
+        self.buf.clear();
+        let wall = || {
+            (self.begin_wall + x)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+        };
+        match self.mode {
+            Some(TimestampMode::Monotone) => {
+                let s = x.as_secs();
+                let m = x.subsec_micros();
+                let _ = write!(self.buf, "{s:06}.{m:06}{sep}");
+            }
+            Some(TimestampMode::Unix) => {
+                let t = wall();
+                let _ = write!(self.buf, "{}.{:06}{sep}", t.as_secs(), t.subsec_micros());
+            }
+            Some(TimestampMode::Delta) => {
+                let d = match self.last_ts {
+                    Some(last) => ts.saturating_duration_since(last),
+                    None => x,
+                };
+                self.last_ts = Some(ts);
+                let _ = write!(self.buf, "+{}.{:06}{sep}", d.as_secs(), d.subsec_micros());
+            }
+            None => (),
+        }
+        if self.rfc3339 {
+            write_rfc3339(&mut self.buf, wall());
+            self.buf.push(sep);
+        }
+        conn.write_all(self.buf.as_bytes()).await
+    }
+}
+
+/// Format time since UNIX epoch as UTC date and time with microseconds
+fn write_rfc3339(buf: &mut String, t: Duration) {
+    let secs = t.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    let _ = write!(
+        buf,
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:06}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        t.subsec_micros(),
+    );
+}
+
+/// Line content for `--json` output: without the separator and truncated to `limit` bytes
+fn json_data(b: &[u8], limit: usize, separator: u8) -> &[u8] {
+    let b = b.strip_suffix(&[separator]).unwrap_or(b);
+    &b[..b.len().min(limit)]
+}
+
+/// Write line content, truncating it to `limit` bytes, but keeping the separator
+async fn write_truncated(
+    mut conn: Pin<&mut impl AsyncWrite>,
+    b: &[u8],
+    limit: usize,
+    separator: u8,
+) -> std::io::Result<()> {
+    if b.len() <= limit {
+        return conn.write_all(b).await;
+    }
+    conn.write_all(&b[..limit]).await?;
+    if b.last() == Some(&separator) {
+        conn.write_all(&[separator]).await?;
+    }
+    Ok(())
+}
+
+/// How content lines are written to clients in text and `--binary-framing` modes
+#[derive(Clone)]
+struct ContentFormat {
+    /// Separator of stored lines
+    separator: u8,
+    encoding: encoding::Encoding,
+    /// Written in place of the separator (e.g. `\r\n` with `--crlf`)
+    terminator: &'static [u8],
+    /// `--prefix`
+    prefix: Bytes,
+    /// `--suffix`
+    suffix: Bytes,
+}
+
+impl ContentFormat {
+    /// Write a content line truncated to `limit` bytes and re-encoded according to `--encoding`
+    async fn write(
+        &self,
+        mut conn: Pin<&mut impl AsyncWrite>,
+        b: &[u8],
+        limit: usize,
+    ) -> std::io::Result<()> {
+        if !self.prefix.is_empty() {
+            conn.write_all(&self.prefix).await?;
+        }
+        if self.encoding == encoding::Encoding::Raw
+            && self.terminator == [self.separator]
+            && self.suffix.is_empty()
+        {
+            return write_truncated(conn, b, limit, self.separator).await;
+        }
+        let (data, terminated) = match b.strip_suffix(&[self.separator]) {
+            Some(x) => (x, true),
+            None => (b, false),
+        };
+        let data = &data[..data.len().min(limit)];
+        conn.write_all(&self.encoding.encode(data)).await?;
+        if !self.suffix.is_empty() {
+            conn.write_all(&self.suffix).await?;
+        }
+        // Encoded lines are always terminated
+        if terminated || self.encoding != encoding::Encoding::Raw {
+            conn.write_all(self.terminator).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Text of special lines announcing overruns, backpressure and EOF in plain text mode
+#[derive(Clone)]
+struct Sentinels {
+    overrun: Arc<str>,
+    /// `None` for the default `BACKPRESSURE` line
+    backpressure: Option<Arc<str>>,
+    /// `None` for the default `EOF` line
+    eof: Option<Arc<str>>,
+    begin: Instant,
+    /// Exit code of `--exec` command, set before EOF is sent
+    exit_code: Arc<OnceLock<u64>>,
+    /// `--seqn-modulus`
+    seqn_modulus: Option<u64>,
+}
+
+impl Sentinels {
+    /// Expand `{n}`, `{seqn}` and `{ts}` placeholders
+    fn expand(&self, template: &str, n: u64, seqn: u64, ts: Instant) -> String {
+        let x = ts - self.begin;
+        template
+            .replace("{n}", &n.to_string())
+            .replace("{seqn}", &wrap_seqn(seqn, self.seqn_modulus).to_string())
+            .replace("{ts}", &format!("{}.{:06}", x.as_secs(), x.subsec_micros()))
+    }
+}
+
+/// Sequence number as shown to clients with `--seqn-modulus`
+fn wrap_seqn(seqn: u64, modulus: Option<u64>) -> u64 {
+    modulus.map_or(seqn, |m| seqn % m)
+}
+
+/// Sequence number sent by a client, which may be wrapped with `--seqn-modulus`,
+/// as the latest one not after `latest`
+fn unwrap_seqn(seqn: u64, latest: u64, modulus: Option<u64>) -> u64 {
+    let Some(m) = modulus else {
+        return seqn;
+    };
+    let (a, b) = (latest % m, seqn % m);
+    latest.saturating_sub(if a >= b { a - b } else { a + (m - b) })
+}
+
+/// Wait for the next tick of the interval, or forever if there is no interval
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(i) => {
+            i.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Random number for identifiers, based on the standard library's randomly seeded hasher
+fn random_u128() -> u128 {
+    use std::hash::{BuildHasher, Hasher};
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let mut x = 0u128;
+    for i in 0..2u8 {
+        let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+        h.write_u8(i);
+        h.write_u128(nanos);
+        x = x << 64 | u128::from(h.finish());
+    }
+    x
+}
+
+/// Name of this host, or `unknown` if it cannot be determined
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for writes of its length
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "unknown".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Client connection handlers driven directly by the accept loop instead of spawned tasks
+#[derive(Default)]
+struct ClientSet(Vec<Pin<Box<dyn Future<Output = ()>>>>);
+
+impl ClientSet {
+    /// Poll all clients, resolving when at least one of them finishes. Never resolves if there are no clients.
+    async fn run(&mut self) {
+        std::future::poll_fn(|cx| {
+            let before = self.0.len();
+            self.0.retain_mut(|f| f.as_mut().poll(cx).is_pending());
+            if self.0.len() < before {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Number of connected clients, with a way to wait for a client to appear or for all of them to leave
+#[derive(Default)]
+struct ClientPresence {
+    count: Mutex<usize>,
+    cv: Condvar,
+    notify: tokio::sync::Notify,
+    /// When the last client disconnected
+    emptied_at: Mutex<Option<Instant>>,
+    /// `--observer-hysteresis-ms`
+    hysteresis: Duration,
+}
+
+impl ClientPresence {
+    fn count(&self) -> usize {
+        *self.count.lock().unwrap()
+    }
+
+    /// Whether the last client disconnected less than `--observer-hysteresis-ms` ago
+    fn recently_emptied(&self) -> bool {
+        self.emptied_at
+            .lock()
+            .unwrap()
+            .is_some_and(|t| t.elapsed() < self.hysteresis)
+    }
+
+    /// Block the thread until at least one client is connected. Returns `false` if `cancelled`
+    /// gets set first, which is checked every `poll` interval.
+    fn wait_for_client(&self, cancelled: &AtomicBool, poll: Duration) -> bool {
+        let mut count = self.count.lock().unwrap();
+        if *count > 0 || self.recently_emptied() {
+            return true;
+        }
+        log_waiting_for_client();
+        while *count == 0 {
+            if cancelled.load(Relaxed) {
+                return false;
+            }
+            count = self.cv.wait_timeout(count, poll).unwrap().0;
+        }
+        log_client_arrived();
+        true
+    }
+
+    /// Async counterpart of [`ClientPresence::wait_for_client`]
+    async fn client_connected(&self) {
+        if self.count() > 0 || self.recently_emptied() {
+            return;
+        }
+        log_waiting_for_client();
+        loop {
+            let notified = self.notify.notified();
+            if self.count() > 0 {
+                break;
+            }
+            notified.await;
+        }
+        log_client_arrived();
+    }
+
+    /// Wait until there are no connected clients
+    async fn all_disconnected(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.count() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+fn log_waiting_for_client() {
+    log::event(
+        log::Level::Info,
+        "waiting_for_client",
+        &[],
+        Some(format_args!("Waiting for observer...")),
+    );
+}
+
+fn log_client_arrived() {
+    log::event(
+        log::Level::Info,
+        "client_arrived",
+        &[],
+        Some(format_args!("Observer connected, resuming stdin read")),
+    );
+}
+
+/// Registers a connected client in [`ClientPresence`] until dropped
+struct ClientGuard(Arc<ClientPresence>);
+
+impl ClientGuard {
+    fn new(presence: Arc<ClientPresence>) -> Self {
+        *presence.count.lock().unwrap() += 1;
+        presence.cv.notify_all();
+        presence.notify.notify_waiters();
+        Self(presence)
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            *self.0.emptied_at.lock().unwrap() = Some(Instant::now());
+        }
+        drop(count);
+        self.0.notify.notify_waiters();
+    }
+}
+
+/// Per-client counters for `--client-stats-on-disconnect`
+#[derive(Default)]
+struct ClientCounters {
+    bytes_sent: AtomicU64,
+    lines_sent: AtomicU64,
+    overruns: AtomicU64,
+    /// `--latency-tracking` samples
+    latency: Option<Mutex<latency::Samples>>,
+}
+
+impl ClientCounters {
+    /// Count one line written to the client, also in global statistics
+    fn line_sent(&self, stats: &stats::Stats) {
+        self.lines_sent.fetch_add(1, Relaxed);
+        stats.lines_sent_total.fetch_add(1, Relaxed);
+    }
+
+    /// Record `--latency-tracking` sample of a line read at `ts`, also in global statistics
+    fn line_written(&self, stats: &stats::Stats, ts: Instant) {
+        let (Some(l), Some(total)) = (&self.latency, &stats.latency) else {
+            return;
+        };
+        let d = ts.elapsed();
+        l.lock().unwrap().record(d);
+        total.lock().unwrap().record(d);
+    }
+}
+
+/// Writer wrapper that counts bytes written to the client
+struct CountingWriter<W> {
+    inner: W,
+    counters: Arc<ClientCounters>,
+    stats: Arc<stats::Stats>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = ret {
+            self.counters.bytes_sent.fetch_add(n as u64, Relaxed);
+            self.stats.bytes_sent_total.fetch_add(n as u64, Relaxed);
+        }
+        ret
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Fails pending writes and flushes with `TimedOut` error if they make no progress for `timeout`
+struct StallTimeoutWriter<W> {
+    inner: W,
+    timeout: Option<Duration>,
+    /// Armed while the inner writer is not ready
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<W> StallTimeoutWriter<W> {
+    fn check<T>(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        ret: Poll<std::io::Result<T>>,
+    ) -> Poll<std::io::Result<T>> {
+        let Some(timeout) = self.timeout else {
+            return ret;
+        };
+        if ret.is_ready() {
+            self.deadline = None;
+            return ret;
+        }
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "client is not reading",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for StallTimeoutWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.check(cx, ret)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let ret = Pin::new(&mut self.inner).poll_flush(cx);
+        self.check(cx, ret)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let ret = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.check(cx, ret)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    if std::env::args_os().nth(1).is_some_and(|x| x == "diagnose") {
+        return diagnose::run(diagnose::DiagnoseArgs::parse_from(
+            std::env::args_os().skip(1),
+        ));
+    }
+
+    let cmd = Args::command();
+    let matches = config::get_matches(cmd.clone())?;
+    if matches.get_flag("dump_config") {
+        config::dump(&cmd, &matches);
+        return Ok(());
+    }
+    let args = Args::from_arg_matches(&matches)?;
+
+    let runtime = match args.threads {
+        0 => anyhow::bail!("--threads must be at least 1"),
+        1 => tokio::runtime::Builder::new_current_thread(),
+        n => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(n);
+            builder
+        }
+    }
+    .enable_all()
+    .build()?;
+    runtime.block_on(serve(args))
+}
+
+async fn serve(args: Args) -> anyhow::Result<()> {
+    let Args {
+        listen_address,
+        listener_options,
+        systemd,
+        pid_file,
+        dry_run,
+        qlen,
+        backpressure,
+        backpressure_threshold,
+        backpressure_sleep_strategy,
+        announce_overruns,
+        disconnect_on_overruns,
+        timestamps: monotone_timestamps,
+        wall_timestamps,
+        unix_timestamps,
+        delta_timestamps,
+        hello_message,
+        client_greeting_echo,
+        hello_text,
+        max_line_size,
+        max_line_action,
+        zero_separated,
+        crlf,
+        output_separator,
+        separator,
+        tee,
+        seqn: print_seqn,
+        history,
+        history_bytes,
+        history_tiers,
+        tier_threshold,
+        pause_stdin_on_no_clients,
+        observer_poll_ms,
+        observer_hysteresis_ms,
+        line_length_limit_per_client,
+        forward_stdin_eof_as_disconnect,
+        input_rate_measure_window,
+        rate_log_interval,
+        stdin_from_socket,
+        connect,
+        reconnect_max_delay,
+        input,
+        exec,
+        exec_merge_stderr,
+        exec_restart,
+        stdin_socket_mode,
+        strip_leading_whitespace,
+        replace_field,
+        field_separator,
+        history_size_report,
+        announce_backpressure_duration,
+        client_write_batch_timeout,
+        flush_mode,
+        history_seqn_index,
+        diag,
+        line_throttle_rate,
+        line_throttle_burst,
+        history_compact_identical_runs,
+        input_multiline_mode,
+        multiline_continuation_pattern,
+        seqn_hash_prefix,
+        seqn_base,
+        seqn_width,
+        seqn_modulus,
+        client_stats_on_disconnect,
+        byte_stats,
+        latency_tracking,
+        history_load_file,
+        broadcast_chunk_size,
+        no_client_task_spawn,
+        config_file: _,
+        dump_config: _,
+        priority_message_file,
+        json,
+        output_pretty_json,
+        binary_framing,
+        netstring,
+        client_timeout,
+        filter,
+        filter_seqn,
+        listen,
+        history_file,
+        seqn_file,
+        seqn_sync_interval,
+        metrics_addr,
+        shutdown_timeout,
+        shutdown_wait,
+        loadbalance,
+        max_clients,
+        max_clients_msg,
+        heartbeat,
+        allow,
+        deny,
+        require_uid,
+        require_gid,
+        async_stdin,
+        encoding,
+        admin_socket,
+        log_json,
+        topic_prefix,
+        route,
+        rate_limit,
+        rate_limit_burst,
+        history_replay_rate,
+        history_tail,
+        history_snapshot_interval,
+        history_ttl,
+        dedup,
+        dedup_window,
+        dedup_no_seqn,
+        stats_interval,
+        print_client_count,
+        threads: _,
+        prefix,
+        suffix,
+        sub,
+        session_tokens,
+        session_ttl,
+        spill_dir,
+        write_buffer,
+        announce_clients,
+        announce_clients_history,
+        extra_input,
+        source_tag,
+        label,
+        overrun_message,
+        backpressure_message,
+        eof_message,
+        access_log,
+        access_log_max_bytes,
+        output_file,
+        tee_socket,
+        escape_separator,
+        unescape,
+        escape_seq,
+    } = args;
+    if log_json {
+        log::enable_json();
+    }
+
+    let hello_message = hello_message || hello_text.is_some();
+    let hello_template: Arc<str> = match (&hello_text, client_greeting_echo) {
+        (Some(text), _) => text,
+        (None, true) => "HELLO from={from} id={id} server={server}",
+        (None, false) => "HELLO",
+    }
+    .into();
+
+    if qlen < 2 && backpressure {
+        anyhow::bail!("backpressure requires qlen at least 2");
+    }
+    if !(backpressure_threshold > 0.0 && backpressure_threshold <= 1.0) {
+        anyhow::bail!("--backpressure-threshold must be greater than 0 and at most 1");
+    }
+    if rate_log_interval.is_some() && input_rate_measure_window.is_none() {
+        anyhow::bail!("--rate-log-interval requires --input-rate-measure-window");
+    }
+    if history.is_some() && history_tiers.is_some() {
+        anyhow::bail!("--history-tiers is an alternative to --history");
+    }
+    if tier_threshold.is_some() && history_tiers.is_none() {
+        anyhow::bail!("--tier-threshold requires --history-tiers");
+    }
+    if history_tiers.is_some() && (topic_prefix.is_some() || !route.is_empty()) {
+        anyhow::bail!("--history-tiers cannot be combined with --topic-prefix or --route");
+    }
+    let history = history.or(history_tiers.map(|t| t[2]));
+    let history =
+        (history.is_some() || history_bytes.is_some()).then(|| history.unwrap_or(usize::MAX));
+    if history_size_report && history.is_none() {
+        anyhow::bail!("--history-size-report requires --history");
+    }
+    if seqn_hash_prefix.is_some_and(|n| n == 0 || n > 16) {
+        anyhow::bail!("--seqn-hash-prefix must be from 1 to 16");
+    }
+    if seqn_modulus == Some(0) {
+        anyhow::bail!("--seqn-modulus must be positive");
+    }
+    if seqn_width.is_some_and(|n| n > 32) {
+        anyhow::bail!("--seqn-width must be at most 32");
+    }
+    if output_pretty_json && !json {
+        anyhow::bail!("--output-pretty-json requires --json");
+    }
+    if json && binary_framing {
+        anyhow::bail!("--json and --binary-framing are mutually exclusive");
+    }
+    if json && netstring {
+        anyhow::bail!("--json and --netstring are mutually exclusive");
+    }
+    if binary_framing && netstring {
+        anyhow::bail!("--binary-framing and --netstring are mutually exclusive");
+    }
+    if zero_separated && (binary_framing || netstring) {
+        log::event(
+            log::Level::Warn,
+            "config_warning",
+            &[],
+            Some(format_args!(
+                "Warning: --zero-separated only affects input splitting with --binary-framing or --netstring"
+            )),
+        );
+    }
+    let topic_prefix = match topic_prefix {
+        Some(c) if !c.is_ascii() => anyhow::bail!("--topic-prefix must be an ASCII character"),
+        x => x.map(|c| c as u8),
+    };
+    if topic_prefix.is_some() && !route.is_empty() {
+        anyhow::bail!("--topic-prefix and --route are mutually exclusive");
+    }
+    let mut routes = Vec::with_capacity(route.len());
+    for r in &route {
+        let Some((re, group)) = r.rsplit_once(':') else {
+            anyhow::bail!("--route must be REGEX:GROUP");
+        };
+        if group.is_empty() || group.contains(char::is_whitespace) || group == "*" {
+            anyhow::bail!("{r}: invalid --route group name");
+        }
+        if group == "default" {
+            anyhow::bail!("{r}: `default` group is reserved for lines matching no route");
+        }
+        let regex = regex::Regex::new(re).map_err(|e| anyhow::anyhow!("{re}: {e}"))?;
+        routes.push((regex, Arc::<str>::from(group)));
+    }
+    if zero_separated && crlf {
+        anyhow::bail!("--zero-separated and --crlf are mutually exclusive");
+    }
+    if broadcast_chunk_size == Some(0) {
+        anyhow::bail!("--broadcast-chunk-size must be positive");
+    }
+    if broadcast_chunk_size.is_some()
+        && (strip_leading_whitespace
+            || replace_field.is_some()
+            || !sub.is_empty()
+            || input_multiline_mode.is_some())
+    {
+        anyhow::bail!("--broadcast-chunk-size is incompatible with line processing options");
+    }
+    if broadcast_chunk_size.is_some()
+        && (max_line_size.is_some() || zero_separated || crlf || separator.is_some())
+    {
+        anyhow::bail!("--broadcast-chunk-size is incompatible with line splitting options");
+    }
+    let max_line_size = max_line_size.unwrap_or(65536);
+    let rate_limit_burst = rate_limit_burst.unwrap_or(2.0 * qlen as f64);
+    if history_replay_rate.is_some_and(|r| r <= 0.0) {
+        anyhow::bail!("--history-replay-rate must be positive");
+    }
+    if history_replay_rate.is_some() && history.is_none() {
+        anyhow::bail!("--history-replay-rate requires --history");
+    }
+    if history_snapshot_interval == Some(0) {
+        anyhow::bail!("--history-snapshot-interval must be positive");
+    }
+    if history_snapshot_interval.is_some() && history.is_none() {
+        anyhow::bail!("--history-snapshot-interval requires --history");
+    }
+    if history_ttl.is_some() && history.is_none() {
+        anyhow::bail!("--history-ttl requires --history");
+    }
+    if history_tail.is_some() && history.is_none() {
+        anyhow::bail!("--history-tail requires --history");
+    }
+    if rate_limit.is_some_and(|r| r <= 0.0) || rate_limit_burst < 1.0 {
+        anyhow::bail!("--rate-limit must be positive and --rate-limit-burst at least 1");
+    }
+    if session_tokens && (binary_framing || netstring) {
+        anyhow::bail!("--session-tokens is not supported with --binary-framing or --netstring");
+    }
+    if announce_clients_history && !(announce_clients && history.is_some()) {
+        anyhow::bail!("--announce-clients-history requires --announce-clients and --history");
+    }
+    let shutdown_timeout = match (shutdown_timeout, shutdown_wait) {
+        (Some(_), Some(_)) => {
+            anyhow::bail!("--shutdown-timeout and --shutdown-wait are mutually exclusive")
+        }
+        (Some(x), None) => x,
+        (None, Some(ms)) => Duration::from_millis(ms),
+        (None, None) => Duration::from_millis(500),
+    };
+    if observer_poll_ms == 0 {
+        anyhow::bail!("--observer-poll-ms must be positive");
+    }
+    if observer_hysteresis_ms > 0 && !pause_stdin_on_no_clients {
+        anyhow::bail!("--observer-hysteresis-ms requires --pause-stdin-on-no-clients");
+    }
+    if dry_run && (systemd || !listen.is_empty()) {
+        anyhow::bail!("--dry-run doesn't listen, so --systemd and --listen can't be used with it");
+    }
+    if dry_run && pause_stdin_on_no_clients {
+        anyhow::bail!("--pause-stdin-on-no-clients would never read input with --dry-run");
+    }
+    if let (FlushMode::Timer(_), Some(_)) = (flush_mode, client_write_batch_timeout) {
+        anyhow::bail!("--flush-mode timer and --client-write-batch-timeout are alternatives");
+    }
+    if write_buffer == 0 {
+        anyhow::bail!("--write-buffer must be positive");
+    }
+    if print_client_count.is_some_and(|x| x.is_zero()) {
+        anyhow::bail!("--print-client-count must be positive");
+    }
+    if spill_dir.is_some() && (backpressure || disconnect_on_overruns) {
+        anyhow::bail!(
+            "--spill-dir is an alternative to --backpressure and --disconnect-on-overruns"
+        );
+    }
+    if line_throttle_rate.is_some_and(|r| r <= 0.0) || line_throttle_burst < 1.0 {
+        anyhow::bail!("--line-throttle-rate must be positive and --line-throttle-burst at least 1");
+    }
+
+    let channel = Arc::new(Mutex::new(broadcaster::Channel::new(qlen)));
+    let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+
+    let begin = Instant::now();
+    let begin_wall = SystemTime::now();
+    if [monotone_timestamps, unix_timestamps, delta_timestamps]
+        .into_iter()
+        .filter(|&x| x)
+        .count()
+        > 1
+    {
+        anyhow::bail!(
+            "--timestamps, --unix-timestamps and --delta-timestamps are mutually exclusive"
+        );
+    }
+    let timestamp_mode = if unix_timestamps {
+        Some(TimestampMode::Unix)
+    } else if delta_timestamps {
+        Some(TimestampMode::Delta)
+    } else {
+        monotone_timestamps.then_some(TimestampMode::Monotone)
+    };
+    let timestamps = timestamp_mode.is_some() || wall_timestamps;
+    let seqn_hash_prefix = seqn_hash_prefix.map(|n| (n, random_u128()));
+    let stats = Arc::new(stats::Stats::new(begin, byte_stats, latency_tracking));
+    let separator = match separator {
+        Some(_) if zero_separated || crlf => {
+            anyhow::bail!("--separator is not compatible with --zero-separated and --crlf")
+        }
+        Some(x) => x,
+        None if zero_separated => b"\0".to_vec(),
+        None if crlf => b"\r\n".to_vec(),
+        None => b"\n".to_vec(),
+    };
+    let byte_to_look_at = *separator.last().unwrap();
+    let topics = match topic_prefix {
+        Some(p) => Some(Arc::new(topic::Router::Prefix(p))),
+        None => (!routes.is_empty()).then(|| {
+            Arc::new(topic::Router::Routes {
+                routes,
+                separator: separator.clone(),
+            })
+        }),
+    };
+    let line_terminator: &'static str = match output_separator {
+        Some(x) => String::from_utf8(x)
+            .map_err(|_| anyhow::anyhow!("--output-separator must be valid UTF-8"))?,
+        None => String::from_utf8(separator.clone())
+            .map_err(|_| anyhow::anyhow!("--separator must be valid UTF-8"))?,
+    }
+    .leak();
+    let content_format = ContentFormat {
+        separator: byte_to_look_at,
+        encoding,
+        terminator: line_terminator.as_bytes(),
+        prefix: Bytes::from(prefix.unwrap_or_default()),
+        suffix: Bytes::from(suffix.unwrap_or_default()),
+    };
+
+    if input_multiline_mode.is_none() && multiline_continuation_pattern.is_some() {
+        anyhow::bail!("--multiline-continuation-pattern requires --input-multiline-mode")
+    }
+    // Each input source needs its own splitter, as it holds incomplete lines and records
+    let new_splitter = |tag: Option<Bytes>| -> anyhow::Result<LineSplitter> {
+        let field_replacer = replace_field
+            .as_ref()
+            .map(|x| FieldReplacer::new(x, field_separator.as_deref().unwrap_or("\t")))
+            .transpose()?;
+        let substitutions = sub
+            .iter()
+            .map(|x| Substitution::new(x))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let multiline = match input_multiline_mode {
+            Some(mode) => {
+                let pattern = multiline_continuation_pattern
+                    .as_ref()
+                    .map(|re| regex::Regex::new(re).map_err(|e| anyhow::anyhow!("{re}: {e}")))
+                    .transpose()?;
+                Some(multiline::Multiline::new(mode, pattern, byte_to_look_at))
+            }
+            None => None,
+        };
+        Ok(LineSplitter {
+            buf: BytesMut::with_capacity(8192 * 2),
+            debt: 0,
+            separator: byte_to_look_at,
+            max_line_size,
+            max_line_action,
+            discarding: false,
+            separator_prefix: separator[..separator.len() - 1].to_vec(),
+            separator_prefix_optional: crlf,
+            strip_leading_whitespace,
+            field_replacer,
+            substitutions,
+            multiline,
+            escape: (escape_separator || unescape).then(|| Escape {
+                separator: byte_to_look_at,
+                seq: escape_seq.clone().unwrap_or_else(|| b"\0n".to_vec()),
+                reverse: unescape,
+            }),
+            tag,
+        })
+    };
+
+    if escape_separator && unescape {
+        anyhow::bail!("--escape-separator and --unescape are mutually exclusive");
+    }
+    if escape_seq.is_some() && !(escape_separator || unescape) {
+        anyhow::bail!("--escape-seq requires --escape-separator or --unescape");
+    }
+    if escape_seq
+        .as_ref()
+        .is_some_and(|x| x.contains(&byte_to_look_at))
+    {
+        anyhow::bail!("--escape-seq must not contain the separator");
+    }
+    if broadcast_chunk_size.is_some() && (escape_separator || unescape) {
+        anyhow::bail!("--broadcast-chunk-size is incompatible with line processing options");
+    }
+    if !source_tag && !label.is_empty() {
+        anyhow::bail!("--label requires --source-tag");
+    }
+    let labels = label
+        .iter()
+        .map(|x| {
+            x.split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("--label {x}: expected <TAG>:<PATH>"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let tag_of = |name: &str| {
+        source_tag.then(|| {
+            let tag = labels
+                .iter()
+                .find(|&&(_, path)| path == name)
+                .map_or(name, |&(tag, _)| tag);
+            Bytes::from(format!("{tag}\t"))
+        })
+    };
+
+    let line_filter = filter
+        .map(|re| {
+            Ok::<_, anyhow::Error>(broadcaster::LineFilter {
+                regex: regex::Regex::new(&re).map_err(|e| anyhow::anyhow!("{re}: {e}"))?,
+                separator: byte_to_look_at,
+                count_seqn: filter_seqn,
+            })
+        })
+        .transpose()?;
+
+    if !dedup && (dedup_window.is_some() || dedup_no_seqn) {
+        anyhow::bail!("--dedup-window and --dedup-no-seqn require --dedup");
+    }
+    if dedup_window == Some(0) {
+        anyhow::bail!("--dedup-window must be at least 1");
+    }
+    let dedup = dedup.then(|| broadcaster::Dedup::new(dedup_window.unwrap_or(1), !dedup_no_seqn));
+
+    let history_buffer = history.map(|hl| {
+        Arc::new(Mutex::new(history::History::new(
+            hl,
+            history_bytes,
+            history_seqn_index,
+            history_compact_identical_runs,
+        )))
+    });
+    let history_buffer2 = history_buffer.clone();
+    // `--history-tiers` buffers other than the largest one, which is `history_buffer`
+    let tier_buffers: Vec<_> = history_tiers
+        .iter()
+        .flat_map(|t| &t[..2])
+        .map(|&n| {
+            Arc::new(Mutex::new(history::History::new(
+                n,
+                history_bytes,
+                history_seqn_index,
+                history_compact_identical_runs,
+            )))
+        })
+        .collect();
+    let topic_histories = match (history, &topics) {
+        (Some(hl), Some(_)) => Some(Arc::new(history::TopicHistories::new(
+            hl,
+            history_bytes,
+            history_seqn_index,
+            history_compact_identical_runs,
+        ))),
+        _ => None,
+    };
+
+    if seqn_sync_interval == 0 {
+        anyhow::bail!("--seqn-sync-interval must be positive");
+    }
+    let mut initial_seqn = 0;
+    let seqn_file = seqn_file
+        .map(|path| {
+            let (f, seqn) = seqnfile::SeqnFile::load(path.clone(), seqn_sync_interval)
+                .map_err(|e| anyhow::anyhow!("--seqn-file {}: {e}", path.display()))?;
+            initial_seqn = seqn;
+            Ok::<_, anyhow::Error>(f)
+        })
+        .transpose()?;
+    if let Some(path) = history_load_file {
+        let Some(ref hb) = history_buffer else {
+            anyhow::bail!("--history-load-file requires --history");
+        };
+        let lines = history::load_file(&path, byte_to_look_at)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        for tb in &tier_buffers {
+            tb.lock()
+                .unwrap()
+                .restore(lines.clone(), initial_seqn, topics.as_deref());
+        }
+        if let (Some(th), Some(t)) = (&topic_histories, &topics) {
+            th.restore(&lines, initial_seqn, t);
+        }
+        initial_seqn = hb
+            .lock()
+            .unwrap()
+            .restore(lines, initial_seqn, topics.as_deref());
+    }
+    if let Some(ref path) = history_file {
+        let Some(ref hb) = history_buffer else {
+            anyhow::bail!("--history-file requires --history");
+        };
+        match history::load_file(path, byte_to_look_at) {
+            Ok(lines) => {
+                for tb in &tier_buffers {
+                    tb.lock()
+                        .unwrap()
+                        .restore(lines.clone(), initial_seqn, topics.as_deref());
+                }
+                if let (Some(th), Some(t)) = (&topic_histories, &topics) {
+                    th.restore(&lines, initial_seqn, t);
+                }
+                initial_seqn = hb
+                    .lock()
+                    .unwrap()
+                    .restore(lines, initial_seqn, topics.as_deref())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => log::event(
+                log::Level::Warn,
+                "history_load_error",
+                &[
+                    ("path", json::Field::Str(&path.to_string_lossy())),
+                    ("error", json::Field::Str(&e.to_string())),
+                ],
+                Some(format_args!(
+                    "Warning: cannot load history from {}: {e}. Starting with empty history.",
+                    path.display()
+                )),
+            ),
+        }
+    }
+
+    let rate_meter = input_rate_measure_window.map(|w| Arc::new(Mutex::new(RateMeter::new(w))));
+
+    let main_source_name = match (&connect, &stdin_from_socket, &input, &exec) {
+        (Some(c), _, _, _) => c.to_string(),
+        (None, Some(addr), _, _) => addr.to_string(),
+        (None, None, Some(path), _) => path.to_string_lossy().into_owned(),
+        (None, None, None, Some(cmd)) => cmd.clone(),
+        (None, None, None, None) => "-".to_owned(),
+    };
+    if (exec_merge_stderr || exec_restart) && exec.is_none() {
+        anyhow::bail!("--exec-merge-stderr and --exec-restart require --exec");
+    }
+    if reconnect_max_delay == 0 {
+        anyhow::bail!("--reconnect-max-delay must be positive");
+    }
+    let exit_code = Arc::new(OnceLock::new());
+    let alt_input = match (connect, stdin_from_socket, input, exec) {
+        (Some(addr), None, None, None) => Some(input::open_connect(
+            addr,
+            Duration::from_millis(reconnect_max_delay),
+            announce_overruns.then_some(byte_to_look_at),
+        )),
+        (None, None, None, None) => None,
+        (None, Some(addr), None, None) => Some(input::open_socket(addr, stdin_socket_mode)?),
+        (None, None, Some(path), None) => Some(input::open_file(path)),
+        (None, None, None, Some(cmd)) => Some(
+            input::open_exec(cmd, exec_merge_stderr, exec_restart, exit_code.clone())
+                .map_err(|e| anyhow::anyhow!("--exec: {e}"))?,
+        ),
+        _ => anyhow::bail!(
+            "--stdin-from-socket, --connect, --input and --exec are mutually exclusive"
+        ),
+    };
+    if async_stdin && (alt_input.is_some() || broadcast_chunk_size.is_some()) {
+        anyhow::bail!(
+            "--async-stdin is not compatible with --input, --stdin-from-socket, --connect, --exec and --broadcast-chunk-size"
+        );
+    }
+    if !extra_input.is_empty() && (async_stdin || broadcast_chunk_size.is_some()) {
+        anyhow::bail!(
+            "--extra-input is not compatible with --async-stdin and --broadcast-chunk-size"
+        );
+    }
+    if source_tag && broadcast_chunk_size.is_some() {
+        anyhow::bail!("--broadcast-chunk-size is incompatible with line processing options");
+    }
+
+    let loadbalancer = loadbalance.then(|| Arc::new(loadbalance::LoadBalancer::default()));
+    let sessions = session_tokens.then(|| Arc::new(session::Sessions::new(session_ttl)));
+    let spill = spill_dir
+        .map(|dir| {
+            spill::Spill::new(dir.clone(), begin)
+                .map_err(|e| anyhow::anyhow!("{}: {e}", dir.display()))
+        })
+        .transpose()?;
+    let spilling = spill.is_some();
+    let bc = Arc::new(Mutex::new(broadcaster::Broadcaster {
+        channel: channel.clone(),
+        backpressure,
+        backpressure_threshold,
+        announce_backpressure_duration,
+        backpressure_sleep: backpressure_sleep_strategy,
+        history: history_buffer2,
+        tier_histories: tier_buffers.clone(),
+        topic_histories: topic_histories.clone(),
+        stats: stats.clone(),
+        throttle: line_throttle_rate.map(|r| TokenBucket::new(r, line_throttle_burst)),
+        filter: line_filter,
+        dedup,
+        loadbalancer: loadbalancer.clone(),
+        topics: topics.clone(),
+        seqn: initial_seqn,
+        seqn_file,
+        spill,
+    }));
+    stats.next_seqn.store(initial_seqn, Relaxed);
+
+    let new_formatter = || {
+        outputfile::Formatter::new(
+            timestamps
+                .then(|| TimestampPrinter::new(begin, timestamp_mode, begin_wall, wall_timestamps)),
+            print_seqn.then(|| {
+                SeqnPrinter::new(
+                    seqn_hash_prefix,
+                    seqn_base,
+                    seqn_width.unwrap_or(0),
+                    seqn_modulus,
+                )
+            }),
+            json.then(|| {
+                json::JsonPrinter::new(
+                    begin,
+                    wall_timestamps.then_some(begin_wall),
+                    content_format.prefix.clone(),
+                    content_format.suffix.clone(),
+                    seqn_modulus,
+                    output_pretty_json.then_some(line_terminator),
+                )
+            }),
+            binary_framing,
+            netstring,
+            content_format.clone(),
+        )
+    };
+    let mut output_files = Vec::with_capacity(output_file.len());
+    for path in output_file {
+        let file = outputfile::OutputFile::open(path.clone())
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        let of = outputfile::OutputFile {
+            path,
+            file,
+            format: new_formatter(),
+        };
+        output_files.push(tokio::spawn(of.run(&channel)));
+    }
+    if let Some(path) = tee_socket {
+        let ts = teesocket::TeeSocket {
+            path,
+            format: new_formatter(),
+        };
+        output_files.push(tokio::spawn(ts.run(&channel)));
+    }
+    if dry_run {
+        output_files.push(tokio::spawn(dryrun::run(&channel, begin)));
+    }
+
+    // `--netstring` shares the framed output paths with `--binary-framing`
+    let framed = binary_framing || netstring;
+    let presence = Arc::new(ClientPresence {
+        hysteresis: Duration::from_millis(observer_hysteresis_ms),
+        ..Default::default()
+    });
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let reader = InputReader {
+        bc: bc.clone(),
+        stats: stats.clone(),
+        presence: presence.clone(),
+        pause_on_no_clients: pause_stdin_on_no_clients,
+        observer_poll: Duration::from_millis(observer_poll_ms),
+        cancelled: cancelled.clone(),
+        rate_meter: rate_meter.clone(),
+        remaining: Arc::new(AtomicUsize::new(1 + extra_input.len())),
+    };
+    // Dropped when all input sources reach EOF
+    let shutdown_tx = Arc::new(shutdown_tx);
+
+    for path in extra_input {
+        let mut splitter = new_splitter(tag_of(&path.to_string_lossy()))?;
+        let reader = reader.clone();
+        let shutdown_tx = shutdown_tx.clone();
+        std::thread::spawn(move || {
+            let _shutdown_tx = shutdown_tx;
+            let mut si = input::open_file(path);
+            reader.read_lines(&mut si, None, &mut splitter);
+            reader.finish_source();
+        });
+    }
+
+    let mut splitter = new_splitter(tag_of(&main_source_name))?;
+
+    if async_stdin {
+        tokio::spawn(async move {
+            let _shutdown_tx = shutdown_tx;
+            let bc = reader.bc;
+            let mut si = tokio::io::stdin();
+            let mut so = tee.then(tokio::io::stdout);
+            let mut lines = Vec::new();
+            loop {
+                if pause_stdin_on_no_clients {
+                    reader.presence.client_connected().await;
+                }
+
+                let n = match si.read(splitter.read_buf()).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        log::event(
+                            log::Level::Error,
+                            "stdin_read_error",
+                            &[("error", json::Field::Str(&e.to_string()))],
+                            Some(format_args!("Reading from stdio: {e}")),
+                        );
+                        break;
+                    }
+                };
+                if reader.cancelled.load(Relaxed) {
+                    break;
+                }
+                if let Some(ref mut so) = so {
+                    if so.write_all(splitter.just_read(n)).await.is_err() {
+                        log::event(
+                            log::Level::Error,
+                            "stdout_write_error",
+                            &[],
+                            Some(format_args!("Writing to stdout failed")),
+                        );
+                        break;
+                    }
+                }
+                reader.stats.bytes_total.fetch_add(n as u64, Relaxed);
+                splitter.split(n, &mut lines);
+                let lines_read = lines.len();
+                for content in lines.drain(..) {
+                    broadcaster::Broadcaster::send_line_async(&bc, content).await;
+                }
+
+                if let Some(ref rm) = reader.rate_meter {
+                    rm.lock().unwrap().record(Instant::now(), n, lines_read);
+                }
+            }
+
+            if let Some(record) = splitter.finish() {
+                broadcaster::Broadcaster::send_line_async(&bc, record).await;
+            }
+            bc.lock().unwrap().send_eof();
+        });
+    } else {
+        std::thread::spawn(move || {
+            let _shutdown_tx = shutdown_tx;
+            let mut si: Box<dyn Read> = match alt_input {
+                Some(x) => x,
+                None => Box::new(std::io::stdin().lock()),
+            };
+
+            let so_;
+            let mut so = if tee {
+                so_ = std::io::stdout();
+                Some(so_.lock())
+            } else {
+                None
+            };
+
+            if let Some(chunk_size) = broadcast_chunk_size {
+                let bc = &reader.bc;
+                loop {
+                    if pause_stdin_on_no_clients
+                        && !reader
+                            .presence
+                            .wait_for_client(&reader.cancelled, reader.observer_poll)
+                    {
+                        break;
+                    }
+                    let chunk = match read_chunk(&mut si, chunk_size) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            log::event(
+                                log::Level::Error,
+                                "stdin_read_error",
+                                &[("error", json::Field::Str(&e.to_string()))],
+                                Some(format_args!("Reading from stdio: {e}")),
+                            );
+                            break;
+                        }
+                    };
+                    if chunk.is_empty() || reader.cancelled.load(Relaxed) {
+                        break;
+                    }
+                    if let Some(ref mut so) = so {
+                        if std::io::Write::write_all(so, &chunk).is_err() {
+                            log::event(
+                                log::Level::Error,
+                                "stdout_write_error",
+                                &[],
+                                Some(format_args!("Writing to stdout failed")),
+                            );
+                            break;
+                        }
+                    }
+                    reader
+                        .stats
+                        .bytes_total
+                        .fetch_add(chunk.len() as u64, Relaxed);
+                    if let Some(ref rm) = reader.rate_meter {
+                        rm.lock().unwrap().record(Instant::now(), chunk.len(), 1);
+                    }
+                    let last = chunk.len() < chunk_size;
+                    broadcaster::Broadcaster::send_line(bc, chunk);
+                    if last {
+                        break;
+                    }
+                }
+                bc.lock().unwrap().send_eof();
+                return;
+            }
+
+            reader.read_lines(
+                &mut si,
+                so.as_mut().map(|x| x as &mut dyn std::io::Write),
+                &mut splitter,
+            );
+            reader.finish_source();
+        });
+    }
+
+    let spill_cleanup = spilling.then(|| bc.clone());
+    let seqn_file_cleanup = bc.lock().unwrap().seqn_file.is_some().then(|| bc.clone());
+    if spilling {
+        let bc = bc.clone();
+        std::thread::spawn(move || {
+            let mut wait_micros = 1;
+            loop {
+                if bc.lock().unwrap().drain_spill() {
+                    wait_micros = 1;
+                } else if wait_micros < 65536 {
+                    wait_micros *= 2;
+                }
+                std::thread::sleep(Duration::from_micros(wait_micros));
+            }
+        });
+    }
 
-            if require_observer {
-                if tx.receiver_count() == 0 {
-                    std::thread::sleep(Duration::from_millis(200));
-                    continue
-                }
+    if let (Some(rm), Some(interval)) = (rate_meter.clone(), rate_log_interval) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let (bps, lps) = rm.lock().unwrap().rates(Instant::now());
+                log::event(
+                    log::Level::Info,
+                    "input_rate",
+                    &[
+                        ("bytes_per_sec", json::Field::Float(bps)),
+                        ("lines_per_sec", json::Field::Float(lps)),
+                    ],
+                    Some(format_args!(
+                        "Input rate: {bps:.1} bytes/s, {lps:.1} lines/s"
+                    )),
+                );
             }
+        });
+    }
 
-            let n = match si.read(&mut buf[debt..]) {
-                Ok(0) => break,
-                Ok(n) => n,
-                Err(e) if e.kind() == ErrorKind::Interrupted => {
-                    dbg!();
-                    continue;
+    let history_snapshot = match (history_snapshot_interval, &history_buffer) {
+        (Some(ms), Some(hb)) => {
+            let snapshot = Arc::new(history::Snapshot::default());
+            snapshot.refresh(hb);
+            let hb = Arc::downgrade(hb);
+            let snapshot2 = snapshot.clone();
+            tokio::spawn(async move {
+                let interval = Duration::from_millis(ms);
+                let mut timer =
+                    tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+                loop {
+                    timer.tick().await;
+                    let Some(hb) = hb.upgrade() else {
+                        break;
+                    };
+                    snapshot2.refresh(&hb);
                 }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    dbg!();
-                    if !noticed_about_nonblocking_stdin {
-                        eprintln!(
-                            "Warning: stdin is set to nonblocking mode. Using a timer to poll it."
-                        );
-                        noticed_about_nonblocking_stdin = true;
-                    }
-                    std::thread::sleep(Duration::from_millis(20));
+            });
+            Some(snapshot)
+        }
+        _ => None,
+    };
+
+    if let (true, Some(hb)) = (history_size_report, history_buffer.clone()) {
+        let mut sig = signals::Signal::new(libc::SIGUSR1)?;
+        tokio::spawn(async move {
+            while sig.recv().await.is_ok() {
+                let Ok(hb) = hb.try_lock() else {
+                    log::event(
+                        log::Level::Warn,
+                        "history_locked",
+                        &[],
+                        Some(format_args!("history locked, try again")),
+                    );
                     continue;
+                };
+                let bytes = hb.bytes();
+                let seqn_or_none = |msg: Option<&Msg>| match msg {
+                    Some(msg) => msg.seqn.to_string(),
+                    None => "none".to_owned(),
+                };
+                let mut fields = vec![
+                    ("lines", json::Field::Num(hb.len() as u64)),
+                    ("bytes", json::Field::Num(bytes as u64)),
+                ];
+                if let (Some(front), Some(back)) = (hb.front(), hb.back()) {
+                    fields.push(("oldest_seqn", json::Field::Num(front.seqn)));
+                    fields.push(("newest_seqn", json::Field::Num(back.seqn)));
                 }
-                Err(e) => {
-                    eprintln!("Reading from stdio: {e}");
-                    break;
-                }
-            };
-            if let Some(ref mut so) = so {
-                if std::io::Write::write_all(so, &buf[debt..(debt + n)]).is_err() {
-                    eprintln!("Writing to stdout failed");
-                    break;
-                }
+                log::event(
+                    log::Level::Info,
+                    "history_size",
+                    &fields,
+                    Some(format_args!(
+                        "history: {} lines, {bytes} bytes, oldest_seqn={}, newest_seqn={}",
+                        hb.len(),
+                        seqn_or_none(hb.front()),
+                        seqn_or_none(hb.back()),
+                    )),
+                );
             }
-            let mut n = n;
+        });
+    }
 
-            assert!(buf.len() >= debt + n);
-            'restarter: loop {
-                for i in 0..n {
-                    if buf[debt + i] == byte_to_look_at || debt + i == max_line_size {
-                        let content = buf.split_to(debt + i + 1).freeze();
-                        debt = 0;
-                        n -= i + 1;
+    if let Some(path) = priority_message_file {
+        let mut sig = signals::Signal::new(libc::SIGUSR1)?;
+        tokio::spawn(async move {
+            while sig.recv().await.is_ok() {
+                let lines = match std::fs::read(&path) {
+                    Ok(mut x) => {
+                        if x.last().is_some_and(|&b| b != byte_to_look_at) {
+                            x.push(byte_to_look_at);
+                        }
+                        history::split_lines(x.into(), byte_to_look_at)
+                    }
+                    Err(e) => {
+                        log::event(
+                            log::Level::Error,
+                            "priority_message_file_error",
+                            &[
+                                ("path", json::Field::Str(&path.to_string_lossy())),
+                                ("error", json::Field::Str(&e.to_string())),
+                            ],
+                            Some(format_args!("{}: {e}", path.display())),
+                        );
+                        continue;
+                    }
+                };
+                let bc = bc.clone();
+                let _ = tokio::task::spawn_blocking(move || {
+                    let mut bc = bc.lock().unwrap();
+                    for line in lines {
+                        bc.send_priority_line(line);
+                    }
+                })
+                .await;
+            }
+        });
+    }
 
-                        let ts = Instant::now();
+    let sentinels = Sentinels {
+        overrun: overrun_message.into(),
+        backpressure: backpressure_message.map(Arc::from),
+        eof: eof_message.map(Arc::from),
+        begin,
+        exit_code: exit_code.clone(),
+        seqn_modulus,
+    };
 
-                        let content_msg = Msg {
-                            ts,
-                            inner: MsgInner::Content(content),
-                            seqn,
-                        };
+    if access_log_max_bytes.is_some() && access_log.is_none() {
+        anyhow::bail!("--access-log-max-bytes requires --access-log");
+    }
+    let access_log = access_log
+        .map(|path| accesslog::spawn(path, access_log_max_bytes))
+        .transpose()?;
 
-                        if let Some((hl, ref hb)) = history_buffer {
-                            let mut hb = hb.lock().unwrap();
-                            if hb.len() >= hl {
-                                hb.pop_front();
-                            }
-                            hb.push_back(content_msg.clone());
-                        }
+    let hostname: Arc<str> = hostname().into();
+    if let Some(addr) = metrics_addr {
+        let l = tokio_listener::Listener::bind(
+            &addr,
+            &tokio_listener::SystemOptions::default(),
+            &tokio_listener::UserOptions::default(),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("{addr}: {e}"))?;
+        tokio::spawn(metrics::serve(
+            l,
+            metrics::Sources {
+                stats: stats.clone(),
+                presence: presence.clone(),
+                history: history_buffer.clone(),
+                rate_meter: rate_meter.clone(),
+            },
+        ));
+    }
 
-                        if !backpressure || tx.len() < qlen - 1 {
-                            let _ = tx.send(content_msg);
-                        } else {
-                            let _ = tx.send(Msg {
-                                ts,
-                                inner: MsgInner::Backpressure,
-                                seqn,
-                            });
-                            let mut wait_micros = 1;
-                            while tx.len() >= qlen - 1 {
-                                std::thread::sleep(Duration::from_micros(wait_micros));
-                                if wait_micros < 65536 {
-                                    wait_micros *= 2;
-                                }
-                            }
-                            let _ = tx.send(content_msg);
-                        }
-                        seqn += 1;
+    if let Some(interval) = stats_interval {
+        let channel = Arc::downgrade(&channel);
+        let stats = stats.clone();
+        let presence = presence.clone();
+        let rate_meter = rate_meter.clone();
+        tokio::spawn(async move {
+            let mut timer =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                timer.tick().await;
+                let Some(channel) = channel.upgrade() else {
+                    break;
+                };
+                let mut snapshot = stats.snapshot(presence.count());
+                snapshot.rate = rate_meter
+                    .as_ref()
+                    .map(|rm| rm.lock().unwrap().rates(Instant::now()));
+                let msg = Msg::stats(snapshot, stats.next_seqn.load(Relaxed));
+                channel.lock().unwrap().send(msg);
+            }
+        });
+    }
 
-                        continue 'restarter;
-                    }
-                }
-                break 'restarter;
+    if let Some(interval) = print_client_count {
+        let presence = presence.clone();
+        tokio::spawn(async move {
+            let mut timer =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                timer.tick().await;
+                let n = presence.count();
+                let ts = if monotone_timestamps {
+                    let x = begin.elapsed();
+                    format!("{:06}.{:06}\t", x.as_secs(), x.subsec_micros())
+                } else {
+                    String::new()
+                };
+                log::event(
+                    log::Level::Info,
+                    "client_count",
+                    &[("clients", json::Field::Num(n as u64))],
+                    Some(format_args!("{ts}clients={n}")),
+                );
             }
+        });
+    }
+
+    let announcer = announce_clients.then(|| {
+        Arc::new(announce::Announcer {
+            channel: Arc::downgrade(&channel),
+            history: history_buffer.clone().filter(|_| announce_clients_history),
+            tier_histories: if announce_clients_history {
+                tier_buffers.clone()
+            } else {
+                vec![]
+            },
+            topic_histories: topic_histories.clone().filter(|_| announce_clients_history),
+            stats: stats.clone(),
+            counter: AtomicU64::new(0),
+        })
+    });
 
-            debt += n;
+    let (disconnect_tx, disconnect_rx) = tokio::sync::watch::channel(0u64);
+    let disconnect_tx = Arc::new(disconnect_tx);
+    let admin_quit = Arc::new(tokio::sync::Notify::new());
+    if let Some(ref path) = admin_socket {
+        if std::fs::metadata(path)
+            .is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_socket(&m.file_type()))
+        {
+            let _ = std::fs::remove_file(path);
         }
+        let l = tokio::net::UnixListener::bind(path)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        tokio::spawn(admin::serve(
+            l,
+            admin::Admin {
+                stats: stats.clone(),
+                presence: presence.clone(),
+                history: history_buffer.clone(),
+                tier_histories: tier_buffers.clone(),
+                topic_histories: topic_histories.clone(),
+                channel: Arc::downgrade(&channel),
+                min_qlen: if backpressure { 2 } else { 1 },
+                disconnect: disconnect_tx.clone(),
+                quit: admin_quit.clone(),
+            },
+        ));
+    }
 
-        let _ = tx.send(Msg {
-            ts: Instant::now(),
-            inner: MsgInner::Eof,
-            seqn,
+    let (extra_conn_tx, mut extra_conn_rx) = tokio::sync::mpsc::channel(1);
+    for addr in listen {
+        let mut l = tokio_listener::Listener::bind(
+            &addr,
+            &tokio_listener::SystemOptions::default(),
+            &listener_options,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("{addr}: {e}"))?;
+        let extra_conn_tx = extra_conn_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok(x) = l.accept().await else {
+                    log::event(
+                        log::Level::Error,
+                        "accept_error",
+                        &[("listen_addr", json::Field::Str(&addr.to_string()))],
+                        Some(format_args!("Error accepting socket on {addr}")),
+                    );
+                    break;
+                };
+                if extra_conn_tx.send(x).await.is_err() {
+                    break;
+                }
+            }
         });
-    });
+    }
+    drop(extra_conn_tx);
+
+    let mut sigterm = signals::Signal::new(libc::SIGTERM)?;
+    let mut sigint = signals::Signal::new(libc::SIGINT)?;
 
-    let mut listener = listener.bind().await?;
+    let listen_address = match listen_address {
+        _ if dry_run => None,
+        Some(_) if systemd => anyhow::bail!("--systemd and listen address are mutually exclusive"),
+        Some(addr) => Some(addr),
+        None if systemd || systemd::activated() => Some(tokio_listener::ListenerAddress::FromFd(3)),
+        None => anyhow::bail!("listen address is required"),
+    };
+    let mut listener = match listen_address {
+        Some(listen_address) => Some(
+            tokio_listener::Listener::bind(
+                &listen_address,
+                &tokio_listener::SystemOptions::default(),
+                &listener_options,
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("{listen_address}: {e}"))?,
+        ),
+        None => None,
+    };
+    let _pid_file = pid_file.map(pidfile::PidFile::create).transpose()?;
+    systemd::notify_ready();
+    let mut client_id = 0u64;
+    let mut clients = ClientSet::default();
+    let ip_filter = ipfilter::IpFilter { allow, deny };
 
     loop {
         let ret = tokio::select! {
             _ = &mut shutdown_rx => break,
-            x = listener.accept() => x,
+            _ = sigterm.recv() => {
+                cancelled.store(true, Relaxed);
+                channel.lock().unwrap().send(Msg::shutdown_eof());
+                break;
+            }
+            _ = sigint.recv() => {
+                cancelled.store(true, Relaxed);
+                channel.lock().unwrap().send(Msg::shutdown_eof());
+                break;
+            }
+            _ = admin_quit.notified() => {
+                cancelled.store(true, Relaxed);
+                channel.lock().unwrap().send(Msg::shutdown_eof());
+                break;
+            }
+            x = async { listener.as_mut().unwrap().accept().await }, if listener.is_some() => x,
+            Some(x) = extra_conn_rx.recv() => Ok(x),
+            _ = clients.run() => continue,
         };
-        let Ok((conn, _addr)) = ret else {
-            eprintln!("Error accepting socket");
+        let Ok((mut conn, addr)) = ret else {
+            log::event(
+                log::Level::Error,
+                "accept_error",
+                &[],
+                Some(format_args!("Error accepting socket")),
+            );
             break;
         };
-        let mut rx = tx.subscribe();
+        if let tokio_listener::SomeSocketAddr::Tcp(a) = addr {
+            if !ip_filter.permits(a.ip()) {
+                if announce_overruns {
+                    tokio::spawn(async move {
+                        let _ =
+                            tokio::time::timeout(HANDSHAKE_TIMEOUT, conn.write_all(b"FORBIDDEN\n"))
+                                .await;
+                    });
+                }
+                continue;
+            }
+        }
+        if let (true, Some(unix)) = (
+            require_uid.is_some() || require_gid.is_some(),
+            conn.try_borrow_unix(),
+        ) {
+            let cred = unix.peer_cred().ok();
+            let permitted = cred.is_some_and(|c| {
+                require_uid.is_none_or(|u| c.uid() == u) && require_gid.is_none_or(|g| c.gid() == g)
+            });
+            if !permitted {
+                let fields = match cred {
+                    Some(c) => vec![
+                        ("uid", json::Field::Num(c.uid().into())),
+                        ("gid", json::Field::Num(c.gid().into())),
+                    ],
+                    None => vec![],
+                };
+                log::event(
+                    log::Level::Warn,
+                    "client_forbidden",
+                    &fields,
+                    Some(format_args!(
+                        "Rejected UNIX socket client with credentials {:?}",
+                        cred.map(|c| (c.uid(), c.gid()))
+                    )),
+                );
+                tokio::spawn(async move {
+                    let _ = tokio::time::timeout(HANDSHAKE_TIMEOUT, conn.write_all(b"FORBIDDEN\n"))
+                        .await;
+                });
+                continue;
+            }
+        }
+        if max_clients.is_some_and(|m| presence.count() >= m) {
+            let msg = max_clients_msg.clone();
+            tokio::spawn(async move {
+                let _ =
+                    tokio::time::timeout(HANDSHAKE_TIMEOUT, conn.write_all(msg.as_bytes())).await;
+            });
+            continue;
+        }
+        client_id += 1;
+        let client_id = client_id;
+        stats.clients_total.fetch_add(1, Relaxed);
+        let peer = match addr {
+            tokio_listener::SomeSocketAddr::Tcp(a) => a.to_string(),
+            _ => "local".to_owned(),
+        };
+        let hostname = hostname.clone();
+        let connected_at = Instant::now();
+        let (mut rx, mut channel_generation) = {
+            let ch = channel.lock().unwrap();
+            (ch.tx.subscribe(), ch.generation)
+        };
+        let channel_weak = Arc::downgrade(&channel);
+        let mut disconnect_rx = disconnect_rx.clone();
+        let first_live_seqn = stats.next_seqn.load(Relaxed);
+        let hello_template = hello_template.clone();
+        let custom_hello = hello_text.is_some();
         let history_buffer = history_buffer.clone();
+        let tier_buffers = tier_buffers.clone();
+        let topic_histories = topic_histories.clone();
+        let history_snapshot = history_snapshot.clone();
+        let client_guard = ClientGuard::new(presence.clone());
+        let lb_registration = loadbalancer.as_ref().map(|lb| lb.register(client_id));
+        let sessions = sessions.clone();
+        let session = sessions.as_ref().map(|s| s.issue());
+        let stats = stats.clone();
+        let client_addr = peer.clone();
+        let content_format = content_format.clone();
+        let sentinels = sentinels.clone();
+        let connect_fields = [
+            ("client_id", json::Field::Num(client_id)),
+            ("client_addr", json::Field::Str(&peer)),
+        ];
+        log::event(log::Level::Info, "client_connected", &connect_fields, None);
+        if let Some(ref al) = access_log {
+            al.record("connect", &connect_fields);
+        }
+        let access_log = access_log.clone();
+        if let Some(ref a) = announcer {
+            a.announce(client_id, peer.as_str().into(), None);
+        }
+        let announcer = announcer.clone();
+        let topics = topics.clone();
 
-        tokio::task::spawn(async move {
-            let ret: anyhow::Result<()> = async move {
-                let conn = tokio::io::BufWriter::new(conn);
+        let client = async move {
+            let _lb_registration = lb_registration;
+            let counters = Arc::new(ClientCounters {
+                latency: latency_tracking
+                    .then(|| Mutex::new(latency::Samples::new(latency::CLIENT_SAMPLES))),
+                ..Default::default()
+            });
+            let counters2 = counters.clone();
+            let ret: anyhow::Result<&'static str> = async move {
+                let counters = counters2;
+                let (conn_r, conn_w) = tokio::io::split(conn);
+                let conn_w = StallTimeoutWriter {
+                    inner: CountingWriter {
+                        inner: conn_w,
+                        counters: counters.clone(),
+                        stats: stats.clone(),
+                    },
+                    timeout: client_timeout,
+                    deadline: None,
+                };
+                let mut conn_r = tokio::io::BufReader::new(conn_r);
+
+                let conn = tokio::io::BufWriter::with_capacity(write_buffer, conn_w);
                 tokio::pin!(conn);
-                let mut tsprinter = TimestampPrinter::new(begin);
+
+                let mut tsprinter = TimestampPrinter::new(
+                    begin,
+                    timestamp_mode,
+                    begin_wall,
+                    wall_timestamps,
+                );
+                let mut seqnprinter = SeqnPrinter::new(seqn_hash_prefix, seqn_base, seqn_width.unwrap_or(0), seqn_modulus);
+                let mut frame = Vec::new();
+                let mut jsonprinter = json.then(|| {
+                    json::JsonPrinter::new(
+                        begin,
+                        wall_timestamps.then_some(begin_wall),
+                        content_format.prefix.clone(),
+                        content_format.suffix.clone(),
+                        seqn_modulus,
+                        output_pretty_json.then_some(line_terminator),
+                    )
+                });
+
+                if let Some(ref session) = session {
+                    if let Some(ref mut jp) = jsonprinter {
+                        let token = json::Field::Str(session.token());
+                        jp.special(conn.as_mut(), Instant::now(), "session", &[("token", token)])
+                            .await?;
+                    } else {
+                        if timestamps {
+                            tsprinter.print(conn.as_mut(), Instant::now(), ' ').await?;
+                        }
+                        let line = format!("SESSION {}{line_terminator}", session.token());
+                        conn.as_mut().write_all(line.as_bytes()).await?;
+                    }
+                    conn.as_mut().flush().await?;
+                }
+
+                let mut max_write_line_size = usize::MAX;
+                let mut subscription = topic::Subscription::Topic(None);
+                let mut resume_from = None;
+                let mut tier = match tier_threshold {
+                    Some(n) if client_id > n => 0,
+                    _ => 2,
+                };
+                if line_length_limit_per_client
+                    || diag
+                    || topics.is_some()
+                    || sessions.is_some()
+                    || history_tiers.is_some()
+                {
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, conn_r.read_line(&mut line))
+                            .await
+                        {
+                            Ok(Ok(n)) if n > 0 => (),
+                            _ => break,
+                        }
+                        let line = line.trim_end();
+                        if let (true, Some(n)) =
+                            (line_length_limit_per_client, line.strip_prefix("MAX_LINE "))
+                        {
+                            match n.trim().parse::<usize>() {
+                                Ok(n) => max_write_line_size = n.min(max_line_size),
+                                Err(_) => {
+                                    handshake_error(
+                                        conn.as_mut(),
+                                        client_id,
+                                        line,
+                                        "BAD_MAX_LINE",
+                                        line_terminator,
+                                    )
+                                    .await?
+                                }
+                            }
+                        } else if let (Some(ref t), Some(name)) =
+                            (&topics, line.strip_prefix("SUBSCRIBE "))
+                        {
+                            subscription = t.subscription(name.trim());
+                        } else if let (true, Some(n)) =
+                            (history_tiers.is_some(), line.strip_prefix("TIER "))
+                        {
+                            match n.trim().parse::<usize>() {
+                                Ok(n) if n <= 2 => tier = n,
+                                _ => {
+                                    handshake_error(conn.as_mut(), client_id, line, "BAD_TIER", line_terminator)
+                                        .await?
+                                }
+                            }
+                        } else if let (Some(ref s), Some(args)) =
+                            (&sessions, line.strip_prefix("RESUME "))
+                        {
+                            if let Some((token, last_seqn)) = args.trim().split_once(' ') {
+                                match last_seqn.trim().parse::<u64>() {
+                                    Ok(last_seqn) => {
+                                        let latest =
+                                            stats.next_seqn.load(Relaxed).saturating_sub(1);
+                                        let last_seqn =
+                                            unwrap_seqn(last_seqn, latest, seqn_modulus);
+                                        if s.resume(token) {
+                                            resume_from = Some(last_seqn + 1);
+                                        }
+                                    }
+                                    Err(_) => {
+                                        handshake_error(
+                                            conn.as_mut(),
+                                            client_id,
+                                            line,
+                                            "BAD_SEQN",
+                                            line_terminator,
+                                        )
+                                        .await?
+                                    }
+                                }
+                            }
+                        } else if diag && line == "DIAG" {
+                            let history_size = match history_buffer {
+                                Some(ref hb) => hb.lock().unwrap().len(),
+                                None => 0,
+                            };
+                            let client_count = client_guard.0.count();
+                            let mut report = stats.health_json(client_count, history_size);
+                            report.push_str(line_terminator);
+                            conn.as_mut().write_all(report.as_bytes()).await?;
+                            conn.as_mut().flush().await?;
+                        } else {
+                            break;
+                        }
+                    }
+                }
 
                 let mut overrun_counter = 0;
 
-                let mut minseqn = 0;
+                let mut minseqn = resume_from.unwrap_or(0);
+                let topic_buffer = match (&topic_histories, &subscription) {
+                    (Some(th), topic::Subscription::Topic(t)) => th.get(t),
+                    _ => None,
+                };
+                let (history_buffer, history_snapshot) =
+                    match topic_buffer.or_else(|| tier_buffers.get(tier).cloned()) {
+                        Some(hb) => (Some(hb), None),
+                        None => (history_buffer, history_snapshot),
+                    };
+                // Number of the last `--announce-clients` event replayed from history
+                let mut last_client_event = None;
 
-                if let Some((_, ref hb)) = history_buffer {
+                if let Some(ref hb) = history_buffer {
+                    if let Some(ttl) = history_ttl {
+                        hb.lock().unwrap().expire(ttl);
+                    }
+                    // The copy shares line contents with the history, so it is cheap
+                    // and the lock is not held while writing to the client
                     let mut history_copy: VecDeque<Msg>;
-                    {
+                    if let Some(ref snapshot) = history_snapshot {
+                        history_copy = snapshot.copy_from(hb, minseqn);
+                        if let (None, Some(n)) = (resume_from, history_tail) {
+                            minseqn = history::tail_seqn(&history_copy, n).unwrap_or(0);
+                            history_copy.retain(|msg| msg.last_seqn() >= minseqn);
+                        }
+                    } else {
                         let hb = hb.lock().unwrap();
-                        history_copy = hb.clone();
+                        if let (None, Some(n)) = (resume_from, history_tail) {
+                            minseqn = hb.tail_seqn(n).unwrap_or(0);
+                        }
+                        history_copy = hb.snapshot_from(minseqn);
                         // unlock
                     }
+                    let mut replay_limiter = history_replay_rate.map(|r| TokenBucket::new(r, r));
 
                     while let Some(msg) = history_copy.pop_front() {
-                        let MsgInner::Content(buf) = msg.inner else {
-                            continue
+                        let buf = match msg.inner {
+                            MsgInner::Content(ref buf) => buf,
+                            MsgInner::ClientEvent(ref ev) => {
+                                last_client_event = Some(ev.n);
+                                if ev.id == client_id {
+                                    continue;
+                                }
+                                if let Some(ref mut jp) = jsonprinter {
+                                    jp.special(conn.as_mut(), msg.ts, ev.kind(), &ev.fields())
+                                        .await?;
+                                } else if !framed {
+                                    if timestamps {
+                                        tsprinter.print(conn.as_mut(), msg.ts, ' ').await?;
+                                    }
+                                    if print_seqn {
+                                        seqnprinter.print(conn.as_mut(), msg.seqn).await?;
+                                    }
+                                    let line = format!("{ev}{line_terminator}");
+                                    conn.as_mut().write_all(line.as_bytes()).await?;
+                                }
+                                continue;
+                            }
+                            _ => continue,
                         };
+                        if !subscription.matches(&msg) {
+                            continue;
+                        }
+                        if let Some(ref mut tb) = replay_limiter {
+                            if let Some(wait) = tb.take_n(Instant::now(), buf.len() as f64) {
+                                conn.as_mut().flush().await?;
+                                tokio::time::sleep(wait).await;
+                            }
+                        }
+                        if let Some(ref mut jp) = jsonprinter {
+                            let data = json_data(buf, max_write_line_size, byte_to_look_at);
+                            jp.content(conn.as_mut(), msg.ts, msg.seqn, data, false)
+                                .await?;
+                            counters.line_sent(&stats);
+                            if msg.repeat_count > 0 {
+                                let count = json::Field::Num(msg.repeat_count.into());
+                                jp.special(conn.as_mut(), msg.ts, "repeated", &[("count", count)])
+                                    .await?;
+                            }
+                            minseqn = msg.last_seqn() + 1;
+                            continue;
+                        }
+                        if framed {
+                            frame.clear();
+                            if timestamps {
+                                tsprinter.print(Pin::new(&mut frame), msg.ts, '\t').await?;
+                            }
+                            if print_seqn {
+                                seqnprinter.print(Pin::new(&mut frame), msg.seqn).await?;
+                            }
+                            content_format
+                                .write(Pin::new(&mut frame), buf, max_write_line_size)
+                                .await?;
+                            framing::write_frame(conn.as_mut(), netstring, &frame).await?;
+                            counters.line_sent(&stats);
+                            if msg.repeat_count > 0 {
+                                let count = msg.repeat_count.into();
+                                framing::write_special(conn.as_mut(), netstring, framing::TAG_REPEATED, count)
+                                    .await?;
+                            }
+                            minseqn = msg.last_seqn() + 1;
+                            continue;
+                        }
                         if timestamps {
                             tsprinter.print(conn.as_mut(), msg.ts, '\t').await?;
                         }
                         if print_seqn {
-                            let mut buf = String::with_capacity(8);
-                            let _ = write!(buf, "{}\t", msg.seqn,);
+                            seqnprinter.print(conn.as_mut(), msg.seqn).await?;
+                        }
+                        content_format.write(conn.as_mut(), buf, max_write_line_size).await?;
+                        counters.line_sent(&stats);
+                        if msg.repeat_count > 0 {
+                            if timestamps {
+                                tsprinter.print(conn.as_mut(), msg.ts, ' ').await?;
+                            }
+                            let mut buf = String::with_capacity(32);
+                            let _ =
+                                write!(buf, "REPEATED {} times{line_terminator}", msg.repeat_count);
                             conn.as_mut().write_all(buf.as_bytes()).await?;
                         }
-                        conn.as_mut().write_all(&buf).await?;
-                        minseqn=msg.seqn+1;
+                        minseqn = msg.last_seqn() + 1;
                     }
                     conn.as_mut().flush().await?;
                 }
 
-                if hello_message {
+                let next_seqn = minseqn.max(first_live_seqn);
+                let hello = hello_template
+                    .replace("{seqn}", &wrap_seqn(next_seqn, seqn_modulus).to_string())
+                    .replace("{from}", &peer)
+                    .replace("{id}", &client_id.to_string())
+                    .replace("{server}", &hostname);
+                if let (true, Some(jp)) = (hello_message, jsonprinter.as_mut()) {
+                    let mut fields = vec![
+                        ("from", json::Field::Str(&peer)),
+                        ("id", json::Field::Num(client_id)),
+                        ("server", json::Field::Str(&hostname)),
+                        ("seqn", json::Field::Num(wrap_seqn(next_seqn, seqn_modulus))),
+                    ];
+                    if custom_hello {
+                        fields.push(("text", json::Field::Str(&hello)));
+                    }
+                    jp.special(conn.as_mut(), Instant::now(), "hello", &fields)
+                        .await?;
+                    conn.as_mut().flush().await?;
+                } else if hello_message && framed {
+                    framing::write_special(conn.as_mut(), netstring, framing::TAG_HELLO, client_id).await?;
+                    conn.as_mut().flush().await?;
+                } else if hello_message {
                     if timestamps {
                         tsprinter.print(conn.as_mut(), Instant::now(), ' ').await?;
                     }
-                    let mut buf = String::with_capacity(16);
-                    let _ = write!(buf, "HELLO{separator_char}");
+                    let mut buf = hello;
+                    buf.push_str(line_terminator);
                     conn.as_mut().write_all(buf.as_bytes()).await?;
                     conn.as_mut().flush().await?;
                 }
 
+                let flush_period = match flush_mode {
+                    FlushMode::Timer(d) => Some(d),
+                    _ => client_write_batch_timeout,
+                };
+                let mut flush_timer = flush_period.map(tokio::time::interval);
+
+                let mut command = Vec::new();
+                let mut client_eof = false;
+                let mut heartbeat_timer =
+                    heartbeat.map(|d| tokio::time::interval_at(tokio::time::Instant::now() + d, d));
+                let mut last_seqn = minseqn.checked_sub(1);
+                let mut rate_limiter = rate_limit.map(|r| TokenBucket::new(r, rate_limit_burst));
+
                 loop {
-                    match rx.recv().await {
+                    let ret = tokio::select! {
+                        x = rx.recv() => x,
+                        Ok(()) = disconnect_rx.changed() => return Ok("admin"),
+                        _ = tick(&mut flush_timer) => {
+                            conn.as_mut().flush().await?;
+                            continue;
+                        }
+                        _ = tick(&mut heartbeat_timer) => {
+                            let now = Instant::now();
+                            let wrapped_seqn = last_seqn.map(|s| wrap_seqn(s, seqn_modulus));
+                            if let Some(ref mut jp) = jsonprinter {
+                                let fields: &[_] = match wrapped_seqn {
+                                    Some(s) => &[("seqn", json::Field::Num(s))],
+                                    None => &[],
+                                };
+                                jp.special(conn.as_mut(), now, "heartbeat", fields).await?;
+                            } else if framed {
+                                let value = wrapped_seqn.unwrap_or(0);
+                                framing::write_special(conn.as_mut(), netstring, framing::TAG_HEARTBEAT, value)
+                                    .await?;
+                            } else {
+                                if timestamps {
+                                    tsprinter.print(conn.as_mut(), now, ' ').await?;
+                                }
+                                if let (true, Some(s)) = (print_seqn, last_seqn) {
+                                    seqnprinter.print(conn.as_mut(), s).await?;
+                                }
+                                let line = format!("HEARTBEAT{line_terminator}");
+                                conn.as_mut().write_all(line.as_bytes()).await?;
+                            }
+                            conn.as_mut().flush().await?;
+                            continue;
+                        }
+                        r = conn_r.read_until(b'\n', &mut command), if !client_eof => {
+                            if !matches!(r, Ok(n) if n > 0) || !command.ends_with(b"\n") {
+                                client_eof = true;
+                                continue;
+                            }
+                            let line = String::from_utf8_lossy(&command).trim().to_owned();
+                            command.clear();
+                            let Some(from) = line.strip_prefix("REPLAY ") else {
+                                continue;
+                            };
+                            let Some(ref hb) = history_buffer else {
+                                let reply = format!("ERROR NO_HISTORY{line_terminator}");
+                                conn.as_mut().write_all(reply.as_bytes()).await?;
+                                conn.as_mut().flush().await?;
+                                continue;
+                            };
+                            let Ok(from) = from.trim().parse::<u64>() else {
+                                let reply = format!("ERROR BAD_SEQN{line_terminator}");
+                                conn.as_mut().write_all(reply.as_bytes()).await?;
+                                conn.as_mut().flush().await?;
+                                continue;
+                            };
+                            let from = unwrap_seqn(from, stats.next_seqn.load(Relaxed), seqn_modulus);
+                            let entries = hb.lock().unwrap().snapshot_from(from);
+                            let lines: Vec<(Instant, u64, &Bytes)> = entries
+                                .iter()
+                                .filter(|msg| subscription.matches(msg))
+                                .filter_map(|msg| match msg.inner {
+                                    MsgInner::Content(ref b) => Some((msg, b)),
+                                    _ => None,
+                                })
+                                .flat_map(|(msg, b)| {
+                                    (msg.seqn..=msg.last_seqn()).map(move |s| (msg.ts, s, b))
+                                })
+                                .filter(|&(_, s, _)| s >= from)
+                                .collect();
+                            if jsonprinter.is_none() {
+                                if framed {
+                                    framing::write_special(
+                                        conn.as_mut(),
+                                        netstring,
+                                        framing::TAG_REPLAY,
+                                        lines.len() as u64,
+                                    )
+                                    .await?;
+                                } else {
+                                    let header = format!("REPLAY {}{line_terminator}", lines.len());
+                                    conn.as_mut().write_all(header.as_bytes()).await?;
+                                }
+                            }
+                            for (ts, seqn, b) in lines {
+                                if let Some(ref mut jp) = jsonprinter {
+                                    let data = json_data(b, max_write_line_size, byte_to_look_at);
+                                    jp.content(conn.as_mut(), ts, seqn, data, true).await?;
+                                    continue;
+                                }
+                                frame.clear();
+                                if timestamps {
+                                    tsprinter.print(Pin::new(&mut frame), ts, '\t').await?;
+                                }
+                                seqnprinter.print(Pin::new(&mut frame), seqn).await?;
+                                content_format
+                                    .write(Pin::new(&mut frame), b, max_write_line_size)
+                                    .await?;
+                                if framed {
+                                    framing::write_frame(conn.as_mut(), netstring, &frame).await?;
+                                } else {
+                                    conn.as_mut().write_all(&frame).await?;
+                                }
+                            }
+                            conn.as_mut().flush().await?;
+                            continue;
+                        }
+                    };
+                    match ret {
                         Ok(msg) => {
-                            if msg.seqn < minseqn {
+                            if msg.seqn < minseqn
+                                || msg.target.is_some_and(|t| t != client_id)
+                                || !subscription.matches(&msg)
+                            {
                                 continue;
                             }
+                            if let (MsgInner::Content(_), Some(tb)) =
+                                (&msg.inner, &mut rate_limiter)
+                            {
+                                if backpressure {
+                                    while let Err(wait) = tb.try_take(Instant::now()) {
+                                        conn.as_mut().flush().await?;
+                                        tokio::time::sleep(wait).await;
+                                    }
+                                } else if tb.try_take(Instant::now()).is_err() {
+                                    stats.overrun_count.fetch_add(1, Relaxed);
+                                    counters.overruns.fetch_add(1, Relaxed);
+                                    overrun_counter += 1;
+                                    continue;
+                                }
+                            }
+                            let content_ts = match msg.inner {
+                                MsgInner::Content(_) => Some(msg.ts),
+                                _ => None,
+                            };
+                            if let MsgInner::Content(_) = msg.inner {
+                                last_seqn = Some(msg.seqn);
+                                if let Some(ref mut t) = heartbeat_timer {
+                                    t.reset();
+                                }
+                            }
                             match msg.inner {
+                                MsgInner::Content(b) if jsonprinter.is_some() => {
+                                    let jp = jsonprinter.as_mut().unwrap();
+                                    if announce_overruns && overrun_counter > 0 {
+                                        let count = json::Field::Num(overrun_counter);
+                                        jp.special(
+                                            conn.as_mut(),
+                                            Instant::now(),
+                                            "overrun",
+                                            &[("count", count)],
+                                        )
+                                        .await?;
+                                        overrun_counter = 0;
+                                    }
+                                    let data = json_data(&b, max_write_line_size, byte_to_look_at);
+                                    jp.content(conn.as_mut(), msg.ts, msg.seqn, data, false)
+                                        .await?;
+                                    counters.line_sent(&stats);
+                                }
+                                MsgInner::Content(b) if framed => {
+                                    if announce_overruns && overrun_counter > 0 {
+                                        framing::write_special(
+                                            conn.as_mut(),
+                                            netstring,
+                                            framing::TAG_OVERRUN,
+                                            overrun_counter,
+                                        )
+                                        .await?;
+                                        overrun_counter = 0;
+                                    }
+                                    frame.clear();
+                                    if timestamps {
+                                        tsprinter.print(Pin::new(&mut frame), msg.ts, '\t').await?;
+                                    }
+                                    if print_seqn {
+                                        seqnprinter.print(Pin::new(&mut frame), msg.seqn).await?;
+                                    }
+                                    content_format
+                                        .write(Pin::new(&mut frame), &b, max_write_line_size)
+                                        .await?;
+                                    framing::write_frame(conn.as_mut(), netstring, &frame).await?;
+                                    counters.line_sent(&stats);
+                                }
                                 MsgInner::Content(b) => {
                                     if announce_overruns && overrun_counter > 0 {
+                                        let now = Instant::now();
                                         if timestamps {
-                                            tsprinter
-                                                .print(conn.as_mut(), Instant::now(), ' ')
-                                                .await?;
+                                            tsprinter.print(conn.as_mut(), now, ' ').await?;
                                         }
-                                        let mut buf = String::with_capacity(16);
-                                        let _ = write!(
-                                            buf,
-                                            "OVERRUN {overrun_counter}{separator_char}"
+                                        let mut buf = sentinels.expand(
+                                            &sentinels.overrun,
+                                            overrun_counter,
+                                            msg.seqn,
+                                            now,
                                         );
+                                        buf.push_str(line_terminator);
                                         conn.as_mut().write_all(buf.as_bytes()).await?;
                                         overrun_counter = 0;
                                     }
@@ -368,64 +4043,573 @@ async fn main() -> anyhow::Result<()> {
                                         tsprinter.print(conn.as_mut(), msg.ts, '\t').await?;
                                     }
                                     if print_seqn {
-                                        let mut buf = String::with_capacity(8);
-                                        let _ = write!(buf, "{}\t", msg.seqn,);
-                                        conn.as_mut().write_all(buf.as_bytes()).await?;
+                                        seqnprinter.print(conn.as_mut(), msg.seqn).await?;
                                     }
-                                    conn.as_mut().write_all(&b).await?;
+                                    content_format
+                                        .write(conn.as_mut(), &b, max_write_line_size)
+                                        .await?;
+                                    counters.line_sent(&stats);
                                 }
                                 MsgInner::Eof => break,
-                                MsgInner::Backpressure => {
+                                MsgInner::Backpressure(duration) if jsonprinter.is_some() => {
+                                    if announce_overruns {
+                                        let fields: &[_] = match duration {
+                                            Some(d) => &[(
+                                                "duration_ms",
+                                                json::Field::Num(d.as_millis() as u64),
+                                            )],
+                                            None => &[],
+                                        };
+                                        jsonprinter
+                                            .as_mut()
+                                            .unwrap()
+                                            .special(conn.as_mut(), msg.ts, "backpressure", fields)
+                                            .await?;
+                                    }
+                                }
+                                MsgInner::Backpressure(duration) if framed => {
+                                    if announce_overruns {
+                                        let ms = duration.map_or(0, |d| d.as_millis() as u64);
+                                        framing::write_special(
+                                            conn.as_mut(),
+                                            netstring,
+                                            framing::TAG_BACKPRESSURE,
+                                            ms,
+                                        )
+                                        .await?;
+                                    }
+                                }
+                                MsgInner::Backpressure(duration) => {
                                     if announce_overruns {
                                         if timestamps {
                                             tsprinter.print(conn.as_mut(), msg.ts, ' ').await?;
                                         }
 
-                                        let mut buf = String::with_capacity(16);
-                                        let _ = write!(buf, "BACKPRESSURE{separator_char}");
+                                        let mut buf = String::with_capacity(32);
+                                        if let Some(ref t) = sentinels.backpressure {
+                                            let ms = duration.map_or(0, |d| d.as_millis() as u64);
+                                            buf = sentinels.expand(t, ms, msg.seqn, msg.ts);
+                                        } else {
+                                            let _ = write!(buf, "BACKPRESSURE");
+                                            if let Some(d) = duration {
+                                                let _ =
+                                                    write!(buf, " duration_ms={}", d.as_millis());
+                                            }
+                                        }
+                                        let _ = write!(buf, "{line_terminator}");
                                         conn.as_mut().write_all(buf.as_bytes()).await?;
                                     }
                                 }
+                                MsgInner::Stats(s) if jsonprinter.is_some() => {
+                                    let mut fields = vec![
+                                        ("lines", json::Field::Num(s.lines)),
+                                        ("bytes", json::Field::Num(s.bytes)),
+                                        ("clients", json::Field::Num(s.clients)),
+                                        ("overruns", json::Field::Num(s.overruns)),
+                                    ];
+                                    if let Some((bps, lps)) = s.rate {
+                                        fields.push(("rate_bps", json::Field::Float(bps)));
+                                        fields.push(("rate_lps", json::Field::Float(lps)));
+                                    }
+                                    jsonprinter
+                                        .as_mut()
+                                        .unwrap()
+                                        .special(conn.as_mut(), msg.ts, "stats", &fields)
+                                        .await?;
+                                }
+                                MsgInner::Stats(_) if framed => (),
+                                MsgInner::ClientEvent(ev)
+                                    if ev.id == client_id
+                                        || last_client_event.is_some_and(|n| ev.n <= n) => {}
+                                MsgInner::ClientEvent(ev) if jsonprinter.is_some() => {
+                                    jsonprinter
+                                        .as_mut()
+                                        .unwrap()
+                                        .special(conn.as_mut(), msg.ts, ev.kind(), &ev.fields())
+                                        .await?;
+                                }
+                                MsgInner::ClientEvent(_) if framed => (),
+                                MsgInner::ClientEvent(ev) => {
+                                    if timestamps {
+                                        tsprinter.print(conn.as_mut(), msg.ts, ' ').await?;
+                                    }
+                                    if print_seqn {
+                                        seqnprinter.print(conn.as_mut(), msg.seqn).await?;
+                                    }
+                                    let line = format!("{ev}{line_terminator}");
+                                    conn.as_mut().write_all(line.as_bytes()).await?;
+                                }
+                                MsgInner::Stats(s) => {
+                                    if timestamps {
+                                        tsprinter.print(conn.as_mut(), msg.ts, ' ').await?;
+                                    }
+                                    let line = format!("{s}{line_terminator}");
+                                    conn.as_mut().write_all(line.as_bytes()).await?;
+                                }
+                            }
+                            if let Some(ts) = content_ts {
+                                counters.line_written(&stats, ts);
                             }
-                            if rx.len() == 0 {
+                            let flush = match flush_mode {
+                                FlushMode::Always => true,
+                                FlushMode::Idle => rx.is_empty(),
+                                FlushMode::Bytes(n) => conn.buffer().len() >= n,
+                                FlushMode::Timer(_) => false,
+                            };
+                            if flush {
                                 conn.as_mut().flush().await?;
+                                if let Some(ref mut t) = flush_timer {
+                                    t.reset();
+                                }
                             }
                         }
                         Err(e) => match e {
-                            RecvError::Closed => break,
+                            RecvError::Closed => {
+                                // The channel may have been replaced by `SET QLEN` admin command
+                                let Some(ch) = channel_weak.upgrade() else {
+                                    break;
+                                };
+                                let ch = ch.lock().unwrap();
+                                if ch.generation == channel_generation {
+                                    break;
+                                }
+                                rx = ch.tx.subscribe();
+                                channel_generation = ch.generation;
+                            }
                             RecvError::Lagged(n) => {
+                                log::event(
+                                    log::Level::Warn,
+                                    "overrun",
+                                    &[
+                                        ("client_id", json::Field::Num(client_id)),
+                                        ("n", json::Field::Num(n)),
+                                    ],
+                                    None,
+                                );
+                                stats.overrun_count.fetch_add(n, Relaxed);
+                                stats.overrun_events.fetch_add(1, Relaxed);
+                                counters.overruns.fetch_add(n, Relaxed);
                                 overrun_counter += n;
                                 if disconnect_on_overruns {
-                                    return Ok(());
+                                    return Ok("overrun");
                                 }
                             }
                         },
                     }
                 }
-                if announce_overruns {
+                let exit_code = sentinels.exit_code.get().copied();
+                if let (true, Some(jp)) = (announce_overruns, jsonprinter.as_mut()) {
+                    let fields: &[_] = match exit_code {
+                        Some(c) => &[("exit_code", json::Field::Num(c))],
+                        None => &[],
+                    };
+                    jp.special(conn.as_mut(), Instant::now(), "eof", fields)
+                        .await?;
+                } else if announce_overruns && framed {
+                    let value = exit_code.unwrap_or(0);
+                    framing::write_special(conn.as_mut(), netstring, framing::TAG_EOF, value)
+                        .await?;
+                } else if announce_overruns {
+                    let now = Instant::now();
                     if timestamps {
-                        tsprinter.print(conn.as_mut(), Instant::now(), ' ').await?;
+                        tsprinter.print(conn.as_mut(), now, ' ').await?;
                     }
-                    let mut buf = String::with_capacity(16);
-                    let _ = write!(buf, "EOF{separator_char}");
+                    let seqn = stats.next_seqn.load(Relaxed);
+                    let mut buf = match sentinels.eof {
+                        Some(ref t) => sentinels.expand(t, exit_code.unwrap_or(0), seqn, now),
+                        None => match exit_code {
+                            Some(c) => format!("EOF exit_code={c}"),
+                            None => "EOF".to_owned(),
+                        },
+                    };
+                    buf.push_str(line_terminator);
                     conn.as_mut().write_all(buf.as_bytes()).await?;
-                    conn.as_mut().flush().await?;
                 }
+                conn.as_mut().flush().await?;
 
-                Ok(())
+                Ok("eof")
             }
             .await;
-            let _ = ret;
-        });
+            if let Err(ref e) = ret {
+                if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == ErrorKind::TimedOut)
+                {
+                    log::event(
+                        log::Level::Warn,
+                        "client_timeout",
+                        &[
+                            ("client_id", json::Field::Num(client_id)),
+                            ("client_addr", json::Field::Str(&client_addr)),
+                        ],
+                        Some(format_args!(
+                            "Disconnecting client {client_id} ({addr}): {e}"
+                        )),
+                    );
+                }
+            }
+            if let Some(ref a) = announcer {
+                let reason = match ret {
+                    Ok(x) => x,
+                    Err(ref e)
+                        if e.downcast_ref::<std::io::Error>()
+                            .is_some_and(|e| e.kind() == ErrorKind::TimedOut) =>
+                    {
+                        "timeout"
+                    }
+                    Err(_) => "error",
+                };
+                a.announce(client_id, client_addr.as_str().into(), Some(reason));
+            }
+            let reason = match ret {
+                Ok(x) => x.to_owned(),
+                Err(e) => format!("error: {e}"),
+            };
+            let disconnected_at = Instant::now();
+            let fields = [
+                ("client_id", json::Field::Num(client_id)),
+                ("client_addr", json::Field::Str(&client_addr)),
+                ("reason", json::Field::Str(&reason)),
+                (
+                    "duration",
+                    json::Field::Float((disconnected_at - connected_at).as_secs_f64()),
+                ),
+                (
+                    "bytes_sent",
+                    json::Field::Num(counters.bytes_sent.load(Relaxed)),
+                ),
+                (
+                    "lines_sent",
+                    json::Field::Num(counters.lines_sent.load(Relaxed)),
+                ),
+                (
+                    "overruns",
+                    json::Field::Num(counters.overruns.load(Relaxed)),
+                ),
+            ];
+            if let Some(ref al) = access_log {
+                al.record("disconnect", &fields);
+            }
+            if client_stats_on_disconnect || byte_stats {
+                log::event(
+                    log::Level::Info,
+                    "client_disconnected",
+                    &fields,
+                    Some(format_args!(
+                        "client_id={client_id} peer={addr} connected_at={:.6} disconnected_at={:.6} \
+                        duration={:.6} bytes_sent={} lines_sent={} overruns={} reason={reason:?}",
+                        (connected_at - begin).as_secs_f64(),
+                        (disconnected_at - begin).as_secs_f64(),
+                        (disconnected_at - connected_at).as_secs_f64(),
+                        counters.bytes_sent.load(Relaxed),
+                        counters.lines_sent.load(Relaxed),
+                        counters.overruns.load(Relaxed),
+                    )),
+                );
+            } else {
+                log::event(log::Level::Info, "client_disconnected", &fields, None);
+            }
+            let latency = counters
+                .latency
+                .as_ref()
+                .map(|l| l.lock().unwrap().percentiles());
+            if let Some(Some([p50, p95, p99])) = latency {
+                log::event(
+                    log::Level::Info,
+                    "client_latency",
+                    &[
+                        ("client_id", json::Field::Num(client_id)),
+                        ("p50_ms", json::Field::Float(p50)),
+                        ("p95_ms", json::Field::Float(p95)),
+                        ("p99_ms", json::Field::Float(p99)),
+                    ],
+                    Some(format_args!(
+                        "client_id={client_id} latency_ms p50={p50:.3} p95={p95:.3} p99={p99:.3}"
+                    )),
+                );
+            }
+        };
+        if no_client_task_spawn {
+            clients.0.push(Box::pin(client));
+        } else {
+            tokio::task::spawn(client);
+        }
     }
-    let mut patience_points = 10;
-    while tx.receiver_count() > 0 {
-        patience_points -= 1;
-        if patience_points == 0 {
-            break;
+    let deadline = tokio::time::Instant::now() + shutdown_timeout;
+    if let Some(ref bc) = spill_cleanup {
+        // Spilled messages, possibly including EOF, must get into the queue before it is closed
+        let _ = tokio::time::timeout_at(deadline, async {
+            while bc.lock().unwrap().spill_pending() {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_millis(1)) => (),
+                    _ = clients.run() => (),
+                }
+            }
+        })
+        .await;
+    }
+    if forward_stdin_eof_as_disconnect {
+        channel.lock().unwrap().close();
+    }
+
+    let _ = tokio::time::timeout_at(deadline, async {
+        loop {
+            tokio::select! {
+                _ = presence.all_disconnected() => break,
+                _ = clients.run() => (),
+            }
+        }
+        for f in output_files {
+            let _ = f.await;
+        }
+    })
+    .await;
+    drop(channel);
+    if let Some(bc) = spill_cleanup {
+        if let Some(ref mut spill) = bc.lock().unwrap().spill {
+            spill.discard();
+        }
+    }
+    if let Some(path) = admin_socket {
+        let _ = std::fs::remove_file(path);
+    }
+
+    if let Some(bc) = seqn_file_cleanup {
+        if let Some(ref f) = bc.lock().unwrap().seqn_file {
+            f.save(stats.next_seqn.load(Relaxed));
         }
-        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    if let (Some(path), Some(hb)) = (history_file, history_buffer) {
+        hb.lock()
+            .unwrap()
+            .save_file(&path, byte_to_look_at)
+            .map_err(|e| anyhow::anyhow!("Saving history to {}: {e}", path.display()))?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seqn_wrapping() {
+        assert_eq!(wrap_seqn(12345, None), 12345);
+        assert_eq!(wrap_seqn(9, Some(10)), 9);
+        assert_eq!(wrap_seqn(10, Some(10)), 0);
+        assert_eq!(wrap_seqn(u64::MAX, Some(10)), 5);
+        assert_eq!(wrap_seqn(7, Some(1)), 0);
+
+        assert_eq!(unwrap_seqn(12345, 5, None), 12345);
+        // Latest line with that wrapped number
+        assert_eq!(unwrap_seqn(5, 25, Some(10)), 25);
+        assert_eq!(unwrap_seqn(3, 25, Some(10)), 23);
+        assert_eq!(unwrap_seqn(7, 25, Some(10)), 17);
+        assert_eq!(unwrap_seqn(17, 25, Some(10)), 17);
+        // Around the wrap boundary
+        assert_eq!(unwrap_seqn(9, 9, Some(10)), 9);
+        assert_eq!(unwrap_seqn(9, 10, Some(10)), 9);
+        assert_eq!(unwrap_seqn(0, 10, Some(10)), 10);
+        assert_eq!(unwrap_seqn(0, 19, Some(10)), 10);
+        assert_eq!(unwrap_seqn(9, 20, Some(10)), 19);
+        // No such line yet
+        assert_eq!(unwrap_seqn(7, 3, Some(10)), 0);
+
+        for latest in [0, 1, 9, 10, 11, 99, 100, 101, u64::MAX - 1] {
+            for seqn in latest.saturating_sub(9)..=latest {
+                let wrapped = wrap_seqn(seqn, Some(10));
+                assert_eq!(
+                    unwrap_seqn(wrapped, latest, Some(10)),
+                    seqn,
+                    "{latest} {seqn}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn history_tiers() {
+        assert_eq!(parse_tiers("100:1000:10000"), Ok([100, 1000, 10000]));
+        assert_eq!(parse_tiers(" 1 : 1 :2"), Ok([1, 1, 2]));
+        assert_eq!(parse_tiers("0:0:0"), Ok([0, 0, 0]));
+        assert!(parse_tiers("10:5:20").is_err());
+        assert!(parse_tiers("1:2").is_err());
+        assert!(parse_tiers("1:2:3:4").is_err());
+        assert!(parse_tiers("1:x:3").is_err());
+        assert!(parse_tiers("").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("200ms"), Ok(Duration::from_millis(200)));
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration(" 2 s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("nan").is_err());
+        assert!(parse_duration("inf").is_err());
+    }
+
+    #[test]
+    fn flush_modes() {
+        assert!(matches!(parse_flush_mode("always"), Ok(FlushMode::Always)));
+        assert!(matches!(parse_flush_mode("idle"), Ok(FlushMode::Idle)));
+        assert!(matches!(
+            parse_flush_mode("bytes:4096"),
+            Ok(FlushMode::Bytes(4096))
+        ));
+        assert!(matches!(
+            parse_flush_mode("bytes 1"),
+            Ok(FlushMode::Bytes(1))
+        ));
+        assert!(matches!(
+            parse_flush_mode("timer: 50"),
+            Ok(FlushMode::Timer(d)) if d == Duration::from_millis(50)
+        ));
+        assert!(parse_flush_mode("").is_err());
+        assert!(parse_flush_mode("always:1").is_err());
+        assert!(parse_flush_mode("bytes").is_err());
+        assert!(parse_flush_mode("bytes:0").is_err());
+        assert!(parse_flush_mode("bytes:-5").is_err());
+        assert!(parse_flush_mode("timer:0").is_err());
+        assert!(parse_flush_mode("timer:1.5").is_err());
+        assert!(parse_flush_mode("never").is_err());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex("0d0a"), Ok(vec![b'\r', b'\n']));
+        assert_eq!(parse_hex("00FFaB"), Ok(vec![0, 0xff, 0xab]));
+        assert!(parse_hex("").is_err());
+        assert!(parse_hex("0").is_err());
+        assert!(parse_hex("0g").is_err());
+        assert!(parse_hex("+f").is_err());
+        assert!(parse_hex("0x0a").is_err());
+        assert!(parse_hex("ü0").is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(parse_escapes("plain"), Ok("plain".to_owned()));
+        assert_eq!(
+            parse_escapes(r"a\nb\tc\rd\0e\\f"),
+            Ok("a\nb\tc\rd\0e\\f".to_owned())
+        );
+        assert_eq!(parse_escapes(r"\\n"), Ok(r"\n".to_owned()));
+        assert_eq!(parse_escapes("ü\\n"), Ok("ü\n".to_owned()));
+        assert!(parse_escapes(r"\x").is_err());
+        assert!(parse_escapes("end\\").is_err());
+    }
+
+    #[test]
+    fn utf8_boundaries() {
+        assert_eq!(utf8_boundary(b""), 0);
+        assert_eq!(utf8_boundary(b"abc"), 3);
+        assert_eq!(utf8_boundary("a€".as_bytes()), 4);
+        assert_eq!(utf8_boundary("a😀".as_bytes()), 5);
+        // Incomplete sequences are cut off
+        assert_eq!(utf8_boundary(b"a\xe2\x82"), 1);
+        assert_eq!(utf8_boundary(b"ab\xc3"), 2);
+        assert_eq!(utf8_boundary(b"a\xf0\x9f\x98"), 1);
+        // ...unless nothing precedes them
+        assert_eq!(utf8_boundary(b"\xe2\x82"), 2);
+        // Invalid bytes are left alone
+        assert_eq!(utf8_boundary(b"a\x80\x80\x80\x80"), 5);
+    }
+
+    #[test]
+    fn rfc3339() {
+        let fmt = |t: Duration| {
+            let mut buf = String::new();
+            write_rfc3339(&mut buf, t);
+            buf
+        };
+        assert_eq!(fmt(Duration::ZERO), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(
+            fmt(Duration::from_secs(59 * 86400)),
+            "1970-03-01T00:00:00.000000Z"
+        );
+        assert_eq!(
+            fmt(Duration::from_secs(951782400)),
+            "2000-02-29T00:00:00.000000Z"
+        );
+        assert_eq!(
+            fmt(Duration::from_secs(1709251199) + Duration::from_nanos(999_999_999)),
+            "2024-02-29T23:59:59.999999Z"
+        );
+        assert_eq!(
+            fmt(Duration::from_secs(4102444800)),
+            "2100-01-01T00:00:00.000000Z"
+        );
+        assert_eq!(
+            fmt(Duration::from_secs(253402300799)),
+            "9999-12-31T23:59:59.000000Z"
+        );
+    }
+
+    #[test]
+    fn json_line_data() {
+        assert_eq!(json_data(b"abc\n", 100, b'\n'), b"abc");
+        assert_eq!(json_data(b"abc", 100, b'\n'), b"abc");
+        assert_eq!(json_data(b"abc\n", 2, b'\n'), b"ab");
+        assert_eq!(json_data(b"a\nb\0", 100, 0), b"a\nb");
+        assert_eq!(json_data(b"\n", 100, b'\n'), b"");
+    }
+
+    #[test]
+    fn sleep_strategy() {
+        use broadcaster::SleepStrategy as S;
+        assert!(matches!(
+            parse_sleep_strategy("exponential"),
+            Ok(S::Exponential)
+        ));
+        assert!(matches!(
+            parse_sleep_strategy("linear:10:20"),
+            Ok(S::Linear {
+                start: 10,
+                step: 20
+            })
+        ));
+        assert!(matches!(
+            parse_sleep_strategy("linear 0 5"),
+            Ok(S::Linear { start: 0, step: 5 })
+        ));
+        assert!(matches!(
+            parse_sleep_strategy("constant:500"),
+            Ok(S::Constant(500))
+        ));
+        assert!(parse_sleep_strategy("").is_err());
+        assert!(parse_sleep_strategy("exponential:1").is_err());
+        assert!(parse_sleep_strategy("linear:10").is_err());
+        assert!(parse_sleep_strategy("linear:0:0").is_err());
+        assert!(parse_sleep_strategy("constant:0").is_err());
+        assert!(parse_sleep_strategy("constant:-1").is_err());
+        assert!(parse_sleep_strategy("random").is_err());
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut tb = TokenBucket::new(10.0, 3.0);
+        let t = tb.last;
+        // Full burst is available right away
+        for _ in 0..3 {
+            assert_eq!(tb.try_take(t), Ok(()));
+        }
+        let wait = tb.try_take(t).unwrap_err();
+        assert!((wait.as_secs_f64() - 0.1).abs() < 1e-9);
+        assert!(tb.try_take(t + Duration::from_millis(50)).is_err());
+        assert_eq!(tb.try_take(t + Duration::from_millis(100)), Ok(()));
+        // Tokens do not accumulate beyond the burst size
+        let t = t + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(tb.try_take(t), Ok(()));
+        }
+        assert!(tb.try_take(t).is_err());
+    }
+}
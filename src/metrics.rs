@@ -0,0 +1,173 @@
+//! `--metrics-addr`: minimal HTTP server exposing Prometheus metrics
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    Take,
+};
+
+use crate::{
+    history::History,
+    log::{self, Level},
+    stats::Stats,
+    ClientPresence, RateMeter,
+};
+
+pub struct Sources {
+    pub stats: Arc<Stats>,
+    pub presence: Arc<ClientPresence>,
+    pub history: Option<Arc<Mutex<History>>>,
+    pub rate_meter: Option<Arc<Mutex<RateMeter>>>,
+}
+
+impl Sources {
+    fn render(&self) -> String {
+        let history_size = match self.history {
+            Some(ref hb) => hb.lock().unwrap().len(),
+            None => 0,
+        };
+        let input_rate = self
+            .rate_meter
+            .as_ref()
+            .map(|rm| rm.lock().unwrap().rates(Instant::now()).0);
+        self.stats
+            .prometheus(self.presence.count(), history_size, input_rate)
+    }
+}
+
+/// Longest accepted request line with headers
+const MAX_REQUEST_SIZE: u64 = 8192;
+/// Time a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the request line and skip the headers. `None` if the request exceeds `MAX_REQUEST_SIZE`.
+async fn read_request(
+    conn: &mut Take<impl AsyncBufRead + Unpin>,
+) -> std::io::Result<Option<String>> {
+    let mut request_line = String::new();
+    conn.read_line(&mut request_line).await?;
+    let mut line = request_line.clone();
+    // Headers end with an empty line
+    while !line.trim_end().is_empty() {
+        if !line.ends_with('\n') {
+            // Either the client stopped sending or the size limit was reached
+            return Ok((conn.limit() > 0).then_some(request_line));
+        }
+        line.clear();
+        conn.read_line(&mut line).await?;
+    }
+    Ok(Some(request_line))
+}
+
+async fn handle(
+    conn: impl AsyncRead + AsyncWrite + Unpin,
+    sources: &Sources,
+) -> std::io::Result<()> {
+    let mut conn = BufReader::new(conn).take(MAX_REQUEST_SIZE);
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut conn))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let mut conn = conn.into_inner();
+    let response = match request {
+        None => {
+            "HTTP/1.0 431 Request Header Fields Too Large\r\nContent-Length: 0\r\n\r\n".to_owned()
+        }
+        Some(request_line) => {
+            let mut parts = request_line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("GET"), Some("/metrics")) => {
+                    let body = sources.render();
+                    format!(
+                        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+                        body.len()
+                    )
+                }
+                _ => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned(),
+            }
+        }
+    };
+    conn.write_all(response.as_bytes()).await?;
+    conn.shutdown().await
+}
+
+/// Serve metrics requests one by one until accepting fails
+pub async fn serve(mut listener: tokio_listener::Listener, sources: Sources) {
+    let sources = Arc::new(sources);
+    loop {
+        let Ok((conn, _)) = listener.accept().await else {
+            log::event(
+                Level::Error,
+                "accept_error",
+                &[],
+                Some(format_args!("Error accepting metrics connection")),
+            );
+            break;
+        };
+        let sources = sources.clone();
+        tokio::spawn(async move {
+            let _ = handle(conn, &sources).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Condvar;
+
+    use super::*;
+
+    fn sources() -> Sources {
+        Sources {
+            stats: Arc::new(Stats::new(Instant::now(), false, false)),
+            presence: Arc::new(ClientPresence {
+                count: Mutex::new(0),
+                cv: Condvar::new(),
+                notify: tokio::sync::Notify::new(),
+                emptied_at: Mutex::new(None),
+                hysteresis: Duration::ZERO,
+            }),
+            history: None,
+            rate_meter: None,
+        }
+    }
+
+    async fn request(data: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let sources = sources();
+        let server = tokio::spawn(async move { handle(server, &sources).await });
+        client.write_all(data).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn responses() {
+        let response = request(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\n# HELP stdintap_lines_read_total"));
+
+        let response = request(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.0 404 "), "{response}");
+    }
+
+    #[tokio::test]
+    async fn request_size_is_limited() {
+        let long_line = vec![b'a'; MAX_REQUEST_SIZE as usize + 1];
+        let response = request(&long_line).await;
+        assert!(response.starts_with("HTTP/1.0 431 "), "{response}");
+
+        let mut headers = b"GET /metrics HTTP/1.1\r\n".to_vec();
+        while headers.len() <= MAX_REQUEST_SIZE as usize {
+            headers.extend_from_slice(b"X-Padding: 0123456789\r\n");
+        }
+        headers.extend_from_slice(b"\r\n");
+        let response = request(&headers).await;
+        assert!(response.starts_with("HTTP/1.0 431 "), "{response}");
+    }
+}
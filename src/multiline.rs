@@ -0,0 +1,121 @@
+//! Handling of multi-line records like stack traces
+
+use bytes::{Bytes, BytesMut};
+
+use crate::regex::Regex;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum MultilineMode {
+    /// Join continuation lines with the preceding line into one record
+    Join,
+    /// Prefix continuation lines with `CONT` and a tab
+    Tag,
+}
+
+pub struct Multiline {
+    mode: MultilineMode,
+    /// Matches continuation lines. By default lines starting with whitespace are continuations.
+    pattern: Option<Regex>,
+    separator: u8,
+    /// Record being accumulated in join mode
+    pending: Option<BytesMut>,
+}
+
+impl Multiline {
+    pub fn new(mode: MultilineMode, pattern: Option<Regex>, separator: u8) -> Self {
+        Self {
+            mode,
+            pattern,
+            separator,
+            pending: None,
+        }
+    }
+
+    fn is_continuation(&self, line: &[u8]) -> bool {
+        let body = line.strip_suffix(&[self.separator]).unwrap_or(line);
+        match self.pattern {
+            Some(ref re) => re.captures_at(body, 0).is_some(),
+            None => body.first().is_some_and(|&b| b == b' ' || b == b'\t'),
+        }
+    }
+
+    /// Process next line. Returns a line or record ready for sending, if any.
+    pub fn process(&mut self, line: Bytes) -> Option<Bytes> {
+        let cont = self.is_continuation(&line);
+        match self.mode {
+            MultilineMode::Tag if cont => {
+                let mut x = BytesMut::with_capacity(line.len() + 5);
+                x.extend_from_slice(b"CONT\t");
+                x.extend_from_slice(&line);
+                Some(x.freeze())
+            }
+            MultilineMode::Tag => Some(line),
+            MultilineMode::Join => {
+                if cont {
+                    if let Some(ref mut p) = self.pending {
+                        if p.last() == Some(&self.separator) {
+                            p.truncate(p.len() - 1);
+                        }
+                        p.extend_from_slice(b"\n");
+                        p.extend_from_slice(&line);
+                        return None;
+                    }
+                }
+                let ready = self.finish();
+                self.pending = Some(BytesMut::from(&line[..]));
+                ready
+            }
+        }
+    }
+
+    /// Take accumulated record, e.g. on EOF
+    pub fn finish(&mut self) -> Option<Bytes> {
+        self.pending.take().map(BytesMut::freeze)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(mut m: Multiline, lines: &[&'static str]) -> Vec<Bytes> {
+        let mut out: Vec<Bytes> = lines
+            .iter()
+            .filter_map(|&l| m.process(Bytes::from_static(l.as_bytes())))
+            .collect();
+        out.extend(m.finish());
+        out
+    }
+
+    #[test]
+    fn join() {
+        let m = Multiline::new(MultilineMode::Join, None, b'\n');
+        let out = run(
+            m,
+            &[
+                "Error: x\n",
+                "  at a\n",
+                "\tat b\n",
+                "next\n",
+                "last\n",
+                " tail",
+            ],
+        );
+        assert_eq!(out, ["Error: x\n  at a\n\tat b\n", "next\n", "last\n tail"]);
+    }
+
+    #[test]
+    fn join_with_pattern_and_separator() {
+        let re = Regex::new("^\\.\\.\\.").unwrap();
+        let m = Multiline::new(MultilineMode::Join, Some(re), 0);
+        let out = run(m, &["...orphan\0", "a\0", "...b\0", "c\0"]);
+        assert_eq!(out, ["...orphan\0", "a\n...b\0", "c\0"]);
+    }
+
+    #[test]
+    fn tag() {
+        let m = Multiline::new(MultilineMode::Tag, None, b'\n');
+        let out = run(m, &["head\n", " cont\n", "\n", "x\n"]);
+        assert_eq!(out, ["head\n", "CONT\t cont\n", "\n", "x\n"]);
+    }
+}
@@ -0,0 +1,165 @@
+//! `--output-file`: writing the broadcast stream to files alongside socket clients
+
+use std::{
+    fs::File,
+    future::Future,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    broadcaster::Channel, framing, json, log, ContentFormat, Msg, MsgInner, SeqnPrinter,
+    TimestampPrinter,
+};
+
+/// Formatting of content lines the same way as for socket clients, for writers that are not clients
+pub struct Formatter {
+    tsprinter: Option<TimestampPrinter>,
+    seqnprinter: Option<SeqnPrinter>,
+    jsonprinter: Option<json::JsonPrinter>,
+    binary_framing: bool,
+    netstring: bool,
+    content_format: ContentFormat,
+    frame: Vec<u8>,
+}
+
+impl Formatter {
+    pub fn new(
+        tsprinter: Option<TimestampPrinter>,
+        seqnprinter: Option<SeqnPrinter>,
+        jsonprinter: Option<json::JsonPrinter>,
+        binary_framing: bool,
+        netstring: bool,
+        content_format: ContentFormat,
+    ) -> Self {
+        Self {
+            tsprinter,
+            seqnprinter,
+            jsonprinter,
+            binary_framing,
+            netstring,
+            content_format,
+            frame: Vec::new(),
+        }
+    }
+
+    /// Write the formatted content line `b` of `msg` to `w`
+    pub async fn write(&mut self, w: &mut impl Write, msg: &Msg, b: &Bytes) -> std::io::Result<()> {
+        self.frame.clear();
+        let frame = &mut self.frame;
+        if let Some(ref mut jp) = self.jsonprinter {
+            let data = crate::json_data(b, usize::MAX, self.content_format.separator);
+            jp.content(Pin::new(frame), msg.ts, msg.seqn, data, false)
+                .await?;
+        } else {
+            if let Some(ref mut tp) = self.tsprinter {
+                tp.print(Pin::new(&mut *frame), msg.ts, '\t').await?;
+            }
+            if let Some(ref mut sp) = self.seqnprinter {
+                sp.print(Pin::new(&mut *frame), msg.seqn).await?;
+            }
+            self.content_format
+                .write(Pin::new(frame), b, usize::MAX)
+                .await?;
+        }
+        if self.binary_framing || self.netstring {
+            framing::write_frame_sync(w, self.netstring, &self.frame)
+        } else {
+            w.write_all(&self.frame)
+        }
+    }
+}
+
+/// File receiving all content lines, formatted the same way as for socket clients
+pub struct OutputFile {
+    pub path: PathBuf,
+    pub file: BufWriter<File>,
+    pub format: Formatter,
+}
+
+impl OutputFile {
+    pub fn open(path: PathBuf) -> std::io::Result<BufWriter<File>> {
+        let f = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(BufWriter::new(f))
+    }
+
+    /// Subscribe to the channel right away, so that no lines are missed, and return the future
+    /// writing lines until EOF or write error. The file gets lines regardless of `--loadbalance`
+    /// and `--topic-prefix`. It is not a client, so it is not counted in statistics,
+    /// but it may overrun if the disk is slower than input.
+    pub fn run(mut self, channel: &Arc<Mutex<Channel>>) -> impl Future<Output = ()> {
+        let (mut rx, mut generation) = {
+            let ch = channel.lock().unwrap();
+            (ch.tx.subscribe(), ch.generation)
+        };
+        let channel = Arc::downgrade(channel);
+        async move {
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(n)) => {
+                        log::event(
+                            log::Level::Warn,
+                            "output_file_overrun",
+                            &[
+                                ("path", json::Field::Str(&self.path.to_string_lossy())),
+                                ("n", json::Field::Num(n)),
+                            ],
+                            Some(format_args!(
+                                "Warning: {} missed {n} lines",
+                                self.path.display()
+                            )),
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => {
+                        // The channel may have been replaced by `SET QLEN` admin command
+                        let Some(ch) = channel.upgrade() else {
+                            break;
+                        };
+                        let ch = ch.lock().unwrap();
+                        if ch.generation == generation {
+                            break;
+                        }
+                        rx = ch.tx.subscribe();
+                        generation = ch.generation;
+                        continue;
+                    }
+                };
+                let b = match msg.inner {
+                    MsgInner::Content(ref b) => b,
+                    MsgInner::Eof => break,
+                    _ => continue,
+                };
+                let mut ret = self.format.write(&mut self.file, &msg, b).await;
+                if ret.is_ok() && rx.is_empty() {
+                    ret = self.file.flush();
+                }
+                if let Err(e) = ret {
+                    log::event(
+                        log::Level::Warn,
+                        "output_file_error",
+                        &[
+                            ("path", json::Field::Str(&self.path.to_string_lossy())),
+                            ("error", json::Field::Str(&e.to_string())),
+                        ],
+                        Some(format_args!(
+                            "Warning: writing to {}: {e}. Not writing to it anymore.",
+                            self.path.display()
+                        )),
+                    );
+                    return;
+                }
+            }
+            let _ = self.file.flush();
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! `--pid-file`: recording process ID for init systems and monitoring tools
+
+use std::{io::ErrorKind, path::PathBuf};
+
+/// Written PID file, removed when dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+/// Whether a process with this ID exists
+fn alive(pid: libc::pid_t) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists and can be signalled
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+impl PidFile {
+    /// Write our PID to the file, unless it names another running process
+    pub fn create(path: PathBuf) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(&path) {
+            Ok(s) => {
+                if let Ok(pid) = s.trim().parse::<libc::pid_t>() {
+                    if pid > 0 && pid as u32 != std::process::id() && alive(pid) {
+                        anyhow::bail!("{}: process {pid} is still running", path.display());
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => anyhow::bail!("{}: {e}", path.display()),
+        }
+        std::fs::write(&path, format!("{}\n", std::process::id()))
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,790 @@
+//! Small regular expression engine working on bytes. It is a Pike VM following all
+//! alternatives at once, so matching time is linear in input length.
+//!
+//! Supported syntax: literals, `.`, bracket classes like `[a-z_]` or `[^0-9]`, escapes
+//! `\d \w \s \D \W \S \b \B \t \n \r \xHH`, anchors `^` and `$`, capturing `(...)` and
+//! non-capturing `(?:...)` groups, alternation `|` and quantifiers `* + ? {n} {n,} {n,m}`
+//! (append `?` for a lazy variant). Counted repetitions and nesting depth are limited,
+//! as they expand into a larger program.
+
+/// Maximum `n` and `m` in `{n}`, `{n,}` and `{n,m}`
+const MAX_REPEAT: usize = 1000;
+/// Maximum nesting of groups
+const MAX_DEPTH: usize = 100;
+/// Maximum number of compiled instructions
+const MAX_PROGRAM: usize = 65536;
+
+/// Capture group positions, index 0 is the whole match
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+enum Node {
+    Byte(u8),
+    /// Any byte except `\n`
+    Any,
+    Class(Box<[bool; 256]>),
+    Start,
+    End,
+    WordBoundary(bool),
+    Group(Option<usize>, Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: usize,
+        greedy: bool,
+    },
+}
+
+pub struct Regex {
+    prog: Vec<Inst>,
+    ngroups: usize,
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+    ngroups: usize,
+    depth: usize,
+}
+
+fn class_of(f: impl Fn(u8) -> bool) -> Box<[bool; 256]> {
+    let mut c = Box::new([false; 256]);
+    for (i, x) in c.iter_mut().enumerate() {
+        *x = f(i as u8);
+    }
+    c
+}
+
+fn is_word(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, String> {
+        let b = self.peek().ok_or("unexpected end of regex")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn alternation(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alts = vec![self.sequence()?];
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            alts.push(self.sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut seq = vec![];
+        while let Some(b) = self.peek() {
+            if b == b'|' || b == b')' {
+                break;
+            }
+            let atom = self.atom()?;
+            seq.push(self.quantifier(atom)?);
+        }
+        Ok(seq)
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.s[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some(b'*') => (0, usize::MAX),
+            Some(b'+') => (1, usize::MAX),
+            Some(b'?') => (0, 1),
+            Some(b'{') => {
+                let saved = self.pos;
+                self.pos += 1;
+                let Some(min) = self.number() else {
+                    // Not a quantifier, treat `{` literally
+                    self.pos = saved;
+                    return Ok(atom);
+                };
+                let max = if self.peek() == Some(b',') {
+                    self.pos += 1;
+                    self.number().unwrap_or(usize::MAX)
+                } else {
+                    min
+                };
+                if self.peek() != Some(b'}') || max < min {
+                    return Err("invalid repetition".to_owned());
+                }
+                if min > MAX_REPEAT || (max > MAX_REPEAT && max != usize::MAX) {
+                    return Err(format!("repetition count exceeds {MAX_REPEAT}"));
+                }
+                (min, max)
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if matches!(
+            atom,
+            Node::Start | Node::End | Node::WordBoundary(_) | Node::Repeat { .. }
+        ) {
+            return Err("invalid repetition target".to_owned());
+        }
+        let greedy = if self.peek() == Some(b'?') {
+            self.pos += 1;
+            false
+        } else {
+            true
+        };
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    fn hex_escape(&mut self) -> Result<u8, String> {
+        let h = [self.next()?, self.next()?];
+        std::str::from_utf8(&h)
+            .ok()
+            .and_then(|h| u8::from_str_radix(h, 16).ok())
+            .ok_or_else(|| "invalid \\x escape".to_owned())
+    }
+
+    /// Parse an escape after `\`. Returns either a single byte or a class.
+    fn escape(&mut self) -> Result<Result<u8, Box<[bool; 256]>>, String> {
+        Ok(match self.next()? {
+            b'd' => Err(class_of(|b| b.is_ascii_digit())),
+            b'D' => Err(class_of(|b| !b.is_ascii_digit())),
+            b'w' => Err(class_of(is_word)),
+            b'W' => Err(class_of(|b| !is_word(b))),
+            b's' => Err(class_of(|b| b.is_ascii_whitespace())),
+            b'S' => Err(class_of(|b| !b.is_ascii_whitespace())),
+            b'n' => Ok(b'\n'),
+            b't' => Ok(b'\t'),
+            b'r' => Ok(b'\r'),
+            b'0' => Ok(b'\0'),
+            b'x' => Ok(self.hex_escape()?),
+            b if b.is_ascii_alphanumeric() => {
+                return Err(format!("unsupported escape \\{}", b as char))
+            }
+            b => Ok(b),
+        })
+    }
+
+    fn bracket_class(&mut self) -> Result<Node, String> {
+        let mut c = [false; 256];
+        let negate = if self.peek() == Some(b'^') {
+            self.pos += 1;
+            true
+        } else {
+            false
+        };
+        let mut first = true;
+        loop {
+            let b = self.next()?;
+            if b == b']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match b {
+                b'\\' => match self.escape()? {
+                    Ok(x) => x,
+                    Err(cls) => {
+                        for (x, y) in c.iter_mut().zip(cls.iter()) {
+                            *x |= *y;
+                        }
+                        continue;
+                    }
+                },
+                b if !b.is_ascii() => {
+                    return Err("non-ASCII characters in classes are not supported".to_owned())
+                }
+                b => b,
+            };
+            let hi = if self.peek() == Some(b'-') && self.s.get(self.pos + 1) != Some(&b']') {
+                self.pos += 1;
+                match self.next()? {
+                    b'\\' => match self.escape()? {
+                        Ok(x) => x,
+                        Err(_) => return Err("invalid class range".to_owned()),
+                    },
+                    b => b,
+                }
+            } else {
+                lo
+            };
+            if hi < lo {
+                return Err("invalid class range".to_owned());
+            }
+            for x in &mut c[lo as usize..=hi as usize] {
+                *x = true;
+            }
+        }
+        if negate {
+            for x in &mut c {
+                *x = !*x;
+            }
+        }
+        Ok(Node::Class(Box::new(c)))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        Ok(match self.next()? {
+            b'(' => {
+                if self.depth >= MAX_DEPTH {
+                    return Err(format!("groups nested deeper than {MAX_DEPTH}"));
+                }
+                let idx = if self.s[self.pos..].starts_with(b"?:") {
+                    self.pos += 2;
+                    None
+                } else {
+                    self.ngroups += 1;
+                    Some(self.ngroups)
+                };
+                self.depth += 1;
+                let alts = self.alternation()?;
+                self.depth -= 1;
+                if self.next()? != b')' {
+                    return Err("unclosed group".to_owned());
+                }
+                Node::Group(idx, alts)
+            }
+            b'[' => self.bracket_class()?,
+            b'.' => Node::Any,
+            b'^' => Node::Start,
+            b'$' => Node::End,
+            b'\\' => match self.peek() {
+                Some(b'b') => {
+                    self.pos += 1;
+                    Node::WordBoundary(true)
+                }
+                Some(b'B') => {
+                    self.pos += 1;
+                    Node::WordBoundary(false)
+                }
+                _ => match self.escape()? {
+                    Ok(b) => Node::Byte(b),
+                    Err(cls) => Node::Class(cls),
+                },
+            },
+            b'*' | b'+' | b'?' => return Err("nothing to repeat".to_owned()),
+            b if b.is_ascii() => Node::Byte(b),
+            _ => {
+                // Keep multi-byte UTF-8 characters together for quantifiers
+                let start = self.pos - 1;
+                while self.peek().is_some_and(|b| b & 0xC0 == 0x80) {
+                    self.pos += 1;
+                }
+                let bytes = self.s[start..self.pos].iter().map(|&b| Node::Byte(b));
+                Node::Group(None, vec![bytes.collect()])
+            }
+        })
+    }
+}
+
+enum Inst {
+    Byte(u8),
+    Any,
+    Class(Box<[bool; 256]>),
+    Start,
+    End,
+    WordBoundary(bool),
+    /// Fork execution, the first branch has priority
+    Split(usize, usize),
+    Jmp(usize),
+    Save(usize),
+    Match,
+}
+
+fn compile(node: &Node, prog: &mut Vec<Inst>) -> Result<(), String> {
+    if prog.len() > MAX_PROGRAM {
+        return Err(format!(
+            "regex compiles to more than {MAX_PROGRAM} instructions"
+        ));
+    }
+    match node {
+        Node::Byte(b) => prog.push(Inst::Byte(*b)),
+        Node::Any => prog.push(Inst::Any),
+        Node::Class(c) => prog.push(Inst::Class(c.clone())),
+        Node::Start => prog.push(Inst::Start),
+        Node::End => prog.push(Inst::End),
+        Node::WordBoundary(x) => prog.push(Inst::WordBoundary(*x)),
+        Node::Group(idx, alts) => {
+            if let Some(i) = idx {
+                prog.push(Inst::Save(2 * i));
+            }
+            let mut jumps_to_end = vec![];
+            for (n, alt) in alts.iter().enumerate() {
+                let split = prog.len();
+                if n + 1 < alts.len() {
+                    prog.push(Inst::Split(split + 1, 0));
+                }
+                for x in alt {
+                    compile(x, prog)?;
+                }
+                if n + 1 < alts.len() {
+                    jumps_to_end.push(prog.len());
+                    prog.push(Inst::Jmp(0));
+                    let next = prog.len();
+                    prog[split] = Inst::Split(split + 1, next);
+                }
+            }
+            let end = prog.len();
+            for j in jumps_to_end {
+                prog[j] = Inst::Jmp(end);
+            }
+            if let Some(i) = idx {
+                prog.push(Inst::Save(2 * i + 1));
+            }
+        }
+        Node::Repeat {
+            node,
+            min,
+            max,
+            greedy,
+        } => {
+            let fork = |body: usize, exit: usize| {
+                if *greedy {
+                    Inst::Split(body, exit)
+                } else {
+                    Inst::Split(exit, body)
+                }
+            };
+            for _ in 0..*min {
+                compile(node, prog)?;
+            }
+            if *max == usize::MAX {
+                let split = prog.len();
+                prog.push(Inst::Jmp(0));
+                compile(node, prog)?;
+                prog.push(Inst::Jmp(split));
+                prog[split] = fork(split + 1, prog.len());
+            } else {
+                let mut splits = vec![];
+                for _ in *min..*max {
+                    splits.push(prog.len());
+                    prog.push(Inst::Jmp(0));
+                    compile(node, prog)?;
+                }
+                let end = prog.len();
+                for s in splits {
+                    prog[s] = fork(s + 1, end);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Thread list for the Pike VM: threads in priority order plus membership marks
+struct Threads {
+    list: Vec<(usize, Vec<Option<usize>>)>,
+    seen: Vec<bool>,
+}
+
+impl Threads {
+    fn new(n: usize) -> Self {
+        Threads {
+            list: Vec::new(),
+            seen: vec![false; n],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.seen.iter_mut().for_each(|x| *x = false);
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut p = Parser {
+            s: pattern.as_bytes(),
+            pos: 0,
+            ngroups: 0,
+            depth: 0,
+        };
+        let alts = p.alternation()?;
+        if p.pos != p.s.len() {
+            return Err(format!("unmatched `)` in regex {pattern}"));
+        }
+        let mut prog = vec![];
+        compile(&Node::Group(Some(0), alts), &mut prog)?;
+        prog.push(Inst::Match);
+        Ok(Regex {
+            prog,
+            ngroups: p.ngroups,
+        })
+    }
+
+    /// Number of capture groups, not including the whole match
+    pub fn groups(&self) -> usize {
+        self.ngroups
+    }
+
+    /// Follow non-consuming instructions and add resulting threads to the list.
+    /// Uses an explicit stack, as chains of such instructions may be long.
+    fn add_thread(
+        &self,
+        threads: &mut Threads,
+        pc: usize,
+        hay: &[u8],
+        pos: usize,
+        slots: Vec<Option<usize>>,
+    ) {
+        let mut stack = vec![(pc, slots)];
+        while let Some((mut pc, mut slots)) = stack.pop() {
+            loop {
+                if threads.seen[pc] {
+                    break;
+                }
+                threads.seen[pc] = true;
+                let pass = match self.prog[pc] {
+                    Inst::Jmp(x) => {
+                        pc = x;
+                        continue;
+                    }
+                    Inst::Split(x, y) => {
+                        // The second branch is followed after everything reachable from the first
+                        stack.push((y, slots.clone()));
+                        pc = x;
+                        continue;
+                    }
+                    Inst::Save(n) => {
+                        slots[n] = Some(pos);
+                        true
+                    }
+                    Inst::Start => pos == 0,
+                    Inst::End => pos == hay.len(),
+                    Inst::WordBoundary(want) => {
+                        let before = pos > 0 && is_word(hay[pos - 1]);
+                        let after = pos < hay.len() && is_word(hay[pos]);
+                        (before != after) == want
+                    }
+                    _ => {
+                        threads.list.push((pc, slots));
+                        break;
+                    }
+                };
+                if !pass {
+                    break;
+                }
+                pc += 1;
+            }
+        }
+    }
+
+    pub fn is_match(&self, hay: &[u8]) -> bool {
+        self.captures_at(hay, 0).is_some()
+    }
+
+    /// Find the leftmost match starting at or after `start`
+    pub fn captures_at(&self, hay: &[u8], start: usize) -> Option<Captures> {
+        let nslots = 2 * (self.ngroups + 1);
+        let mut clist = Threads::new(self.prog.len());
+        let mut nlist = Threads::new(self.prog.len());
+        let mut matched: Option<Vec<Option<usize>>> = None;
+        for pos in start..=hay.len() {
+            if matched.is_none() {
+                self.add_thread(&mut clist, 0, hay, pos, vec![None; nslots]);
+            }
+            // Without a match yet, the next position starts a new thread
+            if clist.list.is_empty() && matched.is_some() {
+                break;
+            }
+            let b = hay.get(pos).copied();
+            for (pc, slots) in std::mem::take(&mut clist.list) {
+                let ok = match (&self.prog[pc], b) {
+                    (Inst::Match, _) => {
+                        // Lower priority threads are discarded
+                        matched = Some(slots);
+                        break;
+                    }
+                    (Inst::Byte(x), Some(b)) => *x == b,
+                    (Inst::Any, Some(b)) => b != b'\n',
+                    (Inst::Class(c), Some(b)) => c[b as usize],
+                    _ => false,
+                };
+                if ok {
+                    self.add_thread(&mut nlist, pc + 1, hay, pos + 1, slots);
+                }
+            }
+            std::mem::swap(&mut clist, &mut nlist);
+            nlist.clear();
+        }
+        let slots = matched?;
+        Some(slots.chunks(2).map(|x| Some((x[0]?, x[1]?))).collect())
+    }
+
+    /// Replace first (or all) matches. Returns `None` if there were no matches.
+    pub fn replace(&self, hay: &[u8], rep: &Replacement, all: bool) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut matched = false;
+        let mut last = 0;
+        let mut pos = 0;
+        while let Some(caps) = self.captures_at(hay, pos) {
+            let (s, e) = caps[0].unwrap();
+            if s == e && matched && s == last {
+                // No empty matches right after a previous match
+                pos = s + 1;
+                if pos > hay.len() {
+                    break;
+                }
+                continue;
+            }
+            matched = true;
+            out.extend_from_slice(&hay[last..s]);
+            rep.expand(hay, &caps, &mut out);
+            last = e;
+            pos = if e == s { e + 1 } else { e };
+            if !all || pos > hay.len() {
+                break;
+            }
+        }
+        if !matched {
+            return None;
+        }
+        out.extend_from_slice(&hay[last..]);
+        Some(out)
+    }
+}
+
+enum ReplacementPart {
+    Literal(Vec<u8>),
+    Group(usize),
+}
+
+/// Replacement template with `$1` or `${1}` references to capture groups (`$0` is the whole match, `$$` is `$`)
+pub struct Replacement(Vec<ReplacementPart>);
+
+impl Replacement {
+    /// Parse the template, validating group references against the regex
+    pub fn new(template: &str, regex: &Regex) -> Result<Replacement, String> {
+        let mut parts = vec![];
+        let mut lit = vec![];
+        let s = template.as_bytes();
+        let mut i = 0;
+        while i < s.len() {
+            if s[i] != b'$' {
+                lit.push(s[i]);
+                i += 1;
+                continue;
+            }
+            i += 1;
+            let braced = s.get(i) == Some(&b'{');
+            if braced {
+                i += 1;
+            }
+            let start = i;
+            while s.get(i).is_some_and(|b| b.is_ascii_digit()) {
+                i += 1;
+            }
+            if start == i {
+                if braced || s.get(i) != Some(&b'$') {
+                    return Err(format!("invalid group reference in {template}"));
+                }
+                lit.push(b'$');
+                i += 1;
+                continue;
+            }
+            let n: usize = template[start..i].parse().map_err(|_| "bad group number")?;
+            if braced {
+                if s.get(i) != Some(&b'}') {
+                    return Err(format!("unclosed group reference in {template}"));
+                }
+                i += 1;
+            }
+            if n > regex.groups() {
+                return Err(format!("no capture group ${n} in regex"));
+            }
+            if !lit.is_empty() {
+                parts.push(ReplacementPart::Literal(std::mem::take(&mut lit)));
+            }
+            parts.push(ReplacementPart::Group(n));
+        }
+        if !lit.is_empty() {
+            parts.push(ReplacementPart::Literal(lit));
+        }
+        Ok(Replacement(parts))
+    }
+
+    fn expand(&self, hay: &[u8], caps: &Captures, out: &mut Vec<u8>) {
+        for part in &self.0 {
+            match part {
+                ReplacementPart::Literal(x) => out.extend_from_slice(x),
+                ReplacementPart::Group(n) => {
+                    if let Some((s, e)) = caps[*n] {
+                        out.extend_from_slice(&hay[s..e]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whole match of the leftmost match
+    fn find<'a>(re: &str, hay: &'a str) -> Option<&'a str> {
+        let (s, e) = Regex::new(re).unwrap().captures_at(hay.as_bytes(), 0)?[0]?;
+        Some(&hay[s..e])
+    }
+
+    fn replace(re: &str, template: &str, hay: &str, all: bool) -> Option<String> {
+        let regex = Regex::new(re).unwrap();
+        let rep = Replacement::new(template, &regex).unwrap();
+        let out = regex.replace(hay.as_bytes(), &rep, all)?;
+        Some(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(find("abc", "xxabcxx"), Some("abc"));
+        assert_eq!(find("abc", "abd"), None);
+        assert_eq!(find("a.c", "a\nc abc"), Some("abc"));
+        assert_eq!(find(r"\x41\t", "zA\t"), Some("A\t"));
+        assert_eq!(find("a{b", "a{b"), Some("a{b"));
+        assert_eq!(find("é+", "aééb"), Some("éé"));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(find("[a-c_]+", "xyz_cab!"), Some("_cab"));
+        assert_eq!(find("[^0-9 ]+", "12 ab3"), Some("ab"));
+        assert_eq!(find("[]x]+", "a]x]"), Some("]x]"));
+        assert_eq!(find("[a-]+", "b-a-"), Some("-a-"));
+        assert_eq!(find(r"[\d.]+", "v1.25;"), Some("1.25"));
+        assert_eq!(find(r"\d+", "abc 123"), Some("123"));
+        assert_eq!(find(r"\w+", " foo_1 "), Some("foo_1"));
+        assert_eq!(find(r"\S+", "  x-y "), Some("x-y"));
+        assert_eq!(find(r"\D\W", "1a b"), Some("a "));
+    }
+
+    #[test]
+    fn anchors() {
+        assert_eq!(find("^ab", "abab"), Some("ab"));
+        assert_eq!(find("^b", "ab"), None);
+        assert_eq!(find("b$", "abab"), Some("b"));
+        assert_eq!(find("a$", "ab"), None);
+        assert_eq!(find(r"\bcat\b", "concat cat"), Some("cat"));
+        assert_eq!(find(r"\bcat\b", "concat"), None);
+        assert_eq!(find(r"\Bcat", "concat"), Some("cat"));
+        assert_eq!(find("^$", ""), Some(""));
+    }
+
+    #[test]
+    fn alternation() {
+        assert_eq!(find("cat|dog", "hotdog"), Some("dog"));
+        assert_eq!(find("a|ab", "ab"), Some("a"));
+        assert_eq!(find("x(?:a|b|)y", "xy xay"), Some("xy"));
+        assert_eq!(find("^(?:foo|bar)$", "bar"), Some("bar"));
+        assert_eq!(find("^(?:foo|bar)$", "foobar"), None);
+    }
+
+    #[test]
+    fn greedy_and_lazy() {
+        assert_eq!(find("a.*b", "aXbYb"), Some("aXbYb"));
+        assert_eq!(find("a.*?b", "aXbYb"), Some("aXb"));
+        assert_eq!(find("a.+?", "abc"), Some("ab"));
+        assert_eq!(find("ab??", "ab"), Some("a"));
+        assert_eq!(find("ab?", "ab"), Some("ab"));
+        assert_eq!(find("a*", "baa"), Some(""));
+    }
+
+    #[test]
+    fn counted_repetition() {
+        assert_eq!(find("^a{3}$", "aaa"), Some("aaa"));
+        assert_eq!(find("^a{3}$", "aa"), None);
+        assert_eq!(find("^a{3}$", "aaaa"), None);
+        assert_eq!(find("a{2,}", "caaaaa"), Some("aaaaa"));
+        assert_eq!(find("a{2,}", "ca"), None);
+        assert_eq!(find("a{2,3}", "aaaaa"), Some("aaa"));
+        assert_eq!(find("a{2,3}?", "aaaaa"), Some("aa"));
+        assert_eq!(find("(?:ab){2}", "abaabab"), Some("abab"));
+        assert_eq!(find("x{0}y", "xy"), Some("y"));
+    }
+
+    #[test]
+    fn capture_groups() {
+        let re = Regex::new(r"(\w+)@(\w+)(!)?").unwrap();
+        assert_eq!(re.groups(), 3);
+        let caps = re.captures_at(b"mail bob@host.", 0).unwrap();
+        assert_eq!(caps, [Some((5, 13)), Some((5, 8)), Some((9, 13)), None]);
+        let re = Regex::new("(a)|(b)").unwrap();
+        assert_eq!(
+            re.captures_at(b"xb", 0).unwrap(),
+            [Some((1, 2)), None, Some((1, 2))]
+        );
+        // The last iteration of a repeated group is captured
+        let re = Regex::new("(?:(a|b))+").unwrap();
+        assert_eq!(re.captures_at(b"abb", 0).unwrap()[1], Some((2, 3)));
+        assert_eq!(re.captures_at(b"xab", 1).unwrap()[0], Some((1, 3)));
+    }
+
+    #[test]
+    fn replacement() {
+        assert_eq!(
+            replace(r"(\w+)=(\d+)", "$2=${1}", "a=1 b=2", true).as_deref(),
+            Some("1=a 2=b")
+        );
+        assert_eq!(replace(r"\d", "#", "a1b2", false).as_deref(), Some("a#b2"));
+        assert_eq!(replace(r"\d", "$$0", "x5", true).as_deref(), Some("x$0"));
+        assert_eq!(replace(r"\d", "#", "abc", true), None);
+        let regex = Regex::new("(a)").unwrap();
+        assert!(Replacement::new("$2", &regex).is_err());
+        assert!(Replacement::new("${1", &regex).is_err());
+        assert!(Replacement::new("$x", &regex).is_err());
+    }
+
+    #[test]
+    fn replacement_with_empty_matches() {
+        assert_eq!(replace("x*", "-", "abc", true).as_deref(), Some("-a-b-c-"));
+        // No empty match right after a non-empty one
+        assert_eq!(replace("x*", "-", "axxb", true).as_deref(), Some("-a-b-"));
+        assert_eq!(replace("x*", "-", "axxb", false).as_deref(), Some("-axxb"));
+        assert_eq!(replace("^", ">", "ab", true).as_deref(), Some(">ab"));
+        assert_eq!(replace("$", "<", "ab", true).as_deref(), Some("ab<"));
+        assert_eq!(replace("", "-", "", true).as_deref(), Some("-"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        for re in [
+            "(", "a)", "*a", "a**", "^*", "a{3,2}", "[a", "[b-a]", r"\q", r"\xZZ", "a{2",
+        ] {
+            assert!(Regex::new(re).is_err(), "{re}");
+        }
+    }
+
+    #[test]
+    fn repetition_limits() {
+        assert!(Regex::new("a{1000}").is_ok());
+        assert!(Regex::new("a{1001}").is_err());
+        assert!(Regex::new("a{1,1001}").is_err());
+        assert!(Regex::new("a{1001,}").is_err());
+        assert!(Regex::new("(a?){100000}").is_err());
+        assert!(Regex::new("(x{1000}){1000}").is_err());
+        assert!(Regex::new(&format!("{}a{}", "(".repeat(100), ")".repeat(100))).is_ok());
+        assert!(Regex::new(&format!("{}a{}", "(".repeat(100000), ")".repeat(100000))).is_err());
+    }
+
+    #[test]
+    fn long_programs_do_not_overflow_stack() {
+        // Long chains of empty-matching instructions, followed iteratively
+        let re = Regex::new(&"(?:a?)".repeat(20000)).unwrap();
+        assert!(re.is_match(b""));
+        let re = Regex::new("(?:(?:a?){1000}){30}b").unwrap();
+        assert_eq!(re.captures_at(b"aab", 0).unwrap()[0], Some((0, 3)));
+    }
+}
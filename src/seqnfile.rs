@@ -0,0 +1,101 @@
+//! `--seqn-file`: continuing sequence numbers after restart
+
+use std::{io::Write, path::PathBuf};
+
+use crate::{json::Field, log};
+
+/// File holding the sequence number the next run may start from
+pub struct SeqnFile {
+    path: PathBuf,
+    /// `--seqn-sync-interval`: number of lines between writes
+    interval: u64,
+    /// Sequence number stored in the file. Lines up to it may be sent without writing the file.
+    reserved: u64,
+}
+
+impl SeqnFile {
+    /// Read the stored sequence number, 0 if the file does not exist yet.
+    /// Other failures are errors, as starting from 0 would reuse sequence numbers.
+    pub fn load(path: PathBuf, interval: u64) -> std::io::Result<(Self, u64)> {
+        let seqn = match std::fs::read_to_string(&path) {
+            Ok(s) => s.trim().parse().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid sequence number: {e}"),
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let f = Self {
+            path,
+            interval,
+            reserved: 0,
+        };
+        Ok((f, seqn))
+    }
+
+    /// Called with each new sequence number. Every `interval` lines the file is updated with
+    /// a number `interval` lines ahead, so that sequence numbers are not reused after a crash.
+    pub fn advance(&mut self, next_seqn: u64) {
+        if next_seqn < self.reserved {
+            return;
+        }
+        self.reserved = next_seqn.saturating_add(self.interval);
+        self.save(self.reserved);
+    }
+
+    /// Store the exact next sequence number on clean shutdown
+    pub fn save(&self, next_seqn: u64) {
+        if let Err(e) = self.write(next_seqn) {
+            log::event(
+                log::Level::Warn,
+                "seqn_file_error",
+                &[
+                    ("path", Field::Str(&self.path.to_string_lossy())),
+                    ("error", Field::Str(&e.to_string())),
+                ],
+                Some(format_args!("Writing {}: {e}", self.path.display())),
+            );
+        }
+    }
+
+    fn write(&self, next_seqn: u64) -> std::io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut f = std::fs::File::create(&tmp)?;
+        writeln!(f, "{next_seqn}")?;
+        f.sync_all()?;
+        std::fs::rename(tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_and_reload() {
+        let path = std::env::temp_dir().join(format!("stdintap-seqn-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut f, seqn) = SeqnFile::load(path.clone(), 100).unwrap();
+        assert_eq!(seqn, 0);
+
+        f.advance(0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "100\n");
+        // Not written again until the reserved numbers are used up
+        f.advance(99);
+        assert_eq!(SeqnFile::load(path.clone(), 100).unwrap().1, 100);
+        f.advance(100);
+        assert_eq!(SeqnFile::load(path.clone(), 100).unwrap().1, 200);
+
+        f.save(150);
+        assert_eq!(SeqnFile::load(path.clone(), 100).unwrap().1, 150);
+
+        for garbage in ["garbage", "", "12x", "-1"] {
+            std::fs::write(&path, garbage).unwrap();
+            assert!(SeqnFile::load(path.clone(), 100).is_err(), "{garbage:?}");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
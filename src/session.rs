@@ -0,0 +1,66 @@
+//! `--session-tokens`: letting reconnecting clients resume from the last line they received
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Tokens of current and recently disconnected sessions
+pub struct Sessions {
+    /// Token and the time its client disconnected, `None` while still connected
+    tokens: Mutex<HashMap<String, Option<Instant>>>,
+    /// How long tokens stay valid after disconnection
+    ttl: Option<Duration>,
+}
+
+impl Sessions {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            tokens: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    fn expired(&self, disconnected: Option<Instant>, now: Instant) -> bool {
+        match (self.ttl, disconnected) {
+            (Some(ttl), Some(t)) => now - t > ttl,
+            _ => false,
+        }
+    }
+
+    /// Create a token for a new client, valid until the returned guard is dropped and `ttl` after that
+    pub fn issue(self: &Arc<Self>) -> Session {
+        let token = format!("{:032x}", crate::random_u128());
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, &mut t| !self.expired(t, now));
+        tokens.insert(token.clone(), None);
+        Session(self.clone(), token)
+    }
+
+    /// Check the token of a previous session. It is consumed, as the client gets a new one.
+    pub fn resume(&self, token: &str) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens
+            .remove(token)
+            .is_some_and(|t| !self.expired(t, Instant::now()))
+    }
+}
+
+pub struct Session(Arc<Sessions>, String);
+
+impl Session {
+    pub fn token(&self) -> &str {
+        &self.1
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut tokens = self.0.tokens.lock().unwrap();
+        if let Some(t) = tokens.get_mut(&self.1) {
+            *t = Some(Instant::now());
+        }
+    }
+}
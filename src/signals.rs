@@ -0,0 +1,74 @@
+//! Minimal async signal handling based on the self-pipe trick
+
+use std::{
+    io::ErrorKind,
+    os::{fd::IntoRawFd, unix::net::UnixStream},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use tokio::io::AsyncReadExt;
+
+/// Maximum number of simultaneous signal subscriptions
+const SLOTS: usize = 8;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicI32 = AtomicI32::new(-1);
+/// Signal numbers of subscriptions
+static SIGNALS: [AtomicI32; SLOTS] = [EMPTY; SLOTS];
+/// Write ends of the pipes of subscriptions
+static FDS: [AtomicI32; SLOTS] = [EMPTY; SLOTS];
+
+extern "C" fn handler(sig: libc::c_int) {
+    for (s, fd) in SIGNALS.iter().zip(FDS.iter()) {
+        let fd = fd.load(Ordering::Acquire);
+        if s.load(Ordering::Acquire) == sig && fd >= 0 {
+            // Only async-signal-safe calls here. Pipe is nonblocking, overflow just merges signals.
+            unsafe {
+                libc::write(fd, b"!".as_ptr().cast(), 1);
+            }
+        }
+    }
+}
+
+/// Stream of notifications about received signal of specific type
+pub struct Signal {
+    rx: tokio::net::UnixStream,
+}
+
+impl Signal {
+    /// Install handler for the signal (replacing the default action) and subscribe to it
+    pub fn new(sig: libc::c_int) -> std::io::Result<Signal> {
+        let (rx, tx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+        let slot = FDS
+            .iter()
+            .position(|fd| fd.load(Ordering::Acquire) == -1)
+            .ok_or_else(|| std::io::Error::other("too many signal handlers"))?;
+        SIGNALS[slot].store(sig, Ordering::Release);
+        FDS[slot].store(tx.into_raw_fd(), Ordering::Release);
+
+        unsafe {
+            let mut sa: libc::sigaction = std::mem::zeroed();
+            sa.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            sa.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut sa.sa_mask);
+            if libc::sigaction(sig, &sa, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(Signal {
+            rx: tokio::net::UnixStream::from_std(rx)?,
+        })
+    }
+
+    /// Wait for the next occurrence of the signal
+    pub async fn recv(&mut self) -> std::io::Result<()> {
+        let mut buf = [0u8; 64];
+        let n = self.rx.read(&mut buf).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+//! `--spill-dir`: keeping messages that don't fit in the broadcast queue in files until there is room
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{Msg, MsgInner};
+
+const KIND_CONTENT: u8 = 0;
+const KIND_EOF: u8 = 1;
+/// Length written in place of absent topic
+const NO_TOPIC: u32 = u32::MAX;
+
+/// One spill file, from the moment the queue fills up until all its messages are re-injected
+struct Episode {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: BufReader<File>,
+    /// Messages written, but not read back yet
+    pending: usize,
+}
+
+pub struct Spill {
+    dir: PathBuf,
+    /// Timestamps are stored relative to this
+    begin: Instant,
+    /// Number of the next spill file
+    counter: u64,
+    episode: Option<Episode>,
+}
+
+impl Spill {
+    pub fn new(dir: PathBuf, begin: Instant) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            begin,
+            counter: 0,
+            episode: None,
+        })
+    }
+
+    /// Whether there are spilled messages that must be delivered before new ones
+    pub fn active(&self) -> bool {
+        self.episode.is_some()
+    }
+
+    /// Append the message to the spill file, starting a new file if needed.
+    /// Returns the path of the file if it was just created.
+    pub fn write(&mut self, msg: &Msg) -> std::io::Result<Option<PathBuf>> {
+        let mut created = None;
+        if self.episode.is_none() {
+            let path = self.dir.join(format!(
+                "stdintap-{}-{}.spill",
+                std::process::id(),
+                self.counter
+            ));
+            self.counter += 1;
+            let writer = BufWriter::new(File::create(&path)?);
+            let reader = BufReader::new(File::open(&path)?);
+            created = Some(path.clone());
+            self.episode = Some(Episode {
+                path,
+                writer,
+                reader,
+                pending: 0,
+            });
+        }
+        let episode = self.episode.as_mut().unwrap();
+        let (kind, data) = match msg.inner {
+            MsgInner::Content(ref b) => (KIND_CONTENT, &b[..]),
+            MsgInner::Eof => (KIND_EOF, &[][..]),
+            _ => unreachable!("only content and EOF are spilled"),
+        };
+        let w = &mut episode.writer;
+        w.write_all(&[kind])?;
+        w.write_all(&msg.seqn.to_be_bytes())?;
+        let ts = msg.ts.saturating_duration_since(self.begin).as_nanos() as u64;
+        w.write_all(&ts.to_be_bytes())?;
+        w.write_all(&msg.target.unwrap_or(0).to_be_bytes())?;
+        match msg.topic {
+            Some(ref t) => {
+                w.write_all(&(t.len() as u32).to_be_bytes())?;
+                w.write_all(t.as_bytes())?;
+            }
+            None => w.write_all(&NO_TOPIC.to_be_bytes())?,
+        }
+        w.write_all(&(data.len() as u32).to_be_bytes())?;
+        w.write_all(data)?;
+        episode.pending += 1;
+        Ok(created)
+    }
+
+    /// Read the oldest spilled message back. When the last one is read, the file is removed.
+    pub fn read(&mut self) -> std::io::Result<Option<Msg>> {
+        let Some(ref mut episode) = self.episode else {
+            return Ok(None);
+        };
+        episode.writer.flush()?;
+        let r = &mut episode.reader;
+        let mut kind = [0u8];
+        r.read_exact(&mut kind)?;
+        let seqn = read_u64(r)?;
+        let ts = self.begin + Duration::from_nanos(read_u64(r)?);
+        let target = Some(read_u64(r)?).filter(|&t| t != 0);
+        let topic = match read_u32(r)? {
+            NO_TOPIC => None,
+            n => {
+                let b = read_bytes(r, n)?;
+                let t = String::from_utf8(b).map_err(std::io::Error::other)?;
+                Some(Arc::from(t))
+            }
+        };
+        let len = read_u32(r)?;
+        let data = read_bytes(r, len)?;
+        let inner = match kind[0] {
+            KIND_CONTENT => MsgInner::Content(Bytes::from(data)),
+            _ => MsgInner::Eof,
+        };
+        episode.pending -= 1;
+        if episode.pending == 0 {
+            self.discard();
+        }
+        Ok(Some(Msg {
+            ts,
+            inner,
+            seqn,
+            target,
+            topic,
+            repeat_count: 0,
+        }))
+    }
+
+    /// End the current episode, removing its file
+    pub fn discard(&mut self) {
+        if let Some(episode) = self.episode.take() {
+            let _ = std::fs::remove_file(episode.path);
+        }
+    }
+}
+
+fn read_u64(r: &mut impl Read) -> std::io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_bytes(r: &mut impl Read, n: u32) -> std::io::Result<Vec<u8>> {
+    let mut b = vec![0u8; n as usize];
+    r.read_exact(&mut b)?;
+    Ok(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(
+        ts: Instant,
+        inner: MsgInner,
+        seqn: u64,
+        target: Option<u64>,
+        topic: Option<&str>,
+    ) -> Msg {
+        Msg {
+            ts,
+            inner,
+            seqn,
+            target,
+            topic: topic.map(Arc::from),
+            repeat_count: 0,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("stdintap-spill-test-{}", std::process::id()));
+        let begin = Instant::now();
+        let mut spill = Spill::new(dir.clone(), begin).unwrap();
+        assert!(!spill.active());
+        assert!(spill.read().unwrap().is_none());
+
+        let ts = begin + Duration::from_nanos(1_234_567_891);
+        let msgs = [
+            msg(ts, MsgInner::Content("a\n".into()), 5, None, None),
+            msg(
+                ts,
+                MsgInner::Content(Bytes::new()),
+                6,
+                Some(3),
+                Some("metrics"),
+            ),
+            msg(ts, MsgInner::Eof, 7, None, Some("")),
+        ];
+        let path = spill.write(&msgs[0]).unwrap().unwrap();
+        assert!(path.exists());
+        assert!(spill.write(&msgs[1]).unwrap().is_none());
+        assert!(spill.active());
+
+        let check = |m: Msg, expected: &Msg| {
+            assert_eq!(m.seqn, expected.seqn);
+            assert_eq!(m.ts, expected.ts);
+            assert_eq!(m.target, expected.target);
+            assert_eq!(m.topic, expected.topic);
+            match (m.inner, &expected.inner) {
+                (MsgInner::Content(a), MsgInner::Content(b)) => assert_eq!(a, b),
+                (MsgInner::Eof, MsgInner::Eof) => {}
+                _ => panic!("message kind differs"),
+            }
+        };
+        check(spill.read().unwrap().unwrap(), &msgs[0]);
+        // Writing and reading may interleave
+        assert!(spill.write(&msgs[2]).unwrap().is_none());
+        check(spill.read().unwrap().unwrap(), &msgs[1]);
+        check(spill.read().unwrap().unwrap(), &msgs[2]);
+
+        // The file is removed once everything is read back
+        assert!(!spill.active());
+        assert!(!path.exists());
+        let next = spill.write(&msgs[0]).unwrap().unwrap();
+        assert_ne!(next, path);
+        spill.discard();
+        assert!(!next.exists());
+        let _ = std::fs::remove_dir(dir);
+    }
+}
@@ -0,0 +1,336 @@
+//! Counters shared between the stdin reader and client tasks
+
+use std::{
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use crate::latency::{self, Samples};
+
+/// Counters for `--stats-interval` messages
+#[derive(Clone, Copy)]
+pub struct StatsSnapshot {
+    pub lines: u64,
+    pub bytes: u64,
+    pub clients: u64,
+    pub overruns: u64,
+    /// `--input-rate-measure-window` bytes and lines per second
+    pub rate: Option<(f64, f64)>,
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "STATS lines={} bytes={} clients={} overruns={}",
+            self.lines, self.bytes, self.clients, self.overruns
+        )?;
+        if let Some((bps, lps)) = self.rate {
+            write!(f, " rate_bps={bps:.1} rate_lps={lps:.1}")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Stats {
+    pub begin: Instant,
+    pub lines_total: AtomicU64,
+    pub bytes_total: AtomicU64,
+    /// Number of lines missed by all clients due to overruns
+    pub overrun_count: AtomicU64,
+    /// Number of times any client fell behind
+    pub overrun_events: AtomicU64,
+    pub clients_total: AtomicU64,
+    /// Bytes written to all clients
+    pub bytes_sent_total: AtomicU64,
+    /// Lines written to all clients
+    pub lines_sent_total: AtomicU64,
+    /// Broadcast queue length after the last sent message
+    pub queue_depth: AtomicUsize,
+    pub backpressure_active: AtomicBool,
+    /// Sequence number of the next line to be broadcast
+    pub next_seqn: AtomicU64,
+    /// `--byte-stats`: include totals sent to clients in health reports
+    pub byte_stats: bool,
+    /// `--latency-tracking` samples of all clients
+    pub latency: Option<Mutex<Samples>>,
+}
+
+impl Stats {
+    pub fn new(begin: Instant, byte_stats: bool, latency_tracking: bool) -> Self {
+        Self {
+            begin,
+            lines_total: AtomicU64::new(0),
+            bytes_total: AtomicU64::new(0),
+            overrun_count: AtomicU64::new(0),
+            overrun_events: AtomicU64::new(0),
+            clients_total: AtomicU64::new(0),
+            bytes_sent_total: AtomicU64::new(0),
+            lines_sent_total: AtomicU64::new(0),
+            queue_depth: AtomicUsize::new(0),
+            backpressure_active: AtomicBool::new(false),
+            next_seqn: AtomicU64::new(0),
+            byte_stats,
+            latency: latency_tracking.then(|| Mutex::new(Samples::new(latency::TOTAL_SAMPLES))),
+        }
+    }
+
+    pub fn snapshot(&self, client_count: usize) -> StatsSnapshot {
+        StatsSnapshot {
+            lines: self.lines_total.load(Relaxed),
+            bytes: self.bytes_total.load(Relaxed),
+            clients: client_count as u64,
+            overruns: self.overrun_count.load(Relaxed),
+            rate: None,
+        }
+    }
+
+    /// Single-line JSON health report for `DIAG` handshake command
+    pub fn health_json(&self, client_count: usize, history_size: usize) -> String {
+        let mut s = String::with_capacity(256);
+        let _ = write!(
+            s,
+            "{{\"uptime\":{:.3},\"lines_total\":{},\"bytes_total\":{},\"client_count\":{client_count},\
+            \"queue_depth\":{},\"backpressure_active\":{},\"history_size\":{history_size},\"overrun_count\":{}}}",
+            self.begin.elapsed().as_secs_f64(),
+            self.lines_total.load(Relaxed),
+            self.bytes_total.load(Relaxed),
+            self.queue_depth.load(Relaxed),
+            self.backpressure_active.load(Relaxed),
+            self.overrun_count.load(Relaxed),
+        );
+        s.pop();
+        if self.byte_stats {
+            let _ = write!(
+                s,
+                ",\"bytes_sent_total\":{},\"lines_sent_total\":{}",
+                self.bytes_sent_total.load(Relaxed),
+                self.lines_sent_total.load(Relaxed),
+            );
+        }
+        let latency = self
+            .latency
+            .as_ref()
+            .map(|l| l.lock().unwrap().percentiles());
+        if let Some(Some([p50, p95, p99])) = latency {
+            let _ = write!(
+                s,
+                ",\"latency_p50_ms\":{p50:.3},\"latency_p95_ms\":{p95:.3},\"latency_p99_ms\":{p99:.3}"
+            );
+        }
+        s.push('}');
+        s
+    }
+
+    /// Metrics in Prometheus text exposition format
+    pub fn prometheus(
+        &self,
+        client_count: usize,
+        history_size: usize,
+        input_rate: Option<f64>,
+    ) -> String {
+        let mut s = String::with_capacity(1024);
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            let _ = write!(
+                s,
+                "# HELP stdintap_{name} {help}\n# TYPE stdintap_{name} {kind}\nstdintap_{name} {value}\n"
+            );
+        };
+        metric(
+            "lines_read_total",
+            "counter",
+            "Lines read from input",
+            &self.lines_total.load(Relaxed),
+        );
+        metric(
+            "bytes_read_total",
+            "counter",
+            "Bytes read from input",
+            &self.bytes_total.load(Relaxed),
+        );
+        metric(
+            "clients_connected_total",
+            "counter",
+            "Clients ever connected",
+            &self.clients_total.load(Relaxed),
+        );
+        metric(
+            "clients",
+            "gauge",
+            "Currently connected clients",
+            &client_count,
+        );
+        metric(
+            "overrun_events_total",
+            "counter",
+            "Times a client fell behind the queue",
+            &self.overrun_events.load(Relaxed),
+        );
+        metric(
+            "lagged_messages_total",
+            "counter",
+            "Messages missed by clients due to overruns",
+            &self.overrun_count.load(Relaxed),
+        );
+        metric(
+            "bytes_sent_total",
+            "counter",
+            "Bytes written to all clients",
+            &self.bytes_sent_total.load(Relaxed),
+        );
+        metric(
+            "lines_sent_total",
+            "counter",
+            "Lines written to all clients",
+            &self.lines_sent_total.load(Relaxed),
+        );
+        metric(
+            "history_lines",
+            "gauge",
+            "Entries in history buffer",
+            &history_size,
+        );
+        metric(
+            "queue_depth",
+            "gauge",
+            "Broadcast queue length",
+            &self.queue_depth.load(Relaxed),
+        );
+        if let Some(rate) = input_rate {
+            metric(
+                "input_rate_bytes_per_sec",
+                "gauge",
+                "Input rate over --input-rate-measure-window",
+                &rate,
+            );
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Health report with the varying uptime field removed
+    fn health(stats: &Stats, client_count: usize, history_size: usize) -> String {
+        let s = stats.health_json(client_count, history_size);
+        let (uptime, rest) = s.split_once(',').unwrap();
+        let uptime: f64 = uptime
+            .strip_prefix("{\"uptime\":")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(uptime >= 0.0);
+        format!("{{{rest}")
+    }
+
+    #[test]
+    fn health_report() {
+        let stats = Stats::new(Instant::now(), false, false);
+        assert_eq!(
+            health(&stats, 0, 0),
+            concat!(
+                r#"{"lines_total":0,"bytes_total":0,"client_count":0,"queue_depth":0,"#,
+                r#""backpressure_active":false,"history_size":0,"overrun_count":0}"#
+            )
+        );
+        stats.lines_total.store(3, Relaxed);
+        stats.bytes_total.store(42, Relaxed);
+        stats.queue_depth.store(2, Relaxed);
+        stats.backpressure_active.store(true, Relaxed);
+        stats.overrun_count.store(7, Relaxed);
+        assert_eq!(
+            health(&stats, 5, 100),
+            concat!(
+                r#"{"lines_total":3,"bytes_total":42,"client_count":5,"queue_depth":2,"#,
+                r#""backpressure_active":true,"history_size":100,"overrun_count":7}"#
+            )
+        );
+    }
+
+    #[test]
+    fn stats_line() {
+        let stats = Stats::new(Instant::now(), false, false);
+        stats.lines_total.store(3, Relaxed);
+        stats.bytes_total.store(42, Relaxed);
+        let mut snapshot = stats.snapshot(2);
+        assert_eq!(
+            snapshot.to_string(),
+            "STATS lines=3 bytes=42 clients=2 overruns=0"
+        );
+        snapshot.rate = Some((1234.56, 0.04));
+        assert_eq!(
+            snapshot.to_string(),
+            "STATS lines=3 bytes=42 clients=2 overruns=0 rate_bps=1234.6 rate_lps=0.0"
+        );
+    }
+
+    #[test]
+    fn prometheus_metrics() {
+        let stats = Stats::new(Instant::now(), false, false);
+        stats.lines_total.store(10, Relaxed);
+        stats.clients_total.store(4, Relaxed);
+        let text = stats.prometheus(2, 7, None);
+        assert!(text.starts_with(concat!(
+            "# HELP stdintap_lines_read_total Lines read from input\n",
+            "# TYPE stdintap_lines_read_total counter\n",
+            "stdintap_lines_read_total 10\n",
+        )));
+        let samples: Vec<_> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            [
+                "stdintap_lines_read_total 10",
+                "stdintap_bytes_read_total 0",
+                "stdintap_clients_connected_total 4",
+                "stdintap_clients 2",
+                "stdintap_overrun_events_total 0",
+                "stdintap_lagged_messages_total 0",
+                "stdintap_bytes_sent_total 0",
+                "stdintap_lines_sent_total 0",
+                "stdintap_history_lines 7",
+                "stdintap_queue_depth 0",
+            ]
+        );
+        assert_eq!(text.lines().count(), 3 * samples.len());
+
+        let text = stats.prometheus(0, 0, Some(1.5));
+        assert!(text.ends_with("stdintap_input_rate_bytes_per_sec 1.5\n"));
+    }
+
+    #[test]
+    fn health_report_byte_stats() {
+        let stats = Stats::new(Instant::now(), true, false);
+        stats.bytes_sent_total.store(100, Relaxed);
+        stats.lines_sent_total.store(4, Relaxed);
+        let report = health(&stats, 0, 0);
+        assert!(
+            report.ends_with(r#""overrun_count":0,"bytes_sent_total":100,"lines_sent_total":4}"#),
+            "{report}"
+        );
+    }
+
+    #[test]
+    fn health_report_latency() {
+        let stats = Stats::new(Instant::now(), false, true);
+        assert!(health(&stats, 0, 0).ends_with(r#""overrun_count":0}"#));
+        let latency = stats.latency.as_ref().unwrap();
+        latency
+            .lock()
+            .unwrap()
+            .record(std::time::Duration::from_micros(1500));
+        let report = health(&stats, 0, 0);
+        assert!(
+            report.ends_with(concat!(
+                r#""overrun_count":0,"latency_p50_ms":1.500,"#,
+                r#""latency_p95_ms":1.500,"latency_p99_ms":1.500}"#
+            )),
+            "{report}"
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! `--systemd`: socket activation and readiness notification
+
+use std::os::unix::net::UnixDatagram;
+
+use crate::{
+    json::Field,
+    log::{self, Level},
+};
+
+/// Whether the process was started by systemd socket activation with exactly one socket
+pub fn activated() -> bool {
+    let pid_matches = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok())
+        .is_some_and(|p| p == std::process::id());
+    pid_matches && std::env::var("LISTEN_FDS").is_ok_and(|n| n == "1")
+}
+
+/// Send `READY=1` to the service manager if `NOTIFY_SOCKET` is set
+pub fn notify_ready() {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let ret = UnixDatagram::unbound().and_then(|sock| {
+        match path.to_str().and_then(|p| p.strip_prefix('@')) {
+            Some(name) => sock.send_to_addr(b"READY=1", &crate::input::abstract_addr(name)?),
+            None => sock.send_to(b"READY=1", &path),
+        }
+    });
+    if let Err(e) = ret {
+        log::event(
+            Level::Warn,
+            "sd_notify_error",
+            &[("error", Field::Str(&e.to_string()))],
+            Some(format_args!("Notifying systemd: {e}")),
+        );
+    }
+}
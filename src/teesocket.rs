@@ -0,0 +1,133 @@
+//! `--tee-socket`: writing the broadcast stream to a UNIX socket, e.g. of another stdintap instance
+
+use std::{
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{io::AsyncWriteExt, net::UnixStream, sync::broadcast::error::RecvError, time::Instant};
+
+use crate::{broadcaster::Channel, json, log, outputfile::Formatter, MsgInner};
+
+/// First delay before reconnecting, doubled after each failure
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Write buffered lines when there are no more pending ones or when this many bytes accumulate
+const MAX_BUFFERED: usize = 65536;
+
+/// Socket connected to as an internal client receiving all content lines
+pub struct TeeSocket {
+    pub path: PathBuf,
+    pub format: Formatter,
+}
+
+impl TeeSocket {
+    /// Subscribe to the channel right away and return the future writing lines to the socket
+    /// until EOF. Like `--output-file`, it gets lines regardless of `--loadbalance` and
+    /// `--topic-prefix`. Lines arriving while the socket is not connected are skipped.
+    pub fn run(mut self, channel: &Arc<Mutex<Channel>>) -> impl Future<Output = ()> {
+        let (mut rx, mut generation) = {
+            let ch = channel.lock().unwrap();
+            (ch.tx.subscribe(), ch.generation)
+        };
+        let channel = Arc::downgrade(channel);
+        async move {
+            let mut conn: Option<UnixStream> = None;
+            let mut buf = Vec::new();
+            let mut delay = MIN_RETRY_DELAY;
+            let mut retry_at = Instant::now();
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(n)) => {
+                        log::event(
+                            log::Level::Warn,
+                            "tee_socket_overrun",
+                            &[
+                                ("path", json::Field::Str(&self.path.to_string_lossy())),
+                                ("n", json::Field::Num(n)),
+                            ],
+                            Some(format_args!(
+                                "Warning: {} missed {n} lines",
+                                self.path.display()
+                            )),
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => {
+                        // The channel may have been replaced by `SET QLEN` admin command
+                        let Some(ch) = channel.upgrade() else {
+                            break;
+                        };
+                        let ch = ch.lock().unwrap();
+                        if ch.generation == generation {
+                            break;
+                        }
+                        rx = ch.tx.subscribe();
+                        generation = ch.generation;
+                        continue;
+                    }
+                };
+                let b = match msg.inner {
+                    MsgInner::Content(ref b) => b,
+                    MsgInner::Eof => break,
+                    _ => continue,
+                };
+                if conn.is_none() {
+                    if Instant::now() < retry_at {
+                        continue;
+                    }
+                    match UnixStream::connect(&self.path).await {
+                        Ok(s) => {
+                            conn = Some(s);
+                            delay = MIN_RETRY_DELAY;
+                        }
+                        Err(e) => {
+                            self.log_error(&e, delay);
+                            retry_at = Instant::now() + delay;
+                            delay = (delay * 2).min(MAX_RETRY_DELAY);
+                            continue;
+                        }
+                    }
+                }
+                let Some(ref mut s) = conn else {
+                    continue;
+                };
+                let mut ret = self.format.write(&mut buf, &msg, b).await;
+                if ret.is_ok() && (rx.is_empty() || buf.len() >= MAX_BUFFERED) {
+                    ret = s.write_all(&buf).await;
+                    buf.clear();
+                }
+                if let Err(e) = ret {
+                    self.log_error(&e, delay);
+                    conn = None;
+                    buf.clear();
+                    retry_at = Instant::now() + delay;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+            if let Some(ref mut s) = conn {
+                let _ = s.write_all(&buf).await;
+            }
+        }
+    }
+
+    fn log_error(&self, e: &std::io::Error, retry: Duration) {
+        log::event(
+            log::Level::Warn,
+            "tee_socket_error",
+            &[
+                ("path", json::Field::Str(&self.path.to_string_lossy())),
+                ("error", json::Field::Str(&e.to_string())),
+            ],
+            Some(format_args!(
+                "Warning: {}: {e}. Retrying in {} ms.",
+                self.path.display(),
+                retry.as_millis()
+            )),
+        );
+    }
+}
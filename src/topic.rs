@@ -0,0 +1,174 @@
+//! `--topic-prefix` and `--route`: routing lines to clients subscribed to topics
+
+use std::sync::Arc;
+
+use crate::{regex::Regex, Msg, MsgInner};
+
+/// Longest prefix considered a topic name
+const MAX_TOPIC_LEN: usize = 32;
+
+/// Topic of a line like `metrics:cpu=80`, or `None` for the default topic.
+/// Topic names consist of ASCII alphanumerics, `_`, `-` and `.`.
+pub fn of_line(line: &[u8], delimiter: u8) -> Option<Arc<str>> {
+    let end = line
+        .iter()
+        .take(MAX_TOPIC_LEN + 1)
+        .position(|&b| b == delimiter)?;
+    let name = &line[..end];
+    if name.is_empty()
+        || !name
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'))
+    {
+        return None;
+    }
+    std::str::from_utf8(name).ok().map(Arc::from)
+}
+
+/// How topics of lines are determined
+pub enum Router {
+    /// `--topic-prefix` delimiter
+    Prefix(u8),
+    /// `--route` regexes with their groups, the first matching one wins
+    Routes {
+        routes: Vec<(Regex, Arc<str>)>,
+        /// Line separator, excluded from matching like with `--filter`
+        separator: Vec<u8>,
+    },
+}
+
+impl Router {
+    /// Topic of the line, `None` for the default topic
+    pub fn topic(&self, line: &[u8]) -> Option<Arc<str>> {
+        match self {
+            Router::Prefix(delimiter) => of_line(line, *delimiter),
+            Router::Routes { routes, separator } => {
+                let line = line
+                    .strip_suffix(&separator[..])
+                    .or_else(|| line.strip_suffix(&separator[separator.len() - 1..]))
+                    .unwrap_or(line);
+                routes
+                    .iter()
+                    .find(|(re, _)| re.is_match(line))
+                    .map(|(_, group)| group.clone())
+            }
+        }
+    }
+
+    /// Subscription requested by `SUBSCRIBE <name>` handshake line.
+    /// With `--route`, `default` is the group of lines matching no route.
+    pub fn subscription(&self, name: &str) -> Subscription {
+        match (self, name) {
+            (Router::Routes { .. }, "default") => Subscription::Topic(None),
+            _ => Subscription::parse(name),
+        }
+    }
+}
+
+/// Topics a client receives lines from. Special messages are delivered regardless of topic.
+pub enum Subscription {
+    /// `SUBSCRIBE *`
+    All,
+    /// `SUBSCRIBE <TOPIC>`, or the default topic if the client did not subscribe
+    Topic(Option<Arc<str>>),
+}
+
+impl Subscription {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "*" => Subscription::All,
+            x => Subscription::Topic(Some(x.into())),
+        }
+    }
+
+    pub fn matches(&self, msg: &Msg) -> bool {
+        match (self, &msg.inner) {
+            (Subscription::All, _) => true,
+            (Subscription::Topic(t), MsgInner::Content(_)) => *t == msg.topic,
+            (Subscription::Topic(_), _) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn topic_prefix() {
+        assert_eq!(
+            of_line(b"metrics:cpu=80\n", b':').as_deref(),
+            Some("metrics")
+        );
+        assert_eq!(of_line(b"a.b_c-1:x", b':').as_deref(), Some("a.b_c-1"));
+        assert_eq!(of_line(b"no topic here\n", b':'), None);
+        assert_eq!(of_line(b":empty", b':'), None);
+        assert_eq!(of_line(b"with space:x", b':'), None);
+        assert_eq!(of_line(b"caf\xc3\xa9:x", b':'), None);
+        assert_eq!(of_line(b"t|x:y", b'|').as_deref(), Some("t"));
+
+        let longest = [b'a'; MAX_TOPIC_LEN];
+        let line = [&longest[..], b":x"].concat();
+        assert_eq!(
+            of_line(&line, b':').as_deref().map(str::len),
+            Some(MAX_TOPIC_LEN)
+        );
+        let line = [&longest[..], b"a:x"].concat();
+        assert_eq!(of_line(&line, b':'), None);
+    }
+
+    #[test]
+    fn subscriptions() {
+        let msg = |inner, topic: Option<&str>| Msg {
+            ts: Instant::now(),
+            inner,
+            seqn: 0,
+            target: None,
+            topic: topic.map(Arc::from),
+            repeat_count: 0,
+        };
+        let line = |topic| msg(MsgInner::Content("x\n".into()), topic);
+
+        let all = Subscription::parse("*");
+        assert!(all.matches(&line(Some("a"))));
+        assert!(all.matches(&line(None)));
+
+        let a = Subscription::parse("a");
+        assert!(a.matches(&line(Some("a"))));
+        assert!(!a.matches(&line(Some("b"))));
+        assert!(!a.matches(&line(None)));
+        assert!(a.matches(&msg(MsgInner::Eof, None)));
+
+        let default = Subscription::Topic(None);
+        assert!(default.matches(&line(None)));
+        assert!(!default.matches(&line(Some("a"))));
+
+        let router = Router::Prefix(b':');
+        assert_eq!(router.topic(b"a:x").as_deref(), Some("a"));
+        assert!(matches!(
+            router.subscription("default"),
+            Subscription::Topic(Some(_))
+        ));
+    }
+
+    #[test]
+    fn routes_match_without_separator() {
+        let route = |re: &str, group: &str| (Regex::new(re).unwrap(), Arc::from(group));
+        let router = Router::Routes {
+            routes: vec![route("ERROR$", "errors"), route("^WARN", "warnings")],
+            separator: b"\r\n".to_vec(),
+        };
+        assert_eq!(router.topic(b"disk ERROR\r\n").as_deref(), Some("errors"));
+        assert_eq!(router.topic(b"disk ERROR\n").as_deref(), Some("errors"));
+        assert_eq!(router.topic(b"disk ERROR").as_deref(), Some("errors"));
+        assert_eq!(router.topic(b"ERROR: disk\r\n"), None);
+        assert_eq!(router.topic(b"WARN ERROR\r\n").as_deref(), Some("errors"));
+        assert_eq!(router.topic(b"WARN x\r\n").as_deref(), Some("warnings"));
+        assert!(matches!(
+            router.subscription("default"),
+            Subscription::Topic(None)
+        ));
+    }
+}